@@ -0,0 +1,72 @@
+//! Bridge between [erl_pp] and [erl_parse].
+//!
+//! [Preprocessor::forms] groups the preprocessed tokens by forms,
+//! and each of them is parsed by `erl_parse` as a [Form].
+//!
+//! # Examples
+//!
+//! ```
+//! use erl_pp::Preprocessor;
+//! use erl_tokenize::Lexer;
+//!
+//! let mut pp = Preprocessor::new(Lexer::new("-module(foo). -define(A, 1). f() -> ?A."));
+//! let parsed = erl_parse_bridge::parse_forms(&mut pp).unwrap();
+//! assert_eq!(parsed.forms.len(), 2);
+//! ```
+#![warn(missing_docs)]
+use erl_parse::cst::Form;
+use erl_parse::{Parser, TokenReader};
+use erl_pp::{IncludedFile, Preprocessor, WarningDirective};
+use erl_tokenize::LexicalToken;
+
+/// This crate specific `Result` type.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Possible errors.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The preprocessing failed.
+    #[error(transparent)]
+    Preprocess(#[from] erl_pp::Error),
+
+    /// A preprocessed form could not be parsed.
+    #[error("{0}")]
+    Parse(erl_parse::Error),
+}
+
+/// The parsed forms and the metadata of the preprocessing.
+#[derive(Debug)]
+pub struct ParsedForms {
+    /// The parsed forms in the order of appearance.
+    pub forms: Vec<Form>,
+
+    /// The files included during the preprocessing.
+    pub included_files: Vec<IncludedFile>,
+
+    /// The messages of the `-warning` directives.
+    pub warnings: Vec<WarningDirective>,
+}
+
+/// Preprocesses the remaining tokens of `pp` and parses the resulting forms.
+///
+/// The preprocessing stops at the first error.
+pub fn parse_forms<T>(pp: &mut Preprocessor<T>) -> Result<ParsedForms>
+where
+    T: Iterator<Item = erl_tokenize::Result<LexicalToken>>,
+{
+    let mut forms = Vec::new();
+    for tokens in pp.forms() {
+        forms.push(parse_form(tokens?)?);
+    }
+    Ok(ParsedForms {
+        forms,
+        included_files: pp.included_files().to_vec(),
+        warnings: pp.warnings().to_vec(),
+    })
+}
+
+/// Parses the tokens of a form (e.g., an item of [Preprocessor::forms]).
+pub fn parse_form(tokens: Vec<LexicalToken>) -> Result<Form> {
+    let reader = TokenReader::new(tokens.into_iter().map(Ok::<_, erl_parse::Error>));
+    Parser::new(reader).parse().map_err(Error::Parse)
+}
@@ -1,17 +1,23 @@
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use erl_pp::{MacroDef, Preprocessor};
 use erl_tokenize::tokens::AtomToken;
-use erl_tokenize::{Lexer, Position, PositionRange};
+use erl_tokenize::{Lexer, LexicalToken, Position, PositionRange};
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Read;
 use std::path::Path;
 use std::time::{Duration, Instant};
 
 fn main() -> anyhow::Result<()> {
     let matches = App::new("pp")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(Arg::with_name("SOURCE_FILE").index(1).required(true))
         .arg(Arg::with_name("SILENT").long("silent"))
+        .arg(
+            Arg::with_name("INCLUDE_GRAPH")
+                .long("include-graph")
+                .help("Prints the include graph in Graphviz DOT format"),
+        )
         .arg(
             Arg::with_name("CURRENT_DIR")
                 .long("current-dir")
@@ -23,51 +29,170 @@ fn main() -> anyhow::Result<()> {
                 .takes_value(true)
                 .multiple(true),
         )
+        .arg(
+            Arg::with_name("INCLUDE_DIR")
+                .short("I")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("DEFINE")
+                .short("D")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .subcommand(
+            SubCommand::with_name("eval")
+                .about("Expands an expression in the context of a source file")
+                .arg(Arg::with_name("EXPRESSION").index(1).required(true))
+                .arg(
+                    Arg::with_name("CONTEXT_FILE")
+                        .long("context")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("ERL_LIBS")
+                        .long("libs")
+                        .takes_value(true)
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::with_name("INCLUDE_DIR")
+                        .short("I")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("DEFINE")
+                        .short("D")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                ),
+        )
         .get_matches();
-    let src_file = Path::new(matches.value_of("SOURCE_FILE").unwrap());
-    let silent = matches.is_present("SILENT");
     if let Some(dir) = matches.value_of("CURRENT_DIR") {
         env::set_current_dir(dir)?;
     }
+    if let Some(matches) = matches.subcommand_matches("eval") {
+        return eval(matches);
+    }
 
-    let mut src = String::new();
-    let mut file = File::open(&src_file).expect("Cannot open file");
-    file.read_to_string(&mut src).expect("Cannot read file");
+    let src_file = Path::new(matches.value_of("SOURCE_FILE").unwrap());
+    let silent = matches.is_present("SILENT");
+    let src = read_source(src_file);
 
     let start_time = Instant::now();
     let mut count = 0;
 
-    let mut lexer = Lexer::new(&src);
+    let mut preprocessor = make_preprocessor(&src, src_file, &matches)?;
+    let include_graph = matches.is_present("INCLUDE_GRAPH");
+    for result in &mut preprocessor {
+        let token = match result {
+            Ok(token) => token,
+            Err(e) => {
+                let sources = |path: Option<&Path>| match path {
+                    Some(path) if path != Path::new(src_file.file_name().unwrap()) => {
+                        fs::read_to_string(path).ok()
+                    }
+                    _ => Some(src.clone()),
+                };
+                eprintln!("{}", e.display_with_source(&sources));
+                std::process::exit(1);
+            }
+        };
+        if !silent && !include_graph {
+            println!("[{:?}] {:?}", token.start_position(), token.text());
+        }
+        count += 1;
+    }
+    if include_graph {
+        print!("{}", preprocessor.include_graph_dot());
+        return Ok(());
+    }
+    println!("TOKEN COUNT: {}", count);
+    println!(
+        "ELAPSED: {:?} seconds",
+        to_seconds(Instant::now() - start_time)
+    );
+    Ok(())
+}
+
+fn eval(matches: &ArgMatches) -> anyhow::Result<()> {
+    let context_file = Path::new(matches.value_of("CONTEXT_FILE").unwrap());
+    let src = read_source(context_file);
+
+    // Builds the macro environment of the context file.
+    let mut context = make_preprocessor(&src, context_file, matches)?;
+    for result in &mut context {
+        result?;
+    }
+
+    let mut expander = Preprocessor::new(Lexer::new(matches.value_of("EXPRESSION").unwrap()));
+    *expander.include_paths_mut() = context.include_paths().to_vec();
+    *expander.code_paths_mut() = context.code_paths().clone();
+    *expander.macros_mut() = context.macros().clone();
+    let tokens = expander.collect::<Result<Vec<_>, _>>()?;
+    println!(
+        "{}",
+        tokens
+            .iter()
+            .map(LexicalToken::text)
+            .collect::<Vec<_>>()
+            .join(" ")
+    );
+    Ok(())
+}
+
+fn read_source(src_file: &Path) -> String {
+    let mut src = String::new();
+    let mut file = File::open(&src_file).expect("Cannot open file");
+    file.read_to_string(&mut src).expect("Cannot read file");
+    src
+}
+
+fn make_preprocessor<'a>(
+    src: &'a str,
+    src_file: &Path,
+    matches: &ArgMatches,
+) -> anyhow::Result<Preprocessor<Lexer<&'a str>>> {
+    let mut lexer = Lexer::new(src);
     lexer.set_filepath(src_file.file_name().unwrap());
 
     let mut preprocessor = Preprocessor::new(lexer);
+    if let Some(dirs) = matches.values_of("INCLUDE_DIR") {
+        for dir in dirs {
+            preprocessor.include_paths_mut().push(dir.into());
+        }
+    }
     if let Some(libs) = matches.values_of("ERL_LIBS") {
         for dir in libs {
             preprocessor.code_paths_mut().push_back(dir.into());
         }
     }
-    preprocessor.macros_mut().insert(
-        "MODULE".to_string(),
-        MacroDef::Dynamic(vec![AtomToken::from_value(
-            src_file.file_stem().unwrap().to_str().unwrap(),
-            Position::new(),
-        )
-        .into()]),
-    );
-
-    for result in preprocessor {
-        let token = result?;
-        if !silent {
-            println!("[{:?}] {:?}", token.start_position(), token.text());
+    if let Some(definitions) = matches.values_of("DEFINE") {
+        for definition in definitions {
+            preprocessor.define_from_str(definition)?;
         }
-        count += 1;
     }
-    println!("TOKEN COUNT: {}", count);
-    println!(
-        "ELAPSED: {:?} seconds",
-        to_seconds(Instant::now() - start_time)
+    preprocessor.add_erl_libs_to_code_paths();
+    preprocessor.add_otp_lib_to_code_paths();
+    preprocessor.macros_mut().insert(
+        "MODULE".to_string(),
+        MacroDef::Dynamic(
+            vec![AtomToken::from_value(
+                src_file.file_stem().unwrap().to_str().unwrap(),
+                Position::new(),
+            )
+            .into()]
+            .into(),
+        ),
     );
-    Ok(())
+    Ok(preprocessor)
 }
 
 fn to_seconds(duration: Duration) -> f64 {
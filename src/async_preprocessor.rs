@@ -0,0 +1,111 @@
+use erl_tokenize::LexicalToken;
+use futures_core::Stream;
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::preprocessor::PendingInclude;
+use crate::{IncludeKind, Preprocessor, Result};
+
+/// Future returned by `AsyncIncludeResolver::resolve`.
+pub type ResolveFuture = Pin<Box<dyn Future<Output = io::Result<(PathBuf, String)>> + Send>>;
+
+/// Asynchronous version of [IncludeResolver](crate::IncludeResolver).
+pub trait AsyncIncludeResolver {
+    /// Resolves the target file of an include directive and returns its path and text.
+    ///
+    /// See [IncludeResolver::resolve](crate::IncludeResolver::resolve) for the arguments.
+    fn resolve(&self, kind: IncludeKind, path: PathBuf, search_dirs: Vec<PathBuf>)
+        -> ResolveFuture;
+}
+
+/// Preprocessor which resolves included files asynchronously.
+///
+/// This is a `Stream` version of [Preprocessor] for the applications which must not block
+/// their executors on disk IO (e.g., language servers).
+/// Files are read via the given `AsyncIncludeResolver` instead of the `IncludeResolver`
+/// of the inner preprocessor.
+#[derive(Debug)]
+pub struct AsyncPreprocessor<T, R> {
+    inner: Preprocessor<T>,
+    resolver: R,
+    pending: Option<(PendingInclude, Pending)>,
+}
+impl<T, R> AsyncPreprocessor<T, R>
+where
+    T: Iterator<Item = erl_tokenize::Result<LexicalToken>>,
+    R: AsyncIncludeResolver,
+{
+    /// Makes a new `AsyncPreprocessor` instance.
+    pub fn new(mut inner: Preprocessor<T>, resolver: R) -> Self {
+        inner.set_defer_includes(true);
+        AsyncPreprocessor {
+            inner,
+            resolver,
+            pending: None,
+        }
+    }
+
+    /// Returns a reference to the inner preprocessor.
+    pub fn get_ref(&self) -> &Preprocessor<T> {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner preprocessor.
+    pub fn get_mut(&mut self) -> &mut Preprocessor<T> {
+        &mut self.inner
+    }
+
+    /// Returns the inner preprocessor.
+    pub fn into_inner(mut self) -> Preprocessor<T> {
+        self.inner.set_defer_includes(false);
+        self.inner
+    }
+}
+impl<T, R> Stream for AsyncPreprocessor<T, R>
+where
+    T: Iterator<Item = erl_tokenize::Result<LexicalToken>> + Unpin,
+    R: AsyncIncludeResolver + Unpin,
+{
+    type Item = Result<LexicalToken>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some((_, Pending(future))) = this.pending.as_mut() {
+                let resolved = match future.as_mut().poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(resolved) => resolved,
+                };
+                let (include, _) = this.pending.take().expect("unreachable");
+                if let Err(e) = this.inner.provide_include(include, resolved) {
+                    return Poll::Ready(Some(Err(this.inner.with_error_context(e))));
+                }
+            }
+
+            match this.inner.next_item() {
+                None => {}
+                item => return Poll::Ready(item),
+            }
+            if let Some(include) = this.inner.take_pending_include() {
+                let future = this.resolver.resolve(
+                    include.kind,
+                    include.path.clone(),
+                    include.search_dirs.clone(),
+                );
+                this.pending = Some((include, Pending(future)));
+            } else {
+                return Poll::Ready(None);
+            }
+        }
+    }
+}
+
+struct Pending(ResolveFuture);
+impl std::fmt::Debug for Pending {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Pending(..)")
+    }
+}
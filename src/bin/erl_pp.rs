@@ -0,0 +1,323 @@
+//! Command-line interface of `erl_pp`.
+//!
+//! This binary is available only if the `cli` feature is enabled.
+use clap::{App, Arg, ArgMatches};
+use erl_pp::{ExpansionObserver, FileSystemResolver, MacroCall, Preprocessor, Severity};
+use erl_tokenize::{Lexer, LexicalToken, PositionRange};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Exit code for preprocessing errors (including active `-error` directives).
+const EXIT_PREPROCESS_ERROR: i32 = 1;
+
+/// Exit code for I/O errors (e.g., the source file cannot be read).
+const EXIT_IO_ERROR: i32 = 2;
+
+fn main() {
+    let matches = App::new("erl_pp")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Erlang source code preprocessor")
+        .after_help(
+            "Writes the preprocessed forms to the output, one form per line\n\
+             (or the preprocessed source text if `-E` is specified,\n\
+             or the files read during preprocessing if `--deps` is specified).\n\n\
+             EXIT STATUS:\n    \
+             0    Success\n    \
+             1    Preprocessing failed or an active -error directive was reached\n    \
+             2    I/O error",
+        )
+        .arg(
+            Arg::with_name("SOURCE_FILE")
+                .index(1)
+                .required(true)
+                .help("Source file to preprocess (`-` for the standard input)"),
+        )
+        .arg(
+            Arg::with_name("DEFINE")
+                .short("D")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("NAME[=VALUE]")
+                .help("Defines a macro"),
+        )
+        .arg(
+            Arg::with_name("INCLUDE_DIR")
+                .short("I")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("DIR")
+                .help("Adds a directory searched by -include directives"),
+        )
+        .arg(
+            Arg::with_name("CODE_PATH_A")
+                .long("pa")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("DIR")
+                .help("Adds a directory to the beginning of the code path"),
+        )
+        .arg(
+            Arg::with_name("CODE_PATH_Z")
+                .long("pz")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("DIR")
+                .help("Adds a directory to the end of the code path"),
+        )
+        .arg(
+            Arg::with_name("OUTPUT_FILE")
+                .short("o")
+                .long("output")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Writes the output to the file instead of the standard output"),
+        )
+        .arg(
+            Arg::with_name("EMIT_SOURCE")
+                .short("E")
+                .long("emit-source")
+                .help(
+                    "Writes the preprocessed source text which keeps the layout \
+                     of the original source as much as possible (like `erlc -E`)",
+                ),
+        )
+        .arg(
+            Arg::with_name("DEPS")
+                .long("deps")
+                .takes_value(true)
+                .min_values(0)
+                .max_values(1)
+                .require_equals(true)
+                .possible_values(&["list", "make"])
+                .value_name("FORMAT")
+                .conflicts_with("EMIT_SOURCE")
+                .help(
+                    "Writes the source file and the files included by it (transitively), \
+                     one per line (`list`, default) or as a Makefile rule (`make`)",
+                ),
+        )
+        .arg(
+            Arg::with_name("DEPS_TARGET")
+                .long("deps-target")
+                .takes_value(true)
+                .value_name("TARGET")
+                .requires("DEPS")
+                .help("Sets the target of the Makefile rule [default: SOURCE_FILE with .beam extension]"),
+        )
+        .arg(
+            Arg::with_name("TRACE_MACROS")
+                .long("trace-macros")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .use_delimiter(true)
+                .value_name("NAME")
+                .help(
+                    "Prints each top-level macro expansion (the call site, the call and the result) \
+                     to the standard error, optionally only for the given macros",
+                ),
+        )
+        .get_matches();
+
+    match run(&matches) {
+        Ok(code) => process::exit(code),
+        Err(e) => {
+            eprintln!("erl_pp: {}", e);
+            process::exit(EXIT_IO_ERROR);
+        }
+    }
+}
+
+fn run(matches: &ArgMatches) -> anyhow::Result<i32> {
+    let src_file = matches.value_of("SOURCE_FILE").unwrap();
+    let (src, src_path) = if src_file == "-" {
+        let mut src = String::new();
+        io::stdin().read_to_string(&mut src)?;
+        (src, None)
+    } else {
+        let src = FileSystemResolver::read_source(src_file)
+            .map_err(|e| anyhow::anyhow!("cannot read {:?}: {}", src_file, e))?;
+        (src, Some(PathBuf::from(src_file)))
+    };
+
+    let mut pp = match make_preprocessor(&src, src_path.as_deref(), matches) {
+        Ok(pp) => pp,
+        Err(e) => {
+            eprintln!("erl_pp: {}", e);
+            return Ok(EXIT_PREPROCESS_ERROR);
+        }
+    };
+
+    let mut output: Box<dyn Write> = match matches.value_of("OUTPUT_FILE") {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+    let sources = |path: Option<&Path>| {
+        if path == src_path.as_deref() {
+            Some(src.clone())
+        } else {
+            path.and_then(|path| FileSystemResolver::read_source(path).ok())
+        }
+    };
+    let mut failed = false;
+    if matches.is_present("EMIT_SOURCE") {
+        match pp.preprocessed_text(&sources) {
+            Ok(text) => output.write_all(text.as_bytes())?,
+            Err(e) => {
+                eprintln!("{}", e.display_with_source(&sources));
+                failed = true;
+            }
+        }
+    } else if matches.is_present("DEPS") {
+        for result in &mut pp {
+            if let Err(e) = result {
+                eprintln!("{}", e.display_with_source(&sources));
+                failed = true;
+                break;
+            }
+        }
+        if matches.value_of("DEPS") == Some("make") {
+            let target = matches.value_of("DEPS_TARGET").map_or_else(
+                || {
+                    let stem = src_path.as_deref().and_then(Path::file_stem);
+                    Path::new(stem.unwrap_or_else(|| "stdin".as_ref()))
+                        .with_extension("beam")
+                        .to_string_lossy()
+                        .into_owned()
+                },
+                ToOwned::to_owned,
+            );
+            output.write_all(pp.makefile_deps(&target).as_bytes())?;
+        } else {
+            let mut seen = HashSet::new();
+            let files = src_path
+                .iter()
+                .map(PathBuf::as_path)
+                .chain(pp.included_files().iter().map(|f| f.path.as_path()));
+            for file in files.filter(|file| seen.insert(*file)) {
+                writeln!(output, "{}", file.display())?;
+            }
+        }
+    } else {
+        for form in pp.forms() {
+            match form {
+                Ok(tokens) => writeln!(
+                    output,
+                    "{}",
+                    tokens
+                        .iter()
+                        .map(LexicalToken::text)
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                )?,
+                Err(e) => {
+                    eprintln!("{}", e.display_with_source(&sources));
+                    failed = true;
+                    break;
+                }
+            }
+        }
+    }
+    output.flush()?;
+
+    for diagnostic in pp.diagnostics() {
+        let severity = match diagnostic.severity {
+            Severity::Error => {
+                failed = true;
+                "error"
+            }
+            Severity::Warning => "warning",
+        };
+        let location = diagnostic.span.as_ref().map_or_else(String::new, |(p, _)| {
+            let file = p
+                .filepath()
+                .map_or("<stdin>".into(), |f| f.to_string_lossy());
+            format!("{}:{}:{}: ", file, p.line(), p.column())
+        });
+        eprintln!("{}{}: {}", location, severity, diagnostic.message);
+    }
+    Ok(if failed { EXIT_PREPROCESS_ERROR } else { 0 })
+}
+
+fn make_preprocessor<'a>(
+    src: &'a str,
+    src_path: Option<&Path>,
+    matches: &ArgMatches,
+) -> erl_pp::Result<Preprocessor<Lexer<&'a str>>> {
+    let mut lexer = Lexer::new(src);
+    if let Some(path) = src_path {
+        lexer.set_filepath(path);
+    }
+
+    let mut pp = Preprocessor::new(lexer);
+    if let Some(dirs) = matches.values_of("INCLUDE_DIR") {
+        pp.include_paths_mut().extend(dirs.map(PathBuf::from));
+    }
+    if let Some(dirs) = matches.values_of("CODE_PATH_A") {
+        // As `erl -pa`, the directories keep their order at the beginning of the code path.
+        for dir in dirs.collect::<Vec<_>>().into_iter().rev() {
+            pp.code_paths_mut().push_front(dir.into());
+        }
+    }
+    if let Some(dirs) = matches.values_of("CODE_PATH_Z") {
+        pp.code_paths_mut().extend(dirs.map(PathBuf::from));
+    }
+    pp.add_erl_libs_to_code_paths();
+    pp.add_otp_lib_to_code_paths();
+    if let Some(definitions) = matches.values_of("DEFINE") {
+        for definition in definitions {
+            pp.define_from_str(definition)?;
+        }
+    }
+    if matches.is_present("TRACE_MACROS") {
+        let names = matches
+            .values_of("TRACE_MACROS")
+            .map(|names| {
+                names
+                    .filter(|name| !name.is_empty())
+                    .map(ToOwned::to_owned)
+                    .collect::<HashSet<_>>()
+            })
+            .filter(|names| !names.is_empty());
+        pp.set_observer(MacroTracer { names });
+    }
+    Ok(pp)
+}
+
+/// Observer which prints macro expansions for `--trace-macros`.
+#[derive(Debug)]
+struct MacroTracer {
+    names: Option<HashSet<String>>,
+}
+impl ExpansionObserver for MacroTracer {
+    fn on_macro_expanded(&mut self, call: &MacroCall, output: &[LexicalToken]) {
+        if let Some(ref names) = self.names {
+            if !names.contains(call.name.value()) {
+                return;
+            }
+        }
+        let position = call.start_position();
+        let file = position
+            .filepath()
+            .map_or("<stdin>".into(), |f| f.to_string_lossy());
+        eprintln!(
+            "{}:{}:{}: trace: {} => {}",
+            file,
+            position.line(),
+            position.column(),
+            call,
+            output
+                .iter()
+                .map(LexicalToken::text)
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+    }
+}
@@ -0,0 +1,163 @@
+//! Integration with [codespan-reporting](https://docs.rs/codespan-reporting).
+//!
+//! This module is available only if the `codespan` feature is enabled.
+use codespan_reporting::diagnostic::{Diagnostic, Label};
+use codespan_reporting::files::{Files, SimpleFile, SimpleFiles};
+use erl_tokenize::Position;
+use std::collections::BTreeMap;
+#[cfg(feature = "fs")]
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "fs")]
+use crate::util;
+#[cfg(feature = "fs")]
+use crate::IncludedFile;
+use crate::{Error, WarningDirective};
+
+/// A file database which maps the file paths of positions to `codespan-reporting` file ids.
+///
+/// The source given to a `Preprocessor` without a file path is registered by `add(None, text)`.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate codespan_reporting;
+/// # extern crate erl_pp;
+/// # extern crate erl_tokenize;
+/// use erl_pp::codespan::SourceFiles;
+/// use erl_pp::Preprocessor;
+/// use erl_tokenize::Lexer;
+///
+/// # fn main() {
+/// let src = "?FOO.";
+/// let mut files = SourceFiles::new();
+/// files.add(None, src);
+///
+/// let error = Preprocessor::new(Lexer::new(src)).next().unwrap().unwrap_err();
+/// let diagnostic = error.to_diagnostic(&files);
+/// assert_eq!(diagnostic.labels[0].range, 0..4);
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct SourceFiles {
+    files: SimpleFiles<String, String>,
+    ids: BTreeMap<Option<PathBuf>, usize>,
+}
+impl SourceFiles {
+    /// Makes a new empty `SourceFiles` instance.
+    pub fn new() -> Self {
+        SourceFiles {
+            files: SimpleFiles::new(),
+            ids: BTreeMap::new(),
+        }
+    }
+
+    /// Adds a file and returns its id.
+    ///
+    /// If a file which has the same path exists, the new id replaces the old one.
+    pub fn add<S: Into<String>>(&mut self, path: Option<&Path>, text: S) -> usize {
+        let name = path.map_or_else(|| "<unknown>".to_owned(), |p| p.display().to_string());
+        let id = self.files.add(name, text.into());
+        self.ids.insert(path.map(Path::to_path_buf), id);
+        id
+    }
+
+    /// Reads a file from the filesystem and adds it unless it has been added already.
+    ///
+    /// This is available only if the `fs` feature is enabled.
+    #[cfg(feature = "fs")]
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> io::Result<usize> {
+        let path = path.as_ref();
+        if let Some(id) = self.file_id(Some(path)) {
+            return Ok(id);
+        }
+        let text = util::read_file(path)?;
+        Ok(self.add(Some(path), text))
+    }
+
+    /// Reads the files included during preprocessing and adds them.
+    ///
+    /// See also: [Preprocessor::included_files()](crate::Preprocessor::included_files).
+    ///
+    /// This is available only if the `fs` feature is enabled.
+    #[cfg(feature = "fs")]
+    pub fn load_included_files(&mut self, files: &[IncludedFile]) -> io::Result<()> {
+        for file in files {
+            self.load(&file.path)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the id of the file which has the given path.
+    pub fn file_id(&self, path: Option<&Path>) -> Option<usize> {
+        self.ids.get(&path.map(Path::to_path_buf)).copied()
+    }
+
+    /// Returns the underlying database which is passed to `codespan_reporting::term::emit()`.
+    pub fn files(&self) -> &SimpleFiles<String, String> {
+        &self.files
+    }
+
+    /// Returns the file which has the given id.
+    pub fn get(&self, id: usize) -> Option<&SimpleFile<String, String>> {
+        self.files.get(id).ok()
+    }
+
+    fn label(&self, start: &Position, end: &Position) -> Option<(usize, std::ops::Range<usize>)> {
+        let id = self.file_id(start.filepath().map(PathBuf::as_path))?;
+        let len = self.files.source(id).ok()?.len();
+        let start = start.offset().min(len);
+        let end = end.offset().max(start).min(len);
+        Some((id, start..end))
+    }
+}
+impl Default for SourceFiles {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Error {
+    /// Converts this error into a `codespan-reporting` diagnostic.
+    ///
+    /// The primary label points to `Error::span()`, and each entry of `Error::include_stack()`
+    /// is added as a secondary label.
+    /// Labels for files which are not registered in `files` are omitted.
+    ///
+    /// This method is available only if the `codespan` feature is enabled.
+    pub fn to_diagnostic(&self, files: &SourceFiles) -> Diagnostic<usize> {
+        let mut labels = Vec::new();
+        if let Some((id, range)) = self
+            .span()
+            .and_then(|(start, end)| files.label(&start, &end))
+        {
+            labels.push(Label::primary(id, range));
+        }
+        for position in self.include_stack() {
+            if let Some((id, range)) = files.label(position, position) {
+                labels.push(Label::secondary(id, range).with_message("included from here"));
+            }
+        }
+        Diagnostic::error()
+            .with_message(self.message())
+            .with_code(self.code())
+            .with_labels(labels)
+    }
+}
+
+impl WarningDirective {
+    /// Converts this warning into a `codespan-reporting` diagnostic.
+    ///
+    /// This method is available only if the `codespan` feature is enabled.
+    pub fn to_diagnostic(&self, files: &SourceFiles) -> Diagnostic<usize> {
+        let labels = files
+            .label(&self.position, &self.position)
+            .map(|(id, range)| Label::primary(id, range))
+            .into_iter()
+            .collect();
+        Diagnostic::warning()
+            .with_message(self.message.clone())
+            .with_labels(labels)
+    }
+}
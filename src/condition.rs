@@ -0,0 +1,397 @@
+use erl_tokenize::values::{Keyword, Symbol};
+use erl_tokenize::{Lexer, LexicalToken, Position, PositionRange};
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+
+use crate::expander::MacroExpander;
+use crate::macros::MacroEnv;
+use crate::{Error, Result};
+
+/// Evaluates a condition expression (e.g., the argument of an `-if` directive)
+/// under the given macro environment.
+///
+/// The macro calls in the condition are expanded before the evaluation.
+/// The condition is a constant expression consisting of
+/// integers, atoms, strings, arithmetic, comparison and boolean operators,
+/// and `defined(NAME)` calls.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate erl_pp;
+/// use erl_pp::{evaluate_condition, MacroDef, MacroEnv};
+///
+/// # fn main() {
+/// let mut env = MacroEnv::new();
+/// env.macros_mut().insert("OTP_RELEASE".to_string(), MacroDef::Dynamic(Vec::new().into()));
+/// assert!(evaluate_condition("defined(OTP_RELEASE) andalso 1 + 2 == 3", &env).unwrap());
+/// assert!(!evaluate_condition("defined(DEBUG)", &env).unwrap());
+/// # }
+/// ```
+pub fn evaluate_condition<C: ConditionInput>(condition: C, env: &MacroEnv) -> Result<bool> {
+    let tokens = condition.into_condition_tokens()?;
+    let tokens = MacroExpander::new(env).expand(&tokens)?;
+    evaluate(&tokens, |name| env.is_defined(name))
+}
+
+/// Types which can be used as the input of [evaluate_condition].
+pub trait ConditionInput {
+    /// Converts this value into the tokens of a condition expression.
+    fn into_condition_tokens(self) -> Result<Vec<LexicalToken>>;
+}
+impl ConditionInput for &str {
+    fn into_condition_tokens(self) -> Result<Vec<LexicalToken>> {
+        Ok(Lexer::new(self).collect::<erl_tokenize::Result<_>>()?)
+    }
+}
+impl ConditionInput for &[LexicalToken] {
+    fn into_condition_tokens(self) -> Result<Vec<LexicalToken>> {
+        Ok(self.to_vec())
+    }
+}
+impl ConditionInput for Vec<LexicalToken> {
+    fn into_condition_tokens(self) -> Result<Vec<LexicalToken>> {
+        Ok(self)
+    }
+}
+
+/// Evaluates the given (already macro expanded) condition expression.
+pub(crate) fn evaluate<F>(tokens: &[LexicalToken], is_defined: F) -> Result<bool>
+where
+    F: Fn(&str) -> bool,
+{
+    let mut parser = Parser { tokens, index: 0 };
+    let expr = parser.parse_expr(0)?;
+    if let Some(token) = parser.peek() {
+        return Err(Error::invalid_condition(
+            Some(token.start_position()),
+            "unexpected token",
+        ));
+    }
+    match expr.eval(&is_defined)? {
+        Value::Atom(ref a) if a == "true" => Ok(true),
+        Value::Atom(ref a) if a == "false" => Ok(false),
+        _ => Err(Error::invalid_condition(
+            tokens.first().map(PositionRange::start_position),
+            "the condition is not evaluated to a boolean",
+        )),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Band,
+    Bor,
+    Bxor,
+    Bsl,
+    Bsr,
+    And,
+    Or,
+    Xor,
+    Andalso,
+    Orelse,
+    Eq,
+    NotEq,
+    ExactEq,
+    ExactNotEq,
+    Less,
+    LessEq,
+    Greater,
+    GreaterEq,
+}
+impl Op {
+    fn from_token(token: &LexicalToken) -> Option<Op> {
+        if let Some(s) = token.as_symbol_token() {
+            Some(match s.value() {
+                Symbol::Plus => Op::Add,
+                Symbol::Hyphen => Op::Sub,
+                Symbol::Multiply => Op::Mul,
+                Symbol::Eq => Op::Eq,
+                Symbol::NotEq => Op::NotEq,
+                Symbol::ExactEq => Op::ExactEq,
+                Symbol::ExactNotEq => Op::ExactNotEq,
+                Symbol::Less => Op::Less,
+                Symbol::LessEq => Op::LessEq,
+                Symbol::Greater => Op::Greater,
+                Symbol::GreaterEq => Op::GreaterEq,
+                _ => return None,
+            })
+        } else if let Some(k) = token.as_keyword_token() {
+            Some(match k.value() {
+                Keyword::Div => Op::Div,
+                Keyword::Rem => Op::Rem,
+                Keyword::Band => Op::Band,
+                Keyword::Bor => Op::Bor,
+                Keyword::Bxor => Op::Bxor,
+                Keyword::Bsl => Op::Bsl,
+                Keyword::Bsr => Op::Bsr,
+                Keyword::And => Op::And,
+                Keyword::Or => Op::Or,
+                Keyword::Xor => Op::Xor,
+                Keyword::Andalso => Op::Andalso,
+                Keyword::Orelse => Op::Orelse,
+                _ => return None,
+            })
+        } else {
+            None
+        }
+    }
+
+    // Binding powers (the higher, the tighter).
+    // Comparison operators are non-associative and the others are left-associative.
+    fn precedence(self) -> u8 {
+        match self {
+            Op::Orelse => 1,
+            Op::Andalso => 2,
+            Op::Eq
+            | Op::NotEq
+            | Op::ExactEq
+            | Op::ExactNotEq
+            | Op::Less
+            | Op::LessEq
+            | Op::Greater
+            | Op::GreaterEq => 3,
+            Op::Add | Op::Sub | Op::Bor | Op::Bxor | Op::Bsl | Op::Bsr | Op::Or | Op::Xor => 4,
+            Op::Mul | Op::Div | Op::Rem | Op::Band | Op::And => 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UnaryOp {
+    Plus,
+    Minus,
+    Not,
+    Bnot,
+}
+
+#[derive(Debug)]
+enum Expr {
+    Value(Value),
+    Defined(String),
+    Unary(UnaryOp, Box<Expr>, Position),
+    Binary(Op, Box<Expr>, Box<Expr>, Position),
+}
+impl Expr {
+    fn eval<F>(&self, is_defined: &F) -> Result<Value>
+    where
+        F: Fn(&str) -> bool,
+    {
+        match *self {
+            Expr::Value(ref v) => Ok(v.clone()),
+            Expr::Defined(ref name) => Ok(Value::boolean(is_defined(name))),
+            Expr::Unary(op, ref x, ref position) => {
+                let error = || Error::invalid_condition(Some(position.clone()), "bad argument");
+                let x = x.eval(is_defined)?;
+                match (op, x) {
+                    (UnaryOp::Plus, Value::Integer(x)) => Ok(Value::Integer(x)),
+                    (UnaryOp::Minus, Value::Integer(x)) => {
+                        x.checked_neg().map(Value::Integer).ok_or_else(error)
+                    }
+                    (UnaryOp::Bnot, Value::Integer(x)) => Ok(Value::Integer(!x)),
+                    (UnaryOp::Not, x) => x.as_bool().map(|b| Value::boolean(!b)).ok_or_else(error),
+                    _ => Err(error()),
+                }
+            }
+            Expr::Binary(Op::Andalso, ref x, ref y, ref position) => {
+                match x.eval(is_defined)?.as_bool() {
+                    Some(true) => y.eval(is_defined),
+                    Some(false) => Ok(Value::boolean(false)),
+                    None => Err(Error::invalid_condition(
+                        Some(position.clone()),
+                        "bad argument",
+                    )),
+                }
+            }
+            Expr::Binary(Op::Orelse, ref x, ref y, ref position) => {
+                match x.eval(is_defined)?.as_bool() {
+                    Some(true) => Ok(Value::boolean(true)),
+                    Some(false) => y.eval(is_defined),
+                    None => Err(Error::invalid_condition(
+                        Some(position.clone()),
+                        "bad argument",
+                    )),
+                }
+            }
+            Expr::Binary(op, ref x, ref y, ref position) => {
+                let x = x.eval(is_defined)?;
+                let y = y.eval(is_defined)?;
+                eval_binary(op, x, y).ok_or_else(|| {
+                    Error::invalid_condition(Some(position.clone()), "bad arithmetic or argument")
+                })
+            }
+        }
+    }
+}
+
+fn eval_binary(op: Op, x: Value, y: Value) -> Option<Value> {
+    match op {
+        Op::Eq | Op::ExactEq => return Some(Value::boolean(x == y)),
+        Op::NotEq | Op::ExactNotEq => return Some(Value::boolean(x != y)),
+        Op::Less => return Some(Value::boolean(x.cmp(&y) == Ordering::Less)),
+        Op::LessEq => return Some(Value::boolean(x.cmp(&y) != Ordering::Greater)),
+        Op::Greater => return Some(Value::boolean(x.cmp(&y) == Ordering::Greater)),
+        Op::GreaterEq => return Some(Value::boolean(x.cmp(&y) != Ordering::Less)),
+        Op::And | Op::Or | Op::Xor => {
+            let (x, y) = (x.as_bool()?, y.as_bool()?);
+            let b = match op {
+                Op::And => x && y,
+                Op::Or => x || y,
+                _ => x != y,
+            };
+            return Some(Value::boolean(b));
+        }
+        _ => {}
+    }
+    let (x, y) = match (x, y) {
+        (Value::Integer(x), Value::Integer(y)) => (x, y),
+        _ => return None,
+    };
+    let v = match op {
+        Op::Add => x.checked_add(y)?,
+        Op::Sub => x.checked_sub(y)?,
+        Op::Mul => x.checked_mul(y)?,
+        Op::Div => x.checked_div(y)?,
+        Op::Rem => x.checked_rem(y)?,
+        Op::Band => x & y,
+        Op::Bor => x | y,
+        Op::Bxor => x ^ y,
+        Op::Bsl => x.checked_shl(u32::try_from(y).ok()?)?,
+        Op::Bsr => x.checked_shr(u32::try_from(y).ok()?)?,
+        _ => unreachable!(),
+    };
+    Some(Value::Integer(v))
+}
+
+/// Constant value.
+///
+/// The ordering follows the Erlang term order (number < atom < list).
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Value {
+    Integer(i64),
+    Atom(String),
+    String(String),
+}
+impl Value {
+    fn boolean(b: bool) -> Self {
+        Value::Atom(if b { "true" } else { "false" }.to_owned())
+    }
+    fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Value::Atom(ref a) if a == "true" => Some(true),
+            Value::Atom(ref a) if a == "false" => Some(false),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Parser<'a> {
+    tokens: &'a [LexicalToken],
+    index: usize,
+}
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a LexicalToken> {
+        self.tokens.get(self.index)
+    }
+    fn next(&mut self) -> Result<&'a LexicalToken> {
+        let token = self.tokens.get(self.index).ok_or_else(|| {
+            Error::invalid_condition(
+                self.tokens.last().map(PositionRange::end_position),
+                "unexpected end of the expression",
+            )
+        })?;
+        self.index += 1;
+        Ok(token)
+    }
+    fn expect_symbol(&mut self, expected: Symbol) -> Result<()> {
+        let token = self.next()?;
+        if token.as_symbol_token().map(|s| s.value()) == Some(expected) {
+            Ok(())
+        } else {
+            Err(Error::unexpected_token(token.clone(), expected.as_str()))
+        }
+    }
+
+    fn parse_expr(&mut self, min_precedence: u8) -> Result<Expr> {
+        let mut left = self.parse_unary()?;
+        while let Some(token) = self.peek() {
+            let op = match Op::from_token(token) {
+                Some(op) if op.precedence() >= min_precedence => op,
+                _ => break,
+            };
+            self.index += 1;
+            let right = self.parse_expr(op.precedence() + 1)?;
+            if op.precedence() == 3 {
+                if let Some(op) = self.peek().and_then(Op::from_token) {
+                    if op.precedence() == 3 {
+                        return Err(Error::invalid_condition(
+                            self.peek().map(PositionRange::start_position),
+                            "comparison operators are non-associative",
+                        ));
+                    }
+                }
+            }
+            left = Expr::Binary(op, Box::new(left), Box::new(right), token.start_position());
+        }
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr> {
+        let token = self.next()?;
+        let op = match *token {
+            LexicalToken::Symbol(ref s) if s.value() == Symbol::Plus => Some(UnaryOp::Plus),
+            LexicalToken::Symbol(ref s) if s.value() == Symbol::Hyphen => Some(UnaryOp::Minus),
+            LexicalToken::Keyword(ref k) if k.value() == Keyword::Not => Some(UnaryOp::Not),
+            LexicalToken::Keyword(ref k) if k.value() == Keyword::Bnot => Some(UnaryOp::Bnot),
+            _ => None,
+        };
+        if let Some(op) = op {
+            let operand = self.parse_unary()?;
+            return Ok(Expr::Unary(op, Box::new(operand), token.start_position()));
+        }
+        self.parse_primary(token)
+    }
+
+    fn parse_primary(&mut self, token: &'a LexicalToken) -> Result<Expr> {
+        let position = token.start_position();
+        let value = match *token {
+            LexicalToken::Symbol(ref s) if s.value() == Symbol::OpenParen => {
+                let expr = self.parse_expr(0)?;
+                self.expect_symbol(Symbol::CloseParen)?;
+                return Ok(expr);
+            }
+            LexicalToken::Atom(ref a) if a.value() == "defined" => {
+                self.expect_symbol(Symbol::OpenParen)?;
+                let name = match *self.next()? {
+                    LexicalToken::Atom(ref a) => a.value().to_owned(),
+                    LexicalToken::Variable(ref v) => v.value().to_owned(),
+                    ref other => return Err(Error::unexpected_token(other.clone(), "MacroName")),
+                };
+                self.expect_symbol(Symbol::CloseParen)?;
+                return Ok(Expr::Defined(name));
+            }
+            LexicalToken::Atom(ref a) => Value::Atom(a.value().to_owned()),
+            LexicalToken::Char(ref c) => Value::Integer(i64::from(u32::from(c.value()))),
+            LexicalToken::Integer(ref i) => {
+                let value = i.value().to_string().parse().map_err(|_| {
+                    Error::invalid_condition(Some(position.clone()), "too large integer")
+                })?;
+                Value::Integer(value)
+            }
+            LexicalToken::String(ref s) => Value::String(s.value().to_owned()),
+            _ => {
+                return Err(Error::unexpected_token(
+                    token.clone(),
+                    "Integer|Atom|String|Char|'('",
+                ))
+            }
+        };
+        Ok(Expr::Value(value))
+    }
+}
@@ -0,0 +1,154 @@
+//! Compiler configuration files.
+//!
+//! This module is available only if the `config` feature is enabled.
+//!
+//! A configuration file is written in TOML or JSON, and its `erl_opts` table
+//! corresponds to the `erl_opts` of `rebar.config`:
+//!
+//! ```toml
+//! # {erl_opts, [{d, 'DEBUG'}, {d, 'VSN', "1.0"}, {d, 'LEVEL', 3}, {i, "include"}]}.
+//! [erl_opts]
+//! d = { DEBUG = true, VSN = '"1.0"', LEVEL = 3 }
+//! i = ["include"]
+//!
+//! # Directories searched by `-include_lib` directives (e.g., `ERL_LIBS`).
+//! code_paths = ["_build/default/lib"]
+//! ```
+//!
+//! The values of `d` are booleans, integers or strings which are the Erlang source texts of the values
+//! (i.e., `{d, 'DEBUG'}` is the same as `DEBUG = true`).
+//! Relative paths are resolved against the directory containing the configuration file.
+//!
+//! # Examples
+//!
+//! ```
+//! # extern crate erl_pp;
+//! # extern crate erl_tokenize;
+//! use erl_pp::config::Config;
+//! use erl_pp::Preprocessor;
+//! use erl_tokenize::Lexer;
+//!
+//! # fn main() {
+//! let config = Config::from_json_str(r#"{"erl_opts": {"d": {"VSN": "\"1.0\""}}}"#).unwrap();
+//! let mut pp = Preprocessor::new(Lexer::new("?VSN."));
+//! config.apply(&mut pp).unwrap();
+//! let tokens = pp.collect::<Result<Vec<_>, _>>().unwrap();
+//! assert_eq!(tokens.iter().map(|t| t.text()).collect::<Vec<_>>(), [r#""1.0""#, "."]);
+//! # }
+//! ```
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "fs")]
+use crate::util;
+use crate::{Error, Preprocessor, Result};
+
+/// Compiler configuration.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Options corresponding to `erl_opts` of `rebar.config`.
+    pub erl_opts: ErlOpts,
+
+    /// Code paths searched by `-include_lib` directives.
+    pub code_paths: Vec<PathBuf>,
+
+    #[serde(skip)]
+    base_dir: Option<PathBuf>,
+}
+impl Config {
+    /// Parses a configuration written in TOML.
+    pub fn from_toml_str(text: &str) -> Result<Self> {
+        toml::from_str(text).map_err(|e| config_error(None, e))
+    }
+
+    /// Parses a configuration written in JSON.
+    pub fn from_json_str(text: &str) -> Result<Self> {
+        serde_json::from_str(text).map_err(|e| config_error(None, e))
+    }
+
+    /// Loads a configuration file.
+    ///
+    /// Files whose extension is `json` are parsed as JSON, and the others as TOML.
+    ///
+    /// This is available only if the `fs` feature is enabled.
+    #[cfg(feature = "fs")]
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text = util::read_file(path).map_err(|source| Error::SourceFileError {
+            source,
+            path: path.to_path_buf(),
+        })?;
+        let config = if path.extension().is_some_and(|e| e == "json") {
+            serde_json::from_str(&text).map_err(|e| config_error(Some(path), e))
+        } else {
+            toml::from_str(&text).map_err(|e| config_error(Some(path), e))
+        };
+        config.map(|config: Config| Config {
+            base_dir: path.parent().map(Path::to_path_buf),
+            ..config
+        })
+    }
+
+    /// Installs the macro definitions, include paths and code paths into `pp`.
+    pub fn apply<T>(&self, pp: &mut Preprocessor<T>) -> Result<()> {
+        for (name, value) in &self.erl_opts.d {
+            pp.define_from_str(&format!("{}={}", name, value.to_source()))?;
+        }
+        pp.include_paths_mut()
+            .extend(self.erl_opts.i.iter().map(|p| self.resolve(p)));
+        pp.code_paths_mut()
+            .extend(self.code_paths.iter().map(|p| self.resolve(p)));
+        Ok(())
+    }
+
+    fn resolve(&self, path: &Path) -> PathBuf {
+        match self.base_dir {
+            Some(ref dir) if path.is_relative() => dir.join(path),
+            _ => path.to_path_buf(),
+        }
+    }
+}
+
+/// Options corresponding to `erl_opts` of `rebar.config`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ErlOpts {
+    /// Macro definitions (`{d, Name}` and `{d, Name, Value}`).
+    pub d: BTreeMap<String, DefineValue>,
+
+    /// Include directories (`{i, Dir}`).
+    pub i: Vec<PathBuf>,
+}
+
+/// Value of a macro definition.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum DefineValue {
+    /// Boolean (`true` or `false` atom).
+    Bool(bool),
+
+    /// Integer.
+    Integer(i64),
+
+    /// Erlang source text (e.g., `"\"1.0\""` for the string `"1.0"`).
+    Source(String),
+}
+impl DefineValue {
+    /// Returns the Erlang source text of this value.
+    pub fn to_source(&self) -> String {
+        match *self {
+            DefineValue::Bool(b) => b.to_string(),
+            DefineValue::Integer(n) => n.to_string(),
+            DefineValue::Source(ref s) => s.clone(),
+        }
+    }
+}
+
+fn config_error<E: std::fmt::Display>(path: Option<&Path>, e: E) -> Error {
+    Error::ConfigError {
+        path: path.map(Path::to_path_buf),
+        reason: e.to_string(),
+    }
+}
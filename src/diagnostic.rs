@@ -0,0 +1,72 @@
+use erl_tokenize::Position;
+
+use crate::Error;
+
+/// Severity of a diagnostic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(
+    feature = "json",
+    derive(serde::Serialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum Severity {
+    /// Error.
+    Error,
+
+    /// Warning.
+    Warning,
+}
+
+/// A non-fatal issue reported by a `Preprocessor`.
+///
+/// See also: [Preprocessor::diagnostics()](crate::Preprocessor::diagnostics).
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    /// The severity of the issue.
+    pub severity: Severity,
+
+    /// The message of the issue.
+    pub message: String,
+
+    /// The start and end positions of the range on which the issue occurred.
+    pub span: Option<(Position, Position)>,
+
+    /// The stable code of the issue.
+    ///
+    /// Errors have the same codes as `Error::code()`.
+    /// Warnings have the following codes:
+    ///
+    /// - `EPP1001`: `-warning` directive
+    /// - `EPP1002`: macro redefinition (see `RedefinitionPolicy::Warn`)
+    /// - `EPP1003`: macro prevented from leaking out of an included file
+    /// - `EPP1004`: conditional directive closed in a different file
+    /// - `EPP1005`: undefined macro replaced with a placeholder
+    ///   (see `Preprocessor::set_undefined_macro_placeholder()`)
+    pub code: &'static str,
+}
+impl Diagnostic {
+    /// Makes a `Diagnostic` instance from an error.
+    pub fn from_error(error: &Error) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            message: error.message(),
+            span: error.span(),
+            code: error.code(),
+        }
+    }
+
+    pub(crate) fn new(
+        severity: Severity,
+        code: &'static str,
+        message: String,
+        start: Position,
+        end: Position,
+    ) -> Self {
+        Diagnostic {
+            severity,
+            message,
+            span: Some((start, end)),
+            code,
+        }
+    }
+}
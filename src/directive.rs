@@ -1,11 +1,13 @@
-use erl_tokenize::tokens::{AtomToken, SymbolToken};
-use erl_tokenize::values::Symbol;
+use erl_tokenize::tokens::{AtomToken, KeywordToken, SymbolToken};
+use erl_tokenize::values::{Keyword, Symbol};
 use erl_tokenize::{LexicalToken, Position, PositionRange};
 use std::fmt;
 
 use crate::directives;
 use crate::token_reader::{ReadFrom, TokenReader};
-use crate::{Error, Result};
+use crate::types::ToTokens;
+use crate::util;
+use crate::{Error, Result, SourceMap};
 
 /// Macro directive.
 #[derive(Debug, Clone)]
@@ -18,10 +20,26 @@ pub enum Directive {
     Undef(directives::Undef),
     Ifdef(directives::Ifdef),
     Ifndef(directives::Ifndef),
+    If(directives::If),
+    Elif(directives::Elif),
     Else(directives::Else),
     Endif(directives::Endif),
     Error(directives::Error),
     Warning(directives::Warning),
+    Feature(directives::Feature),
+}
+impl Directive {
+    /// Returns the original text of this directive which is taken from `sources`.
+    ///
+    /// Unlike `Display`, which normalizes the spacing, the text is sliced from the source
+    /// as it is (including the comments inside the directive).
+    /// Returns `None` if the text of the file containing this directive is unavailable.
+    pub fn source_text<S>(&self, sources: &S) -> Option<String>
+    where
+        S: SourceMap + ?Sized,
+    {
+        util::source_slice(sources, &self.start_position(), &self.end_position())
+    }
 }
 impl PositionRange for Directive {
     fn start_position(&self) -> Position {
@@ -32,10 +50,13 @@ impl PositionRange for Directive {
             Directive::Undef(ref t) => t.start_position(),
             Directive::Ifdef(ref t) => t.start_position(),
             Directive::Ifndef(ref t) => t.start_position(),
+            Directive::If(ref t) => t.start_position(),
+            Directive::Elif(ref t) => t.start_position(),
             Directive::Else(ref t) => t.start_position(),
             Directive::Endif(ref t) => t.start_position(),
             Directive::Error(ref t) => t.start_position(),
             Directive::Warning(ref t) => t.start_position(),
+            Directive::Feature(ref t) => t.start_position(),
         }
     }
     fn end_position(&self) -> Position {
@@ -46,10 +67,13 @@ impl PositionRange for Directive {
             Directive::Undef(ref t) => t.end_position(),
             Directive::Ifdef(ref t) => t.end_position(),
             Directive::Ifndef(ref t) => t.end_position(),
+            Directive::If(ref t) => t.end_position(),
+            Directive::Elif(ref t) => t.end_position(),
             Directive::Else(ref t) => t.end_position(),
             Directive::Endif(ref t) => t.end_position(),
             Directive::Error(ref t) => t.end_position(),
             Directive::Warning(ref t) => t.end_position(),
+            Directive::Feature(ref t) => t.end_position(),
         }
     }
 }
@@ -62,10 +86,32 @@ impl fmt::Display for Directive {
             Directive::Undef(ref t) => t.fmt(f),
             Directive::Ifdef(ref t) => t.fmt(f),
             Directive::Ifndef(ref t) => t.fmt(f),
+            Directive::If(ref t) => t.fmt(f),
+            Directive::Elif(ref t) => t.fmt(f),
             Directive::Else(ref t) => t.fmt(f),
             Directive::Endif(ref t) => t.fmt(f),
             Directive::Error(ref t) => t.fmt(f),
             Directive::Warning(ref t) => t.fmt(f),
+            Directive::Feature(ref t) => t.fmt(f),
+        }
+    }
+}
+impl ToTokens for Directive {
+    fn push_tokens(&self, tokens: &mut Vec<LexicalToken>) {
+        match *self {
+            Directive::Include(ref t) => t.push_tokens(tokens),
+            Directive::IncludeLib(ref t) => t.push_tokens(tokens),
+            Directive::Define(ref t) => t.push_tokens(tokens),
+            Directive::Undef(ref t) => t.push_tokens(tokens),
+            Directive::Ifdef(ref t) => t.push_tokens(tokens),
+            Directive::Ifndef(ref t) => t.push_tokens(tokens),
+            Directive::If(ref t) => t.push_tokens(tokens),
+            Directive::Elif(ref t) => t.push_tokens(tokens),
+            Directive::Else(ref t) => t.push_tokens(tokens),
+            Directive::Endif(ref t) => t.push_tokens(tokens),
+            Directive::Error(ref t) => t.push_tokens(tokens),
+            Directive::Warning(ref t) => t.push_tokens(tokens),
+            Directive::Feature(ref t) => t.push_tokens(tokens),
         }
     }
 }
@@ -75,6 +121,11 @@ impl ReadFrom for Directive {
         T: Iterator<Item = erl_tokenize::Result<LexicalToken>>,
     {
         let _hyphen: SymbolToken = reader.read_expected(&Symbol::Hyphen)?;
+        if let Some(_if) = reader.try_read_expected::<KeywordToken>(&Keyword::If)? {
+            reader.unread_token(_if.into());
+            reader.unread_token(_hyphen.into());
+            return reader.read().map(Directive::If);
+        }
         let name: AtomToken = reader
             .try_read()?
             .ok_or_else(|| Error::unexpected_token(_hyphen.clone().into(), "-{DIRECTIVE_NAME}"))?;
@@ -88,10 +139,12 @@ impl ReadFrom for Directive {
             "undef" => reader.read().map(Directive::Undef),
             "ifdef" => reader.read().map(Directive::Ifdef),
             "ifndef" => reader.read().map(Directive::Ifndef),
+            "elif" => reader.read().map(Directive::Elif),
             "else" => reader.read().map(Directive::Else),
             "endif" => reader.read().map(Directive::Endif),
             "error" => reader.read().map(Directive::Error),
             "warning" => reader.read().map(Directive::Warning),
+            "feature" => reader.read().map(Directive::Feature),
             _ => {
                 let _hyphen: SymbolToken = reader.read_expected(&Symbol::Hyphen)?;
                 Err(Error::unexpected_token(_hyphen.into(), "-{DIRECTIVE_NAME}"))
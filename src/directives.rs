@@ -1,16 +1,40 @@
 //! Macro directives.
-use erl_tokenize::tokens::{AtomToken, StringToken, SymbolToken};
-use erl_tokenize::values::Symbol;
+use erl_tokenize::tokens::{AtomToken, KeywordToken, StringToken, SymbolToken};
+use erl_tokenize::values::{Keyword, Symbol};
 use erl_tokenize::{LexicalToken, Position, PositionRange};
-use glob::glob;
+#[cfg(feature = "fs")]
 use std::collections::VecDeque;
 use std::fmt;
-use std::path::{Component, PathBuf};
+use std::path::PathBuf;
 
+#[cfg(feature = "fs")]
+use crate::resolver::{FileSystemResolver, IncludeKind, IncludeResolver};
 use crate::token_reader::{ReadFrom, TokenReader};
-use crate::types::{MacroName, MacroVariables};
-use crate::util;
-use crate::Result;
+use crate::types::{MacroName, MacroVariables, ToTokens, TokenVec};
+use crate::util::{self, EnvVars};
+use crate::{Result, SourceMap};
+
+macro_rules! impl_source_text {
+    ($($t:ty),*) => {
+        $(
+            impl $t {
+                /// Returns the original text of this directive which is taken from `sources`.
+                ///
+                /// See [Directive::source_text()](crate::Directive::source_text).
+                pub fn source_text<S>(&self, sources: &S) -> Option<String>
+                where
+                    S: SourceMap + ?Sized,
+                {
+                    util::source_slice(sources, &self.start_position(), &self.end_position())
+                }
+            }
+        )*
+    };
+}
+impl_source_text!(
+    Include, IncludeLib, Error, Warning, Endif, Else, Undef, Ifdef, Ifndef, If, Elif, Feature,
+    Define
+);
 
 /// `include` directive.
 ///
@@ -22,18 +46,45 @@ pub struct Include {
     pub _hyphen: SymbolToken,
     pub _include: AtomToken,
     pub _open_paren: SymbolToken,
-    pub path: StringToken,
+    pub path: Vec<LexicalToken>,
     pub _close_paren: SymbolToken,
     pub _dot: SymbolToken,
 }
 impl Include {
     /// Executes file inclusion.
+    #[cfg(feature = "fs")]
     pub fn include(&self) -> Result<(PathBuf, String)> {
-        let path = util::substitute_path_variables(self.path.value());
-        let text = util::read_file(&path)
-            .map_err(|e| crate::Error::include_file_error(e, self, path.clone()))?;
+        self.include_from_dirs(&[])
+    }
+
+    /// Executes file inclusion.
+    ///
+    /// If the target file is not found by the path as it is,
+    /// each of `include_dirs` is searched in order.
+    #[cfg(feature = "fs")]
+    pub fn include_from_dirs(&self, include_dirs: &[PathBuf]) -> Result<(PathBuf, String)> {
+        let path = self.target_path()?;
+        let (path, mut text) = FileSystemResolver
+            .resolve(IncludeKind::Include, &path, include_dirs)
+            .map_err(|e| crate::Error::include_file_error(e, self, path))?;
+        util::strip_bom(&mut text);
         Ok((path, text))
     }
+
+    /// Returns the path of the target file.
+    ///
+    /// Environment variables in the path are substituted (only if the `fs` feature is enabled).
+    /// Macros in the path must have been expanded beforehand.
+    pub fn target_path(&self) -> Result<PathBuf> {
+        self.target_path_with(&mut EnvVars::default())
+    }
+
+    pub(crate) fn target_path_with(&self, vars: &mut EnvVars) -> Result<PathBuf> {
+        Ok(util::substitute_path_variables(
+            path_string(&self.path)?,
+            vars,
+        ))
+    }
 }
 impl PositionRange for Include {
     fn start_position(&self) -> Position {
@@ -45,7 +96,21 @@ impl PositionRange for Include {
 }
 impl fmt::Display for Include {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "-include({}).", self.path.text())
+        write!(
+            f,
+            "-include({}).",
+            self.path.iter().map(LexicalToken::text).collect::<String>()
+        )
+    }
+}
+impl ToTokens for Include {
+    fn push_tokens(&self, tokens: &mut Vec<LexicalToken>) {
+        tokens.push(self._hyphen.clone().into());
+        tokens.push(self._include.clone().into());
+        tokens.push(self._open_paren.clone().into());
+        tokens.extend(self.path.iter().cloned());
+        tokens.push(self._close_paren.clone().into());
+        tokens.push(self._dot.clone().into());
     }
 }
 impl ReadFrom for Include {
@@ -53,13 +118,17 @@ impl ReadFrom for Include {
     where
         T: Iterator<Item = erl_tokenize::Result<LexicalToken>>,
     {
+        let _hyphen = reader.read_expected(&Symbol::Hyphen)?;
+        let _include = reader.read_expected("include")?;
+        let _open_paren = reader.read_expected(&Symbol::OpenParen)?;
+        let (path, _close_paren, _dot) = read_until_end(reader)?;
         Ok(Include {
-            _hyphen: reader.read_expected(&Symbol::Hyphen)?,
-            _include: reader.read_expected("include")?,
-            _open_paren: reader.read_expected(&Symbol::OpenParen)?,
-            path: reader.read()?,
-            _close_paren: reader.read_expected(&Symbol::CloseParen)?,
-            _dot: reader.read_expected(&Symbol::Dot)?,
+            _hyphen,
+            _include,
+            _open_paren,
+            path,
+            _close_paren,
+            _dot,
         })
     }
 }
@@ -74,40 +143,40 @@ pub struct IncludeLib {
     pub _hyphen: SymbolToken,
     pub _include_lib: AtomToken,
     pub _open_paren: SymbolToken,
-    pub path: StringToken,
+    pub path: Vec<LexicalToken>,
     pub _close_paren: SymbolToken,
     pub _dot: SymbolToken,
 }
 impl IncludeLib {
     /// Executes file inclusion.
+    ///
+    /// The application directory is searched in `code_paths` by the `{APP_NAME}-*` pattern first.
+    /// If no such directory is found, directories which are named `{APP_NAME}` or
+    /// contain `ebin/{APP_NAME}.app` or `src/{APP_NAME}.app.src` are used instead.
+    #[cfg(feature = "fs")]
     pub fn include_lib(&self, code_paths: &VecDeque<PathBuf>) -> Result<(PathBuf, String)> {
-        let mut path = util::substitute_path_variables(self.path.value());
+        let path = self.target_path()?;
+        let code_paths = code_paths.iter().cloned().collect::<Vec<_>>();
+        let (path, mut text) = FileSystemResolver
+            .resolve(IncludeKind::IncludeLib, &path, &code_paths)
+            .map_err(|e| crate::Error::include_file_error(e, self, path))?;
+        util::strip_bom(&mut text);
+        Ok((path, text))
+    }
 
-        let temp_path = path.clone();
-        let mut components = temp_path.components();
-        if let Some(Component::Normal(app_name)) = components.next() {
-            let app_name = app_name
-                .to_str()
-                .ok_or_else(|| crate::Error::non_utf8_path(&app_name))?;
-            let pattern = format!("{}-*", app_name);
-            'root: for root in code_paths.iter() {
-                let pattern = root.join(&pattern);
-                let pattern = pattern
-                    .to_str()
-                    .ok_or_else(|| crate::Error::non_utf8_path(&pattern))?;
-                if let Some(entry) = glob(pattern)?.next() {
-                    path = entry?;
-                    for c in components {
-                        path.push(c.as_os_str());
-                    }
-                    break 'root;
-                }
-            }
-        }
+    /// Returns the path of the target file.
+    ///
+    /// Environment variables in the path are substituted (only if the `fs` feature is enabled).
+    /// Macros in the path must have been expanded beforehand.
+    pub fn target_path(&self) -> Result<PathBuf> {
+        self.target_path_with(&mut EnvVars::default())
+    }
 
-        let text = util::read_file(&path)
-            .map_err(|e| crate::Error::include_file_error(e, self, path.clone()))?;
-        Ok((path, text))
+    pub(crate) fn target_path_with(&self, vars: &mut EnvVars) -> Result<PathBuf> {
+        Ok(util::substitute_path_variables(
+            path_string(&self.path)?,
+            vars,
+        ))
     }
 }
 impl PositionRange for IncludeLib {
@@ -120,7 +189,21 @@ impl PositionRange for IncludeLib {
 }
 impl fmt::Display for IncludeLib {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "-include_lib({}).", self.path.text())
+        write!(
+            f,
+            "-include_lib({}).",
+            self.path.iter().map(LexicalToken::text).collect::<String>()
+        )
+    }
+}
+impl ToTokens for IncludeLib {
+    fn push_tokens(&self, tokens: &mut Vec<LexicalToken>) {
+        tokens.push(self._hyphen.clone().into());
+        tokens.push(self._include_lib.clone().into());
+        tokens.push(self._open_paren.clone().into());
+        tokens.extend(self.path.iter().cloned());
+        tokens.push(self._close_paren.clone().into());
+        tokens.push(self._dot.clone().into());
     }
 }
 impl ReadFrom for IncludeLib {
@@ -128,13 +211,17 @@ impl ReadFrom for IncludeLib {
     where
         T: Iterator<Item = erl_tokenize::Result<LexicalToken>>,
     {
+        let _hyphen = reader.read_expected(&Symbol::Hyphen)?;
+        let _include_lib = reader.read_expected("include_lib")?;
+        let _open_paren = reader.read_expected(&Symbol::OpenParen)?;
+        let (path, _close_paren, _dot) = read_until_end(reader)?;
         Ok(IncludeLib {
-            _hyphen: reader.read_expected(&Symbol::Hyphen)?,
-            _include_lib: reader.read_expected("include_lib")?,
-            _open_paren: reader.read_expected(&Symbol::OpenParen)?,
-            path: reader.read()?,
-            _close_paren: reader.read_expected(&Symbol::CloseParen)?,
-            _dot: reader.read_expected(&Symbol::Dot)?,
+            _hyphen,
+            _include_lib,
+            _open_paren,
+            path,
+            _close_paren,
+            _dot,
         })
     }
 }
@@ -168,6 +255,16 @@ impl fmt::Display for Error {
         write!(f, "-error({}).", self.message.text())
     }
 }
+impl ToTokens for Error {
+    fn push_tokens(&self, tokens: &mut Vec<LexicalToken>) {
+        tokens.push(self._hyphen.clone().into());
+        tokens.push(self._error.clone().into());
+        tokens.push(self._open_paren.clone().into());
+        tokens.push(self.message.clone().into());
+        tokens.push(self._close_paren.clone().into());
+        tokens.push(self._dot.clone().into());
+    }
+}
 impl ReadFrom for Error {
     fn read_from<T>(reader: &mut TokenReader<T>) -> Result<Self>
     where
@@ -213,6 +310,16 @@ impl fmt::Display for Warning {
         write!(f, "-warning({}).", self.message.text())
     }
 }
+impl ToTokens for Warning {
+    fn push_tokens(&self, tokens: &mut Vec<LexicalToken>) {
+        tokens.push(self._hyphen.clone().into());
+        tokens.push(self._warning.clone().into());
+        tokens.push(self._open_paren.clone().into());
+        tokens.push(self.message.clone().into());
+        tokens.push(self._close_paren.clone().into());
+        tokens.push(self._dot.clone().into());
+    }
+}
 impl ReadFrom for Warning {
     fn read_from<T>(reader: &mut TokenReader<T>) -> Result<Self>
     where
@@ -254,6 +361,13 @@ impl fmt::Display for Endif {
         write!(f, "-endif.")
     }
 }
+impl ToTokens for Endif {
+    fn push_tokens(&self, tokens: &mut Vec<LexicalToken>) {
+        tokens.push(self._hyphen.clone().into());
+        tokens.push(self._endif.clone().into());
+        tokens.push(self._dot.clone().into());
+    }
+}
 impl ReadFrom for Endif {
     fn read_from<T>(reader: &mut TokenReader<T>) -> Result<Self>
     where
@@ -292,6 +406,13 @@ impl fmt::Display for Else {
         write!(f, "-else.")
     }
 }
+impl ToTokens for Else {
+    fn push_tokens(&self, tokens: &mut Vec<LexicalToken>) {
+        tokens.push(self._hyphen.clone().into());
+        tokens.push(self._else.clone().into());
+        tokens.push(self._dot.clone().into());
+    }
+}
 impl ReadFrom for Else {
     fn read_from<T>(reader: &mut TokenReader<T>) -> Result<Self>
     where
@@ -333,6 +454,16 @@ impl fmt::Display for Undef {
         write!(f, "-undef({}).", self.name.text())
     }
 }
+impl ToTokens for Undef {
+    fn push_tokens(&self, tokens: &mut Vec<LexicalToken>) {
+        tokens.push(self._hyphen.clone().into());
+        tokens.push(self._undef.clone().into());
+        tokens.push(self._open_paren.clone().into());
+        self.name.push_tokens(tokens);
+        tokens.push(self._close_paren.clone().into());
+        tokens.push(self._dot.clone().into());
+    }
+}
 impl ReadFrom for Undef {
     fn read_from<T>(reader: &mut TokenReader<T>) -> Result<Self>
     where
@@ -377,6 +508,16 @@ impl fmt::Display for Ifdef {
         write!(f, "-ifdef({}).", self.name.text())
     }
 }
+impl ToTokens for Ifdef {
+    fn push_tokens(&self, tokens: &mut Vec<LexicalToken>) {
+        tokens.push(self._hyphen.clone().into());
+        tokens.push(self._ifdef.clone().into());
+        tokens.push(self._open_paren.clone().into());
+        self.name.push_tokens(tokens);
+        tokens.push(self._close_paren.clone().into());
+        tokens.push(self._dot.clone().into());
+    }
+}
 impl ReadFrom for Ifdef {
     fn read_from<T>(reader: &mut TokenReader<T>) -> Result<Self>
     where
@@ -421,6 +562,16 @@ impl fmt::Display for Ifndef {
         write!(f, "-ifndef({}).", self.name.text())
     }
 }
+impl ToTokens for Ifndef {
+    fn push_tokens(&self, tokens: &mut Vec<LexicalToken>) {
+        tokens.push(self._hyphen.clone().into());
+        tokens.push(self._ifndef.clone().into());
+        tokens.push(self._open_paren.clone().into());
+        self.name.push_tokens(tokens);
+        tokens.push(self._close_paren.clone().into());
+        tokens.push(self._dot.clone().into());
+    }
+}
 impl ReadFrom for Ifndef {
     fn read_from<T>(reader: &mut TokenReader<T>) -> Result<Self>
     where
@@ -437,6 +588,254 @@ impl ReadFrom for Ifndef {
     }
 }
 
+/// `if` directive.
+///
+/// See [9.5 Flow Control in Macros][flow_control] for detailed information.
+///
+/// [flow_control]: http://erlang.org/doc/reference_manual/macros.html#id85859
+#[derive(Debug, Clone)]
+#[allow(missing_docs)]
+pub struct If {
+    pub _hyphen: SymbolToken,
+    pub _if: KeywordToken,
+    pub _open_paren: SymbolToken,
+    pub condition: Vec<LexicalToken>,
+    pub _close_paren: SymbolToken,
+    pub _dot: SymbolToken,
+}
+impl PositionRange for If {
+    fn start_position(&self) -> Position {
+        self._hyphen.start_position()
+    }
+    fn end_position(&self) -> Position {
+        self._dot.end_position()
+    }
+}
+impl fmt::Display for If {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "-if({}).",
+            self.condition
+                .iter()
+                .map(LexicalToken::text)
+                .collect::<String>()
+        )
+    }
+}
+impl ToTokens for If {
+    fn push_tokens(&self, tokens: &mut Vec<LexicalToken>) {
+        tokens.push(self._hyphen.clone().into());
+        tokens.push(self._if.clone().into());
+        tokens.push(self._open_paren.clone().into());
+        tokens.extend(self.condition.iter().cloned());
+        tokens.push(self._close_paren.clone().into());
+        tokens.push(self._dot.clone().into());
+    }
+}
+impl ReadFrom for If {
+    fn read_from<T>(reader: &mut TokenReader<T>) -> Result<Self>
+    where
+        T: Iterator<Item = erl_tokenize::Result<LexicalToken>>,
+    {
+        let _hyphen = reader.read_expected(&Symbol::Hyphen)?;
+        let _if = reader.read_expected(&Keyword::If)?;
+        let _open_paren = reader.read_expected(&Symbol::OpenParen)?;
+        let (condition, _close_paren, _dot) = read_until_end(reader)?;
+        Ok(If {
+            _hyphen,
+            _if,
+            _open_paren,
+            condition,
+            _close_paren,
+            _dot,
+        })
+    }
+}
+
+/// `elif` directive.
+///
+/// See [9.5 Flow Control in Macros][flow_control] for detailed information.
+///
+/// [flow_control]: http://erlang.org/doc/reference_manual/macros.html#id85859
+#[derive(Debug, Clone)]
+#[allow(missing_docs)]
+pub struct Elif {
+    pub _hyphen: SymbolToken,
+    pub _elif: AtomToken,
+    pub _open_paren: SymbolToken,
+    pub condition: Vec<LexicalToken>,
+    pub _close_paren: SymbolToken,
+    pub _dot: SymbolToken,
+}
+impl PositionRange for Elif {
+    fn start_position(&self) -> Position {
+        self._hyphen.start_position()
+    }
+    fn end_position(&self) -> Position {
+        self._dot.end_position()
+    }
+}
+impl fmt::Display for Elif {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "-elif({}).",
+            self.condition
+                .iter()
+                .map(LexicalToken::text)
+                .collect::<String>()
+        )
+    }
+}
+impl ToTokens for Elif {
+    fn push_tokens(&self, tokens: &mut Vec<LexicalToken>) {
+        tokens.push(self._hyphen.clone().into());
+        tokens.push(self._elif.clone().into());
+        tokens.push(self._open_paren.clone().into());
+        tokens.extend(self.condition.iter().cloned());
+        tokens.push(self._close_paren.clone().into());
+        tokens.push(self._dot.clone().into());
+    }
+}
+impl ReadFrom for Elif {
+    fn read_from<T>(reader: &mut TokenReader<T>) -> Result<Self>
+    where
+        T: Iterator<Item = erl_tokenize::Result<LexicalToken>>,
+    {
+        let _hyphen = reader.read_expected(&Symbol::Hyphen)?;
+        let _elif = reader.read_expected("elif")?;
+        let _open_paren = reader.read_expected(&Symbol::OpenParen)?;
+        let (condition, _close_paren, _dot) = read_until_end(reader)?;
+        Ok(Elif {
+            _hyphen,
+            _elif,
+            _open_paren,
+            condition,
+            _close_paren,
+            _dot,
+        })
+    }
+}
+
+/// Reads the tokens followed by `).`.
+fn read_until_end<T>(
+    reader: &mut TokenReader<T>,
+) -> Result<(Vec<LexicalToken>, SymbolToken, SymbolToken)>
+where
+    T: Iterator<Item = erl_tokenize::Result<LexicalToken>>,
+{
+    let mut tokens = Vec::new();
+    loop {
+        if let Some(_close_paren) = reader.try_read_expected(&Symbol::CloseParen)? {
+            if let Some(_dot) = reader.try_read_expected(&Symbol::Dot)? {
+                return Ok((tokens, _close_paren, _dot));
+            }
+            tokens.push(_close_paren.into());
+        } else {
+            let token = reader.read_token()?;
+            if token
+                .as_symbol_token()
+                .is_some_and(|s| s.value() == Symbol::Dot)
+            {
+                return Err(crate::Error::unexpected_token(token, "')'"));
+            }
+            tokens.push(token);
+        }
+    }
+}
+
+/// Concatenates the string literals of the path of `-include` or `-include_lib` directive.
+///
+/// Macros in the path must have been expanded beforehand.
+fn path_string(path: &[LexicalToken]) -> Result<String> {
+    let mut s = String::new();
+    for token in path {
+        match token.as_string_token() {
+            Some(t) => s.push_str(t.value()),
+            None => return Err(crate::Error::unexpected_token(token.clone(), "string")),
+        }
+    }
+    Ok(s)
+}
+
+/// `feature` directive (OTP 25 or later).
+///
+/// See [Features](https://www.erlang.org/doc/reference_manual/features.html)
+/// for detailed information.
+#[derive(Debug, Clone)]
+#[allow(missing_docs)]
+pub struct Feature {
+    pub _hyphen: SymbolToken,
+    pub _feature: AtomToken,
+    pub _open_paren: SymbolToken,
+    pub name: AtomToken,
+    pub _comma: SymbolToken,
+    pub action: AtomToken,
+    pub _close_paren: SymbolToken,
+    pub _dot: SymbolToken,
+}
+impl Feature {
+    /// Returns `true` if this directive enables the feature, or `false` if it disables the feature.
+    pub fn enable(&self) -> bool {
+        self.action.value() == "enable"
+    }
+}
+impl PositionRange for Feature {
+    fn start_position(&self) -> Position {
+        self._hyphen.start_position()
+    }
+    fn end_position(&self) -> Position {
+        self._dot.end_position()
+    }
+}
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "-feature({}, {}).", self.name.text(), self.action.text())
+    }
+}
+impl ToTokens for Feature {
+    fn push_tokens(&self, tokens: &mut Vec<LexicalToken>) {
+        tokens.push(self._hyphen.clone().into());
+        tokens.push(self._feature.clone().into());
+        tokens.push(self._open_paren.clone().into());
+        tokens.push(self.name.clone().into());
+        tokens.push(self._comma.clone().into());
+        tokens.push(self.action.clone().into());
+        tokens.push(self._close_paren.clone().into());
+        tokens.push(self._dot.clone().into());
+    }
+}
+impl ReadFrom for Feature {
+    fn read_from<T>(reader: &mut TokenReader<T>) -> Result<Self>
+    where
+        T: Iterator<Item = erl_tokenize::Result<LexicalToken>>,
+    {
+        let _hyphen = reader.read_expected(&Symbol::Hyphen)?;
+        let _feature = reader.read_expected("feature")?;
+        let _open_paren = reader.read_expected(&Symbol::OpenParen)?;
+        let name = reader.read()?;
+        let _comma = reader.read_expected(&Symbol::Comma)?;
+        let action: AtomToken = reader.read()?;
+        if action.value() != "enable" && action.value() != "disable" {
+            return Err(crate::Error::unexpected_token(
+                action.into(),
+                "'enable' or 'disable'",
+            ));
+        }
+        Ok(Feature {
+            _hyphen,
+            _feature,
+            _open_paren,
+            name,
+            _comma,
+            action,
+            _close_paren: reader.read_expected(&Symbol::CloseParen)?,
+            _dot: reader.read_expected(&Symbol::Dot)?,
+        })
+    }
+}
+
 /// `define` directive.
 ///
 /// See [9.2 Defining and Using Macros][define_and_use] for detailed information.
@@ -451,10 +850,28 @@ pub struct Define {
     pub name: MacroName,
     pub variables: Option<MacroVariables>,
     pub _comma: SymbolToken,
-    pub replacement: Vec<LexicalToken>,
+    pub replacement: TokenVec,
     pub _close_paren: SymbolToken,
     pub _dot: SymbolToken,
 }
+impl Define {
+    /// Returns the original text of the replacement of this macro which is taken from `sources`.
+    ///
+    /// Unlike `Display`, the text is sliced from the source as it is
+    /// (an empty replacement results in an empty string).
+    /// Returns `None` if the text of the file containing this macro is unavailable.
+    pub fn raw_replacement_text<S>(&self, sources: &S) -> Option<String>
+    where
+        S: SourceMap + ?Sized,
+    {
+        match (self.replacement.first(), self.replacement.last()) {
+            (Some(first), Some(last)) => {
+                util::source_slice(sources, &first.start_position(), &last.end_position())
+            }
+            _ => Some(String::new()),
+        }
+    }
+}
 impl PositionRange for Define {
     fn start_position(&self) -> Position {
         self._hyphen.start_position()
@@ -479,6 +896,21 @@ impl fmt::Display for Define {
         )
     }
 }
+impl ToTokens for Define {
+    fn push_tokens(&self, tokens: &mut Vec<LexicalToken>) {
+        tokens.push(self._hyphen.clone().into());
+        tokens.push(self._define.clone().into());
+        tokens.push(self._open_paren.clone().into());
+        self.name.push_tokens(tokens);
+        if let Some(ref variables) = self.variables {
+            variables.push_tokens(tokens);
+        }
+        tokens.push(self._comma.clone().into());
+        tokens.extend(self.replacement.iter().cloned());
+        tokens.push(self._close_paren.clone().into());
+        tokens.push(self._dot.clone().into());
+    }
+}
 impl ReadFrom for Define {
     fn read_from<T>(reader: &mut TokenReader<T>) -> Result<Self>
     where
@@ -497,7 +929,7 @@ impl ReadFrom for Define {
             };
         let _comma = reader.read_expected(&Symbol::Comma)?;
 
-        let mut replacement = Vec::new();
+        let mut replacement = TokenVec::new();
         loop {
             if let Some(_close_paren) = reader.try_read_expected(&Symbol::CloseParen)? {
                 if let Some(_dot) = reader.try_read_expected(&Symbol::Dot)? {
@@ -518,7 +950,7 @@ impl ReadFrom for Define {
                 let token = reader.read_token()?;
                 if token
                     .as_symbol_token()
-                    .map_or(false, |s| s.value() == Symbol::Dot)
+                    .is_some_and(|s| s.value() == Symbol::Dot)
                 {
                     return Err(crate::Error::unexpected_dot_in_macro_def(&token));
                 }
@@ -1,7 +1,15 @@
 use crate::directive::Directive;
 use crate::macros::{MacroCall, MacroDef};
+use crate::preprocessor::MacroRedefinition;
+#[cfg(feature = "fs")]
+use crate::resolver::FileSystemResolver;
+use crate::resolver::MemoryIncludes;
+#[cfg(feature = "fs")]
+use crate::util;
 use erl_tokenize::tokens::SymbolToken;
 use erl_tokenize::{LexicalToken, Position, PositionRange};
+use std::borrow::Cow;
+use std::fmt;
 use std::path::{Path, PathBuf};
 
 /// Possible errors.
@@ -11,30 +19,41 @@ use std::path::{Path, PathBuf};
 #[allow(clippy::large_enum_variant)]
 pub enum Error {
     /// Unexpected token.
-    #[error("expected a {expected:?} token, but found {token:?}")]
+    #[error("expected a {expected:?} token, but found {token:?}{}{}", include_suffix(.include_stack), line_suffix(.source_line))]
     UnexpectedToken {
         token: LexicalToken,
         expected: String,
+        source_line: Option<SourceLine>,
+        include_stack: Vec<Position>,
     },
 
     /// Include file error.
-    #[error("cannot include file: path={target_file_path:?}, reason={source}")]
+    #[error("cannot include file: path={target_file_path:?}, reason={source}{}{}", include_suffix(.include_stack), line_suffix(.source_line))]
     IncludeFileError {
         source: std::io::Error,
         directive_start: Position,
         directive_end: Position,
         target_file_path: PathBuf,
+        source_line: Option<SourceLine>,
+        include_stack: Vec<Position>,
     },
 
     /// Missing a macro argument.
-    #[error("expected an macro argument before ',' ({position})")]
-    MissingMacroArg { position: Position },
+    #[error("expected an macro argument before ',' ({position}){}{}", include_suffix(.include_stack), line_suffix(.source_line))]
+    MissingMacroArg {
+        position: Position,
+        end_position: Position,
+        source_line: Option<SourceLine>,
+        include_stack: Vec<Position>,
+    },
 
     /// Unbalanced parentheses.
-    #[error("unbalanced parentheses: open={open:?}, close={close:?}")]
+    #[error("unbalanced parentheses: open={open:?}, close={close:?}{}{}", include_suffix(.include_stack), line_suffix(.source_line))]
     UnbalancedParen {
         open: Option<SymbolToken>,
         close: SymbolToken,
+        source_line: Option<SourceLine>,
+        include_stack: Vec<Position>,
     },
 
     /// Unexpected EOF.
@@ -42,22 +61,36 @@ pub enum Error {
     UnexpectedEof,
 
     /// Cannot expand ?FILE macro.
-    #[error("cannot expand ?FILE macro ({macro_call:?})")]
-    FileNotSet { macro_call: MacroCall },
+    #[error("cannot expand ?FILE macro ({macro_call:?}){}{}", include_suffix(.include_stack), line_suffix(.source_line))]
+    FileNotSet {
+        macro_call: MacroCall,
+        source_line: Option<SourceLine>,
+        include_stack: Vec<Position>,
+    },
 
     /// Undefined macro.
-    #[error("undefined macro: {macro_call:?}")]
-    UndefinedMacro { macro_call: MacroCall },
+    #[error("undefined macro: {macro_call:?}{}{}", include_suffix(.include_stack), line_suffix(.source_line))]
+    UndefinedMacro {
+        macro_call: MacroCall,
+        source_line: Option<SourceLine>,
+        include_stack: Vec<Position>,
+    },
 
     /// Undefined macro variable.
     #[error("no such macro variable: {varname:?}")]
-    UndefinedMacroVar { varname: String },
+    UndefinedMacroVar {
+        varname: String,
+        position: Option<Position>,
+        end_position: Option<Position>,
+    },
 
     /// Macro arguments mismatched.
-    #[error("macro arguments mismatched: def={macro_def:?}, call={macro_call:?}")]
+    #[error("macro arguments mismatched: def={macro_def:?}, call={macro_call:?}{}{}", include_suffix(.include_stack), line_suffix(.source_line))]
     MacroArgsMismatched {
         macro_call: MacroCall,
         macro_def: MacroDef,
+        source_line: Option<SourceLine>,
+        include_stack: Vec<Position>,
     },
 
     /// Non UTF-8 path.
@@ -65,31 +98,397 @@ pub enum Error {
     NonUtf8Path { path: PathBuf },
 
     /// Unexpected '.' in `-define` directive.
-    #[error("found unexpected '.' in `-define` directive ({position})")]
-    UnexpectedDotInMacroDef { position: Position },
+    #[error("found unexpected '.' in `-define` directive ({position}){}{}", include_suffix(.include_stack), line_suffix(.source_line))]
+    UnexpectedDotInMacroDef {
+        position: Position,
+        end_position: Position,
+        source_line: Option<SourceLine>,
+        include_stack: Vec<Position>,
+    },
+
+    /// Missing `-if`, `-ifdef` or `-ifndef`.
+    #[error("missing `-if`, `-ifdef` or `-ifndef` directives{}{}", include_suffix(.include_stack), line_suffix(.source_line))]
+    MissingIfDirective {
+        directive: Directive,
+        source_line: Option<SourceLine>,
+        include_stack: Vec<Position>,
+    },
+
+    /// Macro redefinition.
+    #[error("redefining macro {:?} (previous definition: {}){}{}", .redefinition.name, position_text(&.redefinition.old_position), include_suffix(.include_stack), line_suffix(.source_line))]
+    MacroRedefined {
+        redefinition: MacroRedefinition,
+        end_position: Position,
+        source_line: Option<SourceLine>,
+        include_stack: Vec<Position>,
+    },
+
+    /// Recursive macro expansion.
+    #[error("recursive macro expansion: {macro_call:?}{}{}", include_suffix(.include_stack), line_suffix(.source_line))]
+    RecursiveMacro {
+        macro_call: MacroCall,
+        source_line: Option<SourceLine>,
+        include_stack: Vec<Position>,
+    },
 
-    /// Missing `-ifdef` or `-ifndef`.
-    #[error("missing `-ifdef` or `ifndef` directives")]
-    MissingIfDirective { directive: Directive },
+    /// Too deep macro expansion.
+    #[error("macro expansion exceeded the maximum depth {max_depth}: {macro_call:?}{}{}", include_suffix(.include_stack), line_suffix(.source_line))]
+    MacroExpansionTooDeep {
+        macro_call: MacroCall,
+        max_depth: usize,
+        source_line: Option<SourceLine>,
+        include_stack: Vec<Position>,
+    },
+
+    /// `-define` or `-undef` of a predefined macro.
+    #[error("cannot define or undefine the predefined macro {name:?} ({position}){}{}", include_suffix(.include_stack), line_suffix(.source_line))]
+    PredefinedMacroDirective {
+        name: String,
+        position: Position,
+        end_position: Position,
+        source_line: Option<SourceLine>,
+        include_stack: Vec<Position>,
+    },
+
+    /// `-error` directive.
+    #[error("-error: {message} ({position}){}{}", include_suffix(.include_stack), line_suffix(.source_line))]
+    ErrorDirective {
+        message: String,
+        position: Position,
+        end_position: Position,
+        source_line: Option<SourceLine>,
+        include_stack: Vec<Position>,
+    },
+
+    /// Invalid macro definition string (e.g., `erlc -D` option).
+    #[error("invalid macro definition: {definition:?}")]
+    InvalidMacroDefinition { definition: String },
+
+    /// Invalid condition expression.
+    #[error("invalid condition expression: {reason}{}{}", include_suffix(.include_stack), line_suffix(.source_line))]
+    InvalidCondition {
+        position: Option<Position>,
+        reason: String,
+        source_line: Option<SourceLine>,
+        include_stack: Vec<Position>,
+    },
+
+    /// Cannot read a source file.
+    #[error("cannot read source file: path={path:?}, reason={source}")]
+    SourceFileError {
+        source: std::io::Error,
+        path: PathBuf,
+    },
 
     /// Tokenize error.
     #[error(transparent)]
     TokenizeError(#[from] erl_tokenize::Error),
 
     /// Glob pattern error.
+    #[cfg(feature = "fs")]
     #[error(transparent)]
     GlobPatternError(#[from] glob::PatternError),
 
     /// Glob error.
+    #[cfg(feature = "fs")]
     #[error(transparent)]
     GlobError(#[from] glob::GlobError),
+
+    /// Invalid configuration file.
+    #[error("invalid configuration: path={path:?}, reason={reason}")]
+    ConfigError {
+        path: Option<PathBuf>,
+        reason: String,
+    },
 }
 
 impl Error {
+    /// Returns the text of the source line on which this error occurred.
+    ///
+    /// This is available only if the error has been raised by a `Preprocessor`
+    /// and the text of the file was held by it (i.e., an included file)
+    /// or provided via [Preprocessor::set_source_map()](crate::Preprocessor::set_source_map).
+    pub fn source_line(&self) -> Option<&SourceLine> {
+        match self {
+            Self::UnexpectedToken { source_line, .. }
+            | Self::IncludeFileError { source_line, .. }
+            | Self::MissingMacroArg { source_line, .. }
+            | Self::UnbalancedParen { source_line, .. }
+            | Self::FileNotSet { source_line, .. }
+            | Self::UndefinedMacro { source_line, .. }
+            | Self::MacroArgsMismatched { source_line, .. }
+            | Self::UnexpectedDotInMacroDef { source_line, .. }
+            | Self::MissingIfDirective { source_line, .. }
+            | Self::MacroRedefined { source_line, .. }
+            | Self::RecursiveMacro { source_line, .. }
+            | Self::MacroExpansionTooDeep { source_line, .. }
+            | Self::PredefinedMacroDirective { source_line, .. }
+            | Self::ErrorDirective { source_line, .. }
+            | Self::InvalidCondition { source_line, .. } => source_line.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Returns the positions of the include directives through which
+    /// the file containing the error position was included (innermost first).
+    ///
+    /// This is available only if the error has been raised by a `Preprocessor`.
+    pub fn include_stack(&self) -> &[Position] {
+        match self {
+            Self::UnexpectedToken { include_stack, .. }
+            | Self::IncludeFileError { include_stack, .. }
+            | Self::MissingMacroArg { include_stack, .. }
+            | Self::UnbalancedParen { include_stack, .. }
+            | Self::FileNotSet { include_stack, .. }
+            | Self::UndefinedMacro { include_stack, .. }
+            | Self::MacroArgsMismatched { include_stack, .. }
+            | Self::UnexpectedDotInMacroDef { include_stack, .. }
+            | Self::MissingIfDirective { include_stack, .. }
+            | Self::MacroRedefined { include_stack, .. }
+            | Self::RecursiveMacro { include_stack, .. }
+            | Self::MacroExpansionTooDeep { include_stack, .. }
+            | Self::PredefinedMacroDirective { include_stack, .. }
+            | Self::ErrorDirective { include_stack, .. }
+            | Self::InvalidCondition { include_stack, .. } => include_stack,
+            _ => &[],
+        }
+    }
+
+    pub(crate) fn set_include_stack(&mut self, stack: Vec<Position>) {
+        match self {
+            Self::UnexpectedToken { include_stack, .. }
+            | Self::IncludeFileError { include_stack, .. }
+            | Self::MissingMacroArg { include_stack, .. }
+            | Self::UnbalancedParen { include_stack, .. }
+            | Self::FileNotSet { include_stack, .. }
+            | Self::UndefinedMacro { include_stack, .. }
+            | Self::MacroArgsMismatched { include_stack, .. }
+            | Self::UnexpectedDotInMacroDef { include_stack, .. }
+            | Self::MissingIfDirective { include_stack, .. }
+            | Self::MacroRedefined { include_stack, .. }
+            | Self::RecursiveMacro { include_stack, .. }
+            | Self::MacroExpansionTooDeep { include_stack, .. }
+            | Self::PredefinedMacroDirective { include_stack, .. }
+            | Self::ErrorDirective { include_stack, .. }
+            | Self::InvalidCondition { include_stack, .. } => *include_stack = stack,
+            _ => {}
+        }
+    }
+
+    /// Returns the start and end positions of the range on which this error occurred.
+    ///
+    /// The file of the range is available via `Position::filepath()`.
+    /// If the end of the range is unknown (e.g., `InvalidCondition`),
+    /// the end position is the same as the start position.
+    /// `None` is returned for errors which are not related to a source range
+    /// (e.g., `UnexpectedEof` and `InvalidMacroDefinition`).
+    pub fn span(&self) -> Option<(Position, Position)> {
+        match self {
+            Self::UnexpectedToken { token, .. } => {
+                Some((token.start_position(), token.end_position()))
+            }
+            Self::IncludeFileError {
+                directive_start,
+                directive_end,
+                ..
+            } => Some((directive_start.clone(), directive_end.clone())),
+            Self::MissingMacroArg {
+                position,
+                end_position,
+                ..
+            }
+            | Self::UnexpectedDotInMacroDef {
+                position,
+                end_position,
+                ..
+            }
+            | Self::PredefinedMacroDirective {
+                position,
+                end_position,
+                ..
+            }
+            | Self::ErrorDirective {
+                position,
+                end_position,
+                ..
+            } => Some((position.clone(), end_position.clone())),
+            Self::UnbalancedParen { close, .. } => {
+                Some((close.start_position(), close.end_position()))
+            }
+            Self::FileNotSet { macro_call, .. }
+            | Self::UndefinedMacro { macro_call, .. }
+            | Self::MacroArgsMismatched { macro_call, .. }
+            | Self::RecursiveMacro { macro_call, .. }
+            | Self::MacroExpansionTooDeep { macro_call, .. } => {
+                Some((macro_call.start_position(), macro_call.end_position()))
+            }
+            Self::UndefinedMacroVar {
+                position: Some(position),
+                end_position: Some(end_position),
+                ..
+            } => Some((position.clone(), end_position.clone())),
+            Self::MissingIfDirective { directive, .. } => {
+                Some((directive.start_position(), directive.end_position()))
+            }
+            Self::MacroRedefined {
+                redefinition,
+                end_position,
+                ..
+            } => Some((redefinition.new_position.clone(), end_position.clone())),
+            Self::InvalidCondition {
+                position: Some(position),
+                ..
+            } => Some((position.clone(), position.clone())),
+            _ => None,
+        }
+    }
+
+    /// Returns the stable code of this error (e.g., `"EPP0007"` for `UndefinedMacro`).
+    ///
+    /// Codes are never changed nor reused once assigned,
+    /// so they can be used to filter or document diagnostics.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::UnexpectedToken { .. } => "EPP0001",
+            Self::IncludeFileError { .. } => "EPP0002",
+            Self::MissingMacroArg { .. } => "EPP0003",
+            Self::UnbalancedParen { .. } => "EPP0004",
+            Self::UnexpectedEof => "EPP0005",
+            Self::FileNotSet { .. } => "EPP0006",
+            Self::UndefinedMacro { .. } => "EPP0007",
+            Self::UndefinedMacroVar { .. } => "EPP0008",
+            Self::MacroArgsMismatched { .. } => "EPP0009",
+            Self::NonUtf8Path { .. } => "EPP0010",
+            Self::UnexpectedDotInMacroDef { .. } => "EPP0011",
+            Self::MissingIfDirective { .. } => "EPP0012",
+            Self::MacroRedefined { .. } => "EPP0013",
+            Self::RecursiveMacro { .. } => "EPP0014",
+            Self::MacroExpansionTooDeep { .. } => "EPP0015",
+            Self::PredefinedMacroDirective { .. } => "EPP0016",
+            Self::ErrorDirective { .. } => "EPP0017",
+            Self::InvalidMacroDefinition { .. } => "EPP0018",
+            Self::InvalidCondition { .. } => "EPP0019",
+            Self::SourceFileError { .. } => "EPP0020",
+            Self::TokenizeError(_) => "EPP0021",
+            #[cfg(feature = "fs")]
+            Self::GlobPatternError(_) => "EPP0022",
+            #[cfg(feature = "fs")]
+            Self::GlobError(_) => "EPP0023",
+            Self::ConfigError { .. } => "EPP0024",
+        }
+    }
+
+    /// Returns `true` if preprocessing can continue after this error, otherwise `false`.
+    pub(crate) fn is_recoverable(&self) -> bool {
+        match self {
+            #[cfg(feature = "fs")]
+            Self::GlobPatternError(_) | Self::GlobError(_) => false,
+            _ => !matches!(
+                self,
+                Self::UnexpectedEof
+                    | Self::TokenizeError(_)
+                    | Self::SourceFileError { .. }
+                    | Self::ConfigError { .. }
+            ),
+        }
+    }
+
+    /// Returns a value which displays this error with a snippet of the source code.
+    ///
+    /// The snippet consists of the line on which the error occurred and
+    /// carets under `Error::span()`.
+    /// The text of the line is taken from `sources` instead of `Error::source_line()`,
+    /// so errors which are not raised by a `Preprocessor` can be displayed in the same way.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate erl_pp;
+    /// # extern crate erl_tokenize;
+    /// use erl_pp::Preprocessor;
+    /// use erl_tokenize::Lexer;
+    ///
+    /// # fn main() {
+    /// let src = "foo(?BAR).";
+    /// let error = Preprocessor::new(Lexer::new(src)).find_map(|t| t.err()).unwrap();
+    /// assert_eq!(error.display_with_source(src).to_string().lines().skip(1).collect::<Vec<_>>(),
+    ///            [" --> unknown:1:5", "foo(?BAR).", "    ^^^^"]);
+    /// # }
+    /// ```
+    pub fn display_with_source<'a, S>(&'a self, sources: &'a S) -> DisplayWithSource<'a, S>
+    where
+        S: SourceMap + ?Sized,
+    {
+        DisplayWithSource {
+            error: self,
+            sources,
+        }
+    }
+
+    /// Returns the message of this error without the include stack and the source line.
+    pub(crate) fn message(&self) -> String {
+        let message = self.to_string();
+        let suffix = format!(
+            "{}{}",
+            include_suffix(self.include_stack()),
+            line_suffix(&self.source_line().cloned())
+        );
+        match message.strip_suffix(&suffix) {
+            Some(stripped) => stripped.to_owned(),
+            None => message,
+        }
+    }
+
+    pub(crate) fn position(&self) -> Option<Position> {
+        match self {
+            Self::UnexpectedToken { token, .. } => Some(token.start_position()),
+            Self::IncludeFileError {
+                directive_start, ..
+            } => Some(directive_start.clone()),
+            Self::MissingMacroArg { position, .. } => Some(position.clone()),
+            Self::UnbalancedParen { close, .. } => Some(close.start_position()),
+            Self::FileNotSet { macro_call, .. }
+            | Self::UndefinedMacro { macro_call, .. }
+            | Self::MacroArgsMismatched { macro_call, .. }
+            | Self::RecursiveMacro { macro_call, .. }
+            | Self::MacroExpansionTooDeep { macro_call, .. } => Some(macro_call.start_position()),
+            Self::UnexpectedDotInMacroDef { position, .. } => Some(position.clone()),
+            Self::MissingIfDirective { directive, .. } => Some(directive.start_position()),
+            Self::MacroRedefined { redefinition, .. } => Some(redefinition.new_position.clone()),
+            Self::PredefinedMacroDirective { position, .. }
+            | Self::ErrorDirective { position, .. } => Some(position.clone()),
+            Self::InvalidCondition { position, .. } => position.clone(),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn set_source_line(&mut self, line: SourceLine) {
+        match self {
+            Self::UnexpectedToken { source_line, .. }
+            | Self::IncludeFileError { source_line, .. }
+            | Self::MissingMacroArg { source_line, .. }
+            | Self::UnbalancedParen { source_line, .. }
+            | Self::FileNotSet { source_line, .. }
+            | Self::UndefinedMacro { source_line, .. }
+            | Self::MacroArgsMismatched { source_line, .. }
+            | Self::UnexpectedDotInMacroDef { source_line, .. }
+            | Self::MissingIfDirective { source_line, .. }
+            | Self::MacroRedefined { source_line, .. }
+            | Self::RecursiveMacro { source_line, .. }
+            | Self::MacroExpansionTooDeep { source_line, .. }
+            | Self::PredefinedMacroDirective { source_line, .. }
+            | Self::ErrorDirective { source_line, .. }
+            | Self::InvalidCondition { source_line, .. } => *source_line = Some(line),
+            _ => {}
+        }
+    }
+
     pub(crate) fn unexpected_token(token: LexicalToken, expected: &str) -> Self {
         Self::UnexpectedToken {
             token,
             expected: expected.to_owned(),
+            source_line: None,
+            include_stack: Vec::new(),
         }
     }
 
@@ -103,23 +502,43 @@ impl Error {
             directive_start: directive.start_position(),
             directive_end: directive.end_position(),
             target_file_path,
+            source_line: None,
+            include_stack: Vec::new(),
         }
     }
 
-    pub(crate) fn missing_macro_arg(position: Position) -> Self {
-        Self::MissingMacroArg { position }
+    pub(crate) fn missing_macro_arg(token: &impl PositionRange) -> Self {
+        Self::MissingMacroArg {
+            position: token.start_position(),
+            end_position: token.end_position(),
+            source_line: None,
+            include_stack: Vec::new(),
+        }
     }
 
     pub(crate) fn unbalanced_paren(open: Option<SymbolToken>, close: SymbolToken) -> Self {
-        Self::UnbalancedParen { open, close }
+        Self::UnbalancedParen {
+            open,
+            close,
+            source_line: None,
+            include_stack: Vec::new(),
+        }
     }
 
     pub(crate) fn file_not_set(macro_call: MacroCall) -> Self {
-        Self::FileNotSet { macro_call }
+        Self::FileNotSet {
+            macro_call,
+            source_line: None,
+            include_stack: Vec::new(),
+        }
     }
 
     pub(crate) fn undefined_macro(macro_call: MacroCall) -> Self {
-        Self::UndefinedMacro { macro_call }
+        Self::UndefinedMacro {
+            macro_call,
+            source_line: None,
+            include_stack: Vec::new(),
+        }
     }
 
     pub(crate) fn non_utf8_path(path: impl AsRef<Path>) -> Self {
@@ -131,6 +550,9 @@ impl Error {
     pub(crate) fn unexpected_dot_in_macro_def(token: &LexicalToken) -> Self {
         Self::UnexpectedDotInMacroDef {
             position: token.start_position(),
+            end_position: token.end_position(),
+            source_line: None,
+            include_stack: Vec::new(),
         }
     }
 
@@ -138,14 +560,234 @@ impl Error {
         Self::MacroArgsMismatched {
             macro_call,
             macro_def,
+            source_line: None,
+            include_stack: Vec::new(),
         }
     }
 
-    pub(crate) fn undefined_macro_var(varname: String) -> Self {
-        Self::UndefinedMacroVar { varname }
+    pub(crate) fn undefined_macro_var(varname: String, range: Option<&impl PositionRange>) -> Self {
+        Self::UndefinedMacroVar {
+            varname,
+            position: range.map(PositionRange::start_position),
+            end_position: range.map(PositionRange::end_position),
+        }
     }
 
     pub(crate) fn missing_if_directive(directive: Directive) -> Self {
-        Self::MissingIfDirective { directive }
+        Self::MissingIfDirective {
+            directive,
+            source_line: None,
+            include_stack: Vec::new(),
+        }
+    }
+
+    pub(crate) fn macro_redefined(redefinition: MacroRedefinition, end_position: Position) -> Self {
+        Self::MacroRedefined {
+            redefinition,
+            end_position,
+            source_line: None,
+            include_stack: Vec::new(),
+        }
+    }
+
+    pub(crate) fn recursive_macro(macro_call: MacroCall) -> Self {
+        Self::RecursiveMacro {
+            macro_call,
+            source_line: None,
+            include_stack: Vec::new(),
+        }
+    }
+
+    pub(crate) fn macro_expansion_too_deep(macro_call: MacroCall, max_depth: usize) -> Self {
+        Self::MacroExpansionTooDeep {
+            macro_call,
+            max_depth,
+            source_line: None,
+            include_stack: Vec::new(),
+        }
+    }
+
+    pub(crate) fn predefined_macro_directive(name: &str, directive: &impl PositionRange) -> Self {
+        Self::PredefinedMacroDirective {
+            name: name.to_owned(),
+            position: directive.start_position(),
+            end_position: directive.end_position(),
+            source_line: None,
+            include_stack: Vec::new(),
+        }
+    }
+
+    pub(crate) fn error_directive(message: &str, directive: &impl PositionRange) -> Self {
+        Self::ErrorDirective {
+            message: message.to_owned(),
+            position: directive.start_position(),
+            end_position: directive.end_position(),
+            source_line: None,
+            include_stack: Vec::new(),
+        }
+    }
+
+    pub(crate) fn invalid_macro_definition(definition: &str) -> Self {
+        Self::InvalidMacroDefinition {
+            definition: definition.to_owned(),
+        }
+    }
+
+    pub(crate) fn invalid_condition(position: Option<Position>, reason: &str) -> Self {
+        Self::InvalidCondition {
+            position,
+            reason: reason.to_owned(),
+            source_line: None,
+            include_stack: Vec::new(),
+        }
+    }
+}
+
+/// Text of the source line on which an error occurred.
+#[derive(Debug, Clone)]
+pub struct SourceLine {
+    /// The text of the line (without the line terminator).
+    pub text: String,
+
+    /// The column (1-origin) of the error position in the line.
+    pub column: usize,
+}
+impl SourceLine {
+    pub(crate) fn new(source: &str, position: &Position) -> Option<Self> {
+        let text = source.lines().nth(position.line().checked_sub(1)?)?;
+        Some(SourceLine {
+            text: text.to_owned(),
+            column: position.column(),
+        })
+    }
+}
+impl fmt::Display for SourceLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let marker = self
+            .text
+            .chars()
+            .take(self.column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+        write!(f, "{}\n{}^", self.text, marker)
+    }
+}
+
+/// Provider of source texts which is used by `Error::display_with_source()`.
+pub trait SourceMap {
+    /// Returns the text of the file which has the given path.
+    ///
+    /// `path` is `None` for the source which has no file path.
+    fn source_text(&self, path: Option<&Path>) -> Option<Cow<str>>;
+}
+impl SourceMap for str {
+    /// Returns this text regardless of `path`.
+    fn source_text(&self, _path: Option<&Path>) -> Option<Cow<str>> {
+        Some(Cow::Borrowed(self))
+    }
+}
+impl SourceMap for MemoryIncludes {
+    fn source_text(&self, path: Option<&Path>) -> Option<Cow<str>> {
+        path.and_then(|path| self.get(path)).map(Cow::Borrowed)
+    }
+}
+#[cfg(feature = "fs")]
+impl SourceMap for FileSystemResolver {
+    /// Reads the text from the filesystem.
+    fn source_text(&self, path: Option<&Path>) -> Option<Cow<str>> {
+        path.and_then(|path| util::read_file(path).ok())
+            .map(Cow::Owned)
+    }
+}
+impl<F> SourceMap for F
+where
+    F: Fn(Option<&Path>) -> Option<String>,
+{
+    fn source_text(&self, path: Option<&Path>) -> Option<Cow<str>> {
+        self(path).map(Cow::Owned)
+    }
+}
+
+/// An error displayed with a snippet of the source code.
+///
+/// This is created by `Error::display_with_source()`.
+#[derive(Debug)]
+pub struct DisplayWithSource<'a, S: ?Sized> {
+    error: &'a Error,
+    sources: &'a S,
+}
+impl<'a, S> fmt::Display for DisplayWithSource<'a, S>
+where
+    S: SourceMap + ?Sized,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.error.message())?;
+        if let Some((start, end)) = self.error.span() {
+            write!(
+                f,
+                "\n --> {}:{}:{}",
+                path_text(&start),
+                start.line(),
+                start.column()
+            )?;
+            let text = self
+                .sources
+                .source_text(start.filepath().map(PathBuf::as_path));
+            let line = text
+                .as_ref()
+                .and_then(|text| text.lines().nth(start.line().checked_sub(1)?));
+            if let Some(line) = line {
+                let marker = line
+                    .chars()
+                    .take(start.column().saturating_sub(1))
+                    .map(|c| if c == '\t' { '\t' } else { ' ' })
+                    .collect::<String>();
+                let width = if end.line() == start.line() {
+                    end.column().saturating_sub(start.column())
+                } else {
+                    (line.chars().count() + 1).saturating_sub(start.column())
+                };
+                write!(f, "\n{}\n{}{}", line, marker, "^".repeat(width.max(1)))?;
+            }
+        }
+        for position in self.error.include_stack() {
+            write!(
+                f,
+                "\n included from {}:{}",
+                path_text(position),
+                position.line()
+            )?;
+        }
+        Ok(())
     }
 }
+
+fn position_text(position: &Option<Position>) -> String {
+    position
+        .as_ref()
+        .map_or_else(|| "unknown".to_owned(), ToString::to_string)
+}
+
+fn include_suffix(stack: &[Position]) -> String {
+    stack
+        .iter()
+        .map(|position| {
+            format!(
+                ", included from {}:{}",
+                path_text(position),
+                position.line()
+            )
+        })
+        .collect()
+}
+
+fn path_text(position: &Position) -> String {
+    position
+        .filepath()
+        .map_or_else(|| "unknown".to_owned(), |p| p.display().to_string())
+}
+
+fn line_suffix(line: &Option<SourceLine>) -> String {
+    line.as_ref()
+        .map_or_else(String::new, |line| format!("\n{}", line))
+}
@@ -0,0 +1,422 @@
+use erl_tokenize::tokens::{AtomToken, IntegerToken, StringToken, VariableToken};
+use erl_tokenize::{LexicalToken, Position, PositionRange};
+use smallvec::SmallVec;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::slice;
+use std::sync::Arc;
+
+use crate::macros::Stringify;
+use crate::origin::MacroFrame;
+use crate::token_reader::TokenReader;
+use crate::types::{MacroArgs, MacroVariables};
+use crate::util::{self, Arena, ArenaVec, HashSet};
+use crate::xref::{MacroCallSite, ObservedCall};
+use crate::{Error, MacroCall, MacroDef, MacroEnv, Result};
+
+/// Pairs of macro variables and the argument tokens bound to them.
+///
+/// Most macros have a few variables, so they are stored inline.
+type Bindings<'a> = SmallVec<[(&'a str, &'a [LexicalToken]); 4]>;
+
+/// Chains of the macro calls which produced the expanded tokens, keyed by the token positions.
+pub(crate) type ExpansionTrace = RefCell<BTreeMap<Position, Vec<MacroFrame>>>;
+
+/// The default value of `MacroExpander::max_expansion_depth()`.
+pub(crate) const DEFAULT_MAX_EXPANSION_DEPTH: usize = 256;
+
+/// Macro expander which expands the macro calls in a token sequence under a macro environment.
+///
+/// This is the expansion engine used by [Preprocessor](crate::Preprocessor).
+/// It is useful for tools which already have tokens (e.g., from a parser)
+/// and want to expand macros in them without handling directives.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate erl_pp;
+/// # extern crate erl_tokenize;
+/// use erl_pp::{MacroEnv, MacroExpander};
+/// use erl_tokenize::Lexer;
+///
+/// # fn main() {
+/// let mut env = MacroEnv::new();
+/// env.define_from_str("FOO=[1, 2]").unwrap();
+///
+/// let tokens = Lexer::new("foo(?FOO, ?LINE)").collect::<Result<Vec<_>, _>>().unwrap();
+/// let expanded = MacroExpander::new(&env).expand(&tokens).unwrap();
+///
+/// assert_eq!(expanded.iter().map(|t| t.text()).collect::<Vec<_>>(),
+///            ["foo", "(", "[", "1", ",", "2", "]", ",", "1", ")"]);
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct MacroExpander<'a> {
+    env: &'a MacroEnv,
+    max_expansion_depth: usize,
+    features: Option<&'a BTreeMap<String, bool>>,
+    available_features: Option<&'a BTreeSet<String>>,
+    function: Option<(&'a str, usize)>,
+    expanded_macros: Option<&'a RefCell<HashSet<String>>>,
+    placeholder: Option<&'a str>,
+    placeholder_calls: Option<&'a RefCell<Vec<MacroCall>>>,
+    trace: Option<&'a ExpansionTrace>,
+    observed_calls: Option<&'a RefCell<Vec<ObservedCall>>>,
+}
+impl<'a> MacroExpander<'a> {
+    /// Makes a new `MacroExpander` instance which expands macros defined in `env`.
+    pub fn new(env: &'a MacroEnv) -> Self {
+        MacroExpander {
+            env,
+            max_expansion_depth: DEFAULT_MAX_EXPANSION_DEPTH,
+            features: None,
+            available_features: None,
+            function: None,
+            expanded_macros: None,
+            placeholder: None,
+            placeholder_calls: None,
+            trace: None,
+            observed_calls: None,
+        }
+    }
+
+    /// Returns the maximum depth of nested macro expansions.
+    pub fn max_expansion_depth(&self) -> usize {
+        self.max_expansion_depth
+    }
+
+    /// Sets the maximum depth of nested macro expansions.
+    ///
+    /// The default value is `256`.
+    pub fn set_max_expansion_depth(&mut self, depth: usize) {
+        self.max_expansion_depth = depth;
+    }
+
+    /// Sets the features enabled (`true`) or disabled (`false`),
+    /// which are used to expand `?FEATURE_ENABLED(Feature)`.
+    pub fn set_features(&mut self, features: &'a BTreeMap<String, bool>) {
+        self.features = Some(features);
+    }
+
+    /// Sets the features available in the target compiler,
+    /// which are used to expand `?FEATURE_AVAILABLE(Feature)`.
+    pub fn set_available_features(&mut self, features: &'a BTreeSet<String>) {
+        self.available_features = Some(features);
+    }
+
+    /// Sets the name and arity of the current function,
+    /// which are used to expand `?FUNCTION_NAME` and `?FUNCTION_ARITY`.
+    pub fn set_function(&mut self, name: &'a str, arity: usize) {
+        self.function = Some((name, arity));
+    }
+
+    /// Sets the atom to which undefined macros are expanded.
+    ///
+    /// If this is not set (the default), expanding an undefined macro results in an error.
+    /// Note that the arguments of an undefined macro call are left as they are
+    /// (e.g., `?FOO(1)` is expanded to `placeholder(1)`).
+    pub fn set_undefined_macro_placeholder(&mut self, placeholder: &'a str) {
+        self.placeholder = Some(placeholder);
+    }
+
+    pub(crate) fn set_placeholder_calls(&mut self, calls: &'a RefCell<Vec<MacroCall>>) {
+        self.placeholder_calls = Some(calls);
+    }
+
+    pub(crate) fn set_trace(&mut self, trace: &'a ExpansionTrace) {
+        self.trace = Some(trace);
+    }
+
+    pub(crate) fn set_observed_calls(&mut self, calls: &'a RefCell<Vec<ObservedCall>>) {
+        self.observed_calls = Some(calls);
+    }
+
+    pub(crate) fn set_expanded_macros(&mut self, expanded: &'a RefCell<HashSet<String>>) {
+        self.expanded_macros = Some(expanded);
+    }
+
+    /// Expands the macro calls in the given tokens.
+    pub fn expand(&self, tokens: &[LexicalToken]) -> Result<Vec<LexicalToken>> {
+        self.expand_in(&Arena::new(), tokens)
+    }
+
+    /// Expands the given macro call.
+    pub fn expand_call(&self, call: MacroCall) -> Result<Vec<LexicalToken>> {
+        let arena = Arena::new();
+        let expanded = self.expand_macro(&arena, &call, &mut Vec::new())?;
+        Ok(expanded.into_iter().collect())
+    }
+
+    pub(crate) fn expand_in(
+        &self,
+        arena: &Arena,
+        tokens: &[LexicalToken],
+    ) -> Result<Vec<LexicalToken>> {
+        let mut expanded = Vec::with_capacity(tokens.len());
+        let mut reader: TokenReader<_> = TokenReader::new(tokens.iter().map(|t| Ok(t.clone())));
+        loop {
+            if let Some(call) = reader.try_read_macro_call(self.env)? {
+                expanded.extend(self.expand_macro(arena, &call, &mut Vec::new())?);
+            } else if let Some(token) = reader.try_read_token()? {
+                expanded.push(token);
+            } else {
+                break;
+            }
+        }
+        Ok(expanded)
+    }
+
+    pub(crate) fn expand_macro<'b>(
+        &self,
+        arena: &'b Arena,
+        call: &MacroCall,
+        stack: &mut Vec<MacroFrame>,
+    ) -> Result<ArenaVec<'b, LexicalToken>> {
+        if let Some(expanded_macros) = self.expanded_macros {
+            expanded_macros
+                .borrow_mut()
+                .insert(call.name.value().to_owned());
+        }
+        if let Some(expanded) = self.env.expand_macro_fn(call)? {
+            let mut tokens = util::arena_vec(arena);
+            tokens.extend(expanded);
+            self.record_trace(&tokens, stack, Some(call));
+            return Ok(tokens);
+        }
+        let mut predefined = self.env.expand_predefined_macro(call)?;
+        if predefined.is_none() {
+            predefined = self.expand_function_macro(call);
+        }
+        if predefined.is_none() {
+            predefined = self.expand_feature_macro(call)?;
+        }
+        if let Some(expanded) = predefined {
+            let mut tokens = util::arena_vec(arena);
+            tokens.push(expanded);
+            self.record_trace(&tokens, stack, Some(call));
+            Ok(tokens)
+        } else {
+            // Macros with the same name but different arities are distinct (as `epp` does).
+            let arity = call.args.as_ref().map(MacroArgs::len);
+            if stack
+                .iter()
+                .any(|frame| frame.name == call.name.value() && frame.arity == arity)
+            {
+                return Err(Error::recursive_macro(call.clone()));
+            }
+            if stack.len() >= self.max_expansion_depth {
+                return Err(Error::macro_expansion_too_deep(
+                    call.clone(),
+                    self.max_expansion_depth,
+                ));
+            }
+            stack.push(MacroFrame::new(call));
+            let expanded = self.expand_userdefined_macro(arena, call, stack);
+            stack.pop();
+            expanded
+        }
+    }
+    fn expand_function_macro(&self, call: &MacroCall) -> Option<LexicalToken> {
+        let (name, arity) = self.function?;
+        match call.name.value() {
+            "FUNCTION_NAME" => Some(AtomToken::from_value(name, call.start_position()).into()),
+            "FUNCTION_ARITY" => {
+                Some(IntegerToken::from_value(arity.into(), call.start_position()).into())
+            }
+            _ => None,
+        }
+    }
+    fn expand_feature_macro(&self, call: &MacroCall) -> Result<Option<LexicalToken>> {
+        let available = match call.name.value() {
+            "FEATURE_AVAILABLE" => true,
+            "FEATURE_ENABLED" => false,
+            _ => return Ok(None),
+        };
+        let args = call
+            .args
+            .as_ref()
+            .ok_or_else(|| Error::undefined_macro(call.clone()))?;
+        let feature = match args.iter().map(|a| &a.tokens[..]).collect::<Vec<_>>()[..] {
+            [[LexicalToken::Atom(ref feature)]] => feature.value(),
+            _ => {
+                let token = args
+                    .iter()
+                    .next()
+                    .map_or_else(|| args._close_paren.clone().into(), |a| a.tokens[0].clone());
+                return Err(Error::unexpected_token(token, "feature name"));
+            }
+        };
+        let value = if available {
+            self.available_features
+                .is_some_and(|features| features.contains(feature))
+        } else {
+            self.features
+                .is_some_and(|features| features.get(feature) == Some(&true))
+        };
+        let value = if value { "true" } else { "false" };
+        Ok(Some(
+            AtomToken::from_value(value, call.start_position()).into(),
+        ))
+    }
+    fn expand_userdefined_macro<'b>(
+        &self,
+        arena: &'b Arena,
+        call: &MacroCall,
+        stack: &mut Vec<MacroFrame>,
+    ) -> Result<ArenaVec<'b, LexicalToken>> {
+        let name = call.name.value();
+        let definition = match self.env.get(name, call.args.as_ref().map(MacroArgs::len)) {
+            Some(definition) => definition,
+            None => match self.env.macros().definitions(name).first() {
+                Some(other) => {
+                    return Err(Error::macro_args_mismatched(call.clone(), other.clone()))
+                }
+                None => {
+                    let placeholder = match self.placeholder {
+                        None => return Err(Error::undefined_macro(call.clone())),
+                        Some(placeholder) => placeholder,
+                    };
+                    let mut tokens = util::arena_vec(arena);
+                    tokens.push(AtomToken::from_value(placeholder, call.start_position()).into());
+                    if let Some(calls) = self.placeholder_calls {
+                        calls.borrow_mut().push(call.clone());
+                    }
+                    return Ok(tokens);
+                }
+            },
+        };
+        if let Some(calls) = self.observed_calls {
+            // The last frame is the current call.
+            let caller = stack.iter().rev().nth(1).map(|frame| frame.name.clone());
+            calls.borrow_mut().push(ObservedCall {
+                name: name.to_owned(),
+                arity: definition.arity(),
+                definition: match *definition {
+                    MacroDef::Static(ref d) => Some(d.start_position()),
+                    _ => None,
+                },
+                site: MacroCallSite {
+                    position: call.start_position(),
+                    caller,
+                },
+            });
+        }
+        match *definition {
+            MacroDef::Dynamic(ref replacement) => {
+                let mut tokens = util::arena_vec(arena);
+                tokens.extend(replacement.iter().cloned());
+                self.record_trace(&tokens, stack, None);
+                Ok(tokens)
+            }
+            MacroDef::DynamicWithVariables {
+                ref variables,
+                ref replacement,
+            } => {
+                let mut bindings = Bindings::new();
+                bindings.extend(
+                    variables.iter().map(String::as_str).zip(
+                        call.args
+                            .iter()
+                            .flat_map(|i| i.iter().map(|a| &a.tokens[..])),
+                    ),
+                );
+                self.expand_replacement(arena, &bindings, replacement, stack)
+            }
+            MacroDef::Native(ref native) => {
+                let args = call.args.as_ref().expect("unreachable");
+                let mut tokens = util::arena_vec(arena);
+                tokens.extend(native.expand(args)?);
+                self.record_trace(&tokens, stack, None);
+                Ok(tokens)
+            }
+            MacroDef::Static(ref definition) => {
+                if call.args.as_ref().map(MacroArgs::len)
+                    != definition.variables.as_ref().map(MacroVariables::len)
+                {
+                    return Err(Error::macro_args_mismatched(
+                        call.clone(),
+                        MacroDef::Static(Arc::clone(definition)),
+                    ));
+                }
+                let mut bindings = Bindings::new();
+                bindings.extend(
+                    definition
+                        .variables
+                        .as_ref()
+                        .iter()
+                        .flat_map(|i| i.iter().map(VariableToken::value))
+                        .zip(
+                            call.args
+                                .iter()
+                                .flat_map(|i| i.iter().map(|a| &a.tokens[..])),
+                        ),
+                );
+                self.expand_replacement(arena, &bindings, &definition.replacement, stack)
+            }
+        }
+    }
+    fn expand_replacement<'b>(
+        &self,
+        arena: &'b Arena,
+        bindings: &[(&str, &[LexicalToken])],
+        replacement: &[LexicalToken],
+        stack: &mut Vec<MacroFrame>,
+    ) -> Result<ArenaVec<'b, LexicalToken>> {
+        let binding = |name: &str| bindings.iter().find(|b| b.0 == name).map(|b| b.1);
+        let mut expanded = util::arena_vec(arena);
+        let mut reader: TokenReader<_> =
+            TokenReader::new(replacement.iter().map(|t| Ok(t.clone())));
+        loop {
+            if let Some(call) = reader.try_read_macro_call(self.env)? {
+                let nested = self.expand_macro(arena, &call, stack)?;
+                for token in nested.into_iter().rev() {
+                    reader.unread_token(token);
+                }
+            } else if let Some(stringify) = reader.try_read::<Stringify>()? {
+                let tokens = binding(stringify.name.value()).ok_or_else(|| {
+                    Error::undefined_macro_var(stringify.name.value().to_owned(), Some(&stringify))
+                })?;
+                let string = tokens.iter().map(LexicalToken::text).collect::<String>();
+                let token = StringToken::from_value(&string, tokens[0].start_position()).into();
+                self.record_trace(slice::from_ref(&token), stack, None);
+                expanded.push(token);
+            } else if let Some(token) = reader.try_read_token()? {
+                if let Some(value) = token.as_variable_token().and_then(|v| binding(v.value())) {
+                    // Arguments are expanded in the context of the caller,
+                    // but the tokens of them are regarded as produced by the current macro.
+                    self.record_trace(value, stack, None);
+                    let current = stack.pop();
+                    let nested = self.expand_replacement(arena, &[], value, stack);
+                    stack.extend(current);
+                    expanded.extend(nested?);
+                } else {
+                    self.record_trace(slice::from_ref(&token), stack, None);
+                    expanded.push(token);
+                }
+            } else {
+                break;
+            }
+        }
+        Ok(expanded)
+    }
+    fn record_trace(
+        &self,
+        tokens: &[LexicalToken],
+        stack: &[MacroFrame],
+        call: Option<&MacroCall>,
+    ) {
+        let trace = match self.trace {
+            None => return,
+            Some(trace) => trace,
+        };
+        let mut trace = trace.borrow_mut();
+        for token in tokens {
+            // The innermost chain is recorded first and kept.
+            trace.entry(token.start_position()).or_insert_with(|| {
+                let mut chain = stack.to_vec();
+                chain.extend(call.map(MacroFrame::new));
+                chain
+            });
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::path::Path;
+
+use crate::{IncludeKind, IncludedFile};
+
+/// Writes the include graph of `files` to `writer` in Graphviz DOT format.
+///
+/// Nodes are labeled by the resolved paths of the files,
+/// and edges are labeled by the kinds and positions of the include directives.
+pub(crate) fn write_dot<W: Write>(writer: &mut W, files: &[IncludedFile]) -> fmt::Result {
+    let mut nodes = BTreeMap::new();
+    let mut edges = Vec::new();
+    for file in files {
+        let next_id = nodes.len();
+        let from = *nodes
+            .entry(file.position.filepath().map(|p| p.as_path()))
+            .or_insert(next_id);
+        let next_id = nodes.len();
+        let to = *nodes.entry(Some(file.path.as_path())).or_insert(next_id);
+        edges.push((from, to, file));
+    }
+
+    let mut nodes = nodes.into_iter().collect::<Vec<_>>();
+    nodes.sort_by_key(|&(_, id)| id);
+    writeln!(writer, "digraph includes {{")?;
+    for (path, id) in nodes {
+        let label = path.map_or_else(|| "<input>".into(), Path::to_string_lossy);
+        writeln!(writer, "    n{} [label=\"{}\"];", id, escape(&label))?;
+    }
+    for (from, to, file) in edges {
+        let kind = match file.kind {
+            IncludeKind::Include => "include",
+            IncludeKind::IncludeLib => "include_lib",
+        };
+        writeln!(
+            writer,
+            "    n{} -> n{} [label=\"{} {}:{}\"];",
+            from,
+            to,
+            kind,
+            file.position.line(),
+            file.position.column()
+        )?;
+    }
+    writeln!(writer, "}}")
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -0,0 +1,260 @@
+//! JSON representation of errors, warnings and tokens.
+//!
+//! This module is available only if the `json` feature is enabled.
+//!
+//! # Schema
+//!
+//! The output of [to_string()] is an array of diagnostic objects:
+//!
+//! ```text
+//! {
+//!   "severity": "error" | "warning",
+//!   "code": string | null,          // e.g., "EPP0007" (see `Error::code()`)
+//!   "message": string,
+//!   "span": {                       // null if the error is not related to a source range
+//!     "start": location,
+//!     "end": location
+//!   } | null,
+//!   "included_from": [location]     // innermost first
+//! }
+//! ```
+//!
+//! where `location` is:
+//!
+//! ```text
+//! {
+//!   "file": string | null,          // null if the source has no file path
+//!   "line": number,                 // 1-origin
+//!   "column": number,               // 1-origin
+//!   "offset": number                // 0-origin byte offset in the file
+//! }
+//! ```
+//!
+//! The output of [tokens_to_string()] is an array of token objects
+//! ([tokens_to_ndjson()] writes one token object per line instead):
+//!
+//! ```text
+//! {
+//!   "kind": "atom" | "char" | "float" | "integer" | "keyword" | "string" | "symbol" | "variable",
+//!   "text": string,                 // the original textual representation
+//!   "file": string | null,
+//!   "line": number,
+//!   "column": number
+//! }
+//! ```
+//!
+//! # Examples
+//!
+//! ```
+//! # extern crate erl_pp;
+//! # extern crate erl_tokenize;
+//! use erl_pp::json;
+//! use erl_pp::Preprocessor;
+//! use erl_tokenize::Lexer;
+//!
+//! # fn main() {
+//! let error = Preprocessor::new(Lexer::new("?FOO.")).next().unwrap().unwrap_err();
+//! let diagnostics = [json::Diagnostic::from_error(&error)];
+//! let json = json::to_string(&diagnostics);
+//! assert!(json.starts_with(r#"[{"severity":"error","code":"EPP0007","message":"#));
+//! assert!(json.ends_with(concat!(r#""span":{"start":{"file":null,"line":1,"column":1,"offset":0},"#,
+//!                                r#""end":{"file":null,"line":1,"column":5,"offset":4}},"#,
+//!                                r#""included_from":[]}]"#)));
+//! # }
+//! ```
+use erl_tokenize::{LexicalToken, Position, PositionRange};
+use serde::Serialize;
+use std::io::{self, Write};
+
+pub use crate::Severity;
+
+use crate::{Error, WarningDirective};
+
+/// A serializable diagnostic.
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    /// The severity of the diagnostic.
+    pub severity: Severity,
+
+    /// The stable code of the diagnostic (see `Error::code()`).
+    pub code: Option<String>,
+
+    /// The message of the diagnostic.
+    pub message: String,
+
+    /// The source range of the diagnostic.
+    pub span: Option<Span>,
+
+    /// The positions of the include directives through which
+    /// the file containing the span was included (innermost first).
+    pub included_from: Vec<Location>,
+}
+impl Diagnostic {
+    /// Makes a `Diagnostic` instance from an error.
+    pub fn from_error(error: &Error) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            code: Some(error.code().to_owned()),
+            message: error.message(),
+            span: error.span().map(|(start, end)| Span {
+                start: Location::new(&start),
+                end: Location::new(&end),
+            }),
+            included_from: error.include_stack().iter().map(Location::new).collect(),
+        }
+    }
+
+    /// Makes a `Diagnostic` instance from a diagnostic reported by a `Preprocessor`.
+    pub fn from_diagnostic(diagnostic: &crate::Diagnostic) -> Self {
+        Diagnostic {
+            severity: diagnostic.severity,
+            code: Some(diagnostic.code.to_owned()),
+            message: diagnostic.message.clone(),
+            span: diagnostic.span.as_ref().map(|(start, end)| Span {
+                start: Location::new(start),
+                end: Location::new(end),
+            }),
+            included_from: Vec::new(),
+        }
+    }
+
+    /// Makes a `Diagnostic` instance from a `-warning` directive.
+    pub fn from_warning(warning: &WarningDirective) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            code: Some("EPP1001".to_owned()),
+            message: warning.message.clone(),
+            span: Some(Span {
+                start: Location::new(&warning.position),
+                end: Location::new(&warning.position),
+            }),
+            included_from: Vec::new(),
+        }
+    }
+}
+
+/// A serializable source range.
+#[derive(Debug, Clone, Serialize)]
+pub struct Span {
+    /// The start location of the range.
+    pub start: Location,
+
+    /// The end location of the range.
+    pub end: Location,
+}
+
+/// A serializable source location.
+#[derive(Debug, Clone, Serialize)]
+pub struct Location {
+    /// The path of the file.
+    pub file: Option<String>,
+
+    /// The line number (1-origin).
+    pub line: usize,
+
+    /// The column number (1-origin).
+    pub column: usize,
+
+    /// The byte offset in the file (0-origin).
+    pub offset: usize,
+}
+impl Location {
+    /// Makes a `Location` instance from a position.
+    pub fn new(position: &Position) -> Self {
+        Location {
+            file: position.filepath().map(|p| p.display().to_string()),
+            line: position.line(),
+            column: position.column(),
+            offset: position.offset(),
+        }
+    }
+}
+
+/// Serializes the given diagnostics as a JSON array.
+pub fn to_string(diagnostics: &[Diagnostic]) -> String {
+    serde_json::to_string(diagnostics).expect("never fails")
+}
+
+/// Serializes the given diagnostics as a JSON array and writes it to `writer`.
+pub fn to_writer<W: io::Write>(writer: W, diagnostics: &[Diagnostic]) -> io::Result<()> {
+    serde_json::to_writer(writer, diagnostics).map_err(io::Error::from)
+}
+
+/// A serializable token.
+#[derive(Debug, Clone, Serialize)]
+pub struct Token {
+    /// The kind of the token (e.g., `"atom"`).
+    pub kind: &'static str,
+
+    /// The original textual representation of the token.
+    pub text: String,
+
+    /// The path of the file.
+    pub file: Option<String>,
+
+    /// The line number (1-origin).
+    pub line: usize,
+
+    /// The column number (1-origin).
+    pub column: usize,
+}
+impl Token {
+    /// Makes a `Token` instance from a lexical token.
+    pub fn new(token: &LexicalToken) -> Self {
+        let kind = match *token {
+            LexicalToken::Atom(_) => "atom",
+            LexicalToken::Char(_) => "char",
+            LexicalToken::Float(_) => "float",
+            LexicalToken::Integer(_) => "integer",
+            LexicalToken::Keyword(_) => "keyword",
+            LexicalToken::String(_) => "string",
+            LexicalToken::Symbol(_) => "symbol",
+            LexicalToken::Variable(_) => "variable",
+        };
+        let location = Location::new(&token.start_position());
+        Token {
+            kind,
+            text: token.text().to_owned(),
+            file: location.file,
+            line: location.line,
+            column: location.column,
+        }
+    }
+}
+
+/// Serializes the given tokens as a JSON array.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate erl_pp;
+/// # extern crate erl_tokenize;
+/// use erl_pp::json;
+/// use erl_pp::Preprocessor;
+/// use erl_tokenize::Lexer;
+///
+/// # fn main() {
+/// let pp = Preprocessor::new(Lexer::new("-define(FOO, 1). ?FOO."));
+/// let tokens = pp.collect::<Result<Vec<_>, _>>().unwrap();
+/// assert_eq!(json::tokens_to_string(&tokens),
+///            concat!(r#"[{"kind":"integer","text":"1","file":null,"line":1,"column":14},"#,
+///                    r#"{"kind":"symbol","text":".","file":null,"line":1,"column":22}]"#));
+/// # }
+/// ```
+pub fn tokens_to_string(tokens: &[LexicalToken]) -> String {
+    let tokens = tokens.iter().map(Token::new).collect::<Vec<_>>();
+    serde_json::to_string(&tokens).expect("never fails")
+}
+
+/// Writes the given tokens to `writer` in the NDJSON format (i.e., one JSON object per line).
+pub fn tokens_to_ndjson<'a, W, I>(mut writer: W, tokens: I) -> io::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = &'a LexicalToken>,
+{
+    for token in tokens {
+        serde_json::to_writer(&mut writer, &Token::new(token)).map_err(io::Error::from)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
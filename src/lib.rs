@@ -19,25 +19,88 @@
 //! # }
 //! ```
 //!
+//! # Filesystem access
+//!
+//! Reading included files from the filesystem, environment variable substitution in include paths
+//! and OTP installation lookup are provided by the `fs` feature, which is enabled by default.
+//! Without it, the crate builds for targets which have no filesystem (e.g., `wasm32-unknown-unknown`),
+//! and included files are served by the resolver given to
+//! [Preprocessor::set_include_resolver()](crate::Preprocessor::set_include_resolver)
+//! (e.g., [MemoryIncludes]).
+//!
+//! # Memory usage
+//!
+//! The tokens returned by a preprocessor are `erl_tokenize::LexicalToken`s which own their texts,
+//! so they cannot borrow from the source or the included files (a zero-copy pipeline would require
+//! borrowing token types in `erl_tokenize` itself).
+//! Instead, the texts and the tokens of included files are shared among the inclusions
+//! and the replacements of macro definitions are shared among the expansions (and `MacroEnv` clones).
+//! For very large inputs, see also [Preprocessor::set_streaming()](crate::Preprocessor::set_streaming).
+//!
 //! # References
 //!
 //! - [Erlang Reference Manual -- Preprocessor](http://erlang.org/doc/reference_manual/macros.html)
 //!
 #![warn(missing_docs)]
+#[cfg(feature = "async")]
+pub use crate::async_preprocessor::{AsyncIncludeResolver, AsyncPreprocessor, ResolveFuture};
+pub use crate::condition::{evaluate_condition, ConditionInput};
+pub use crate::diagnostic::{Diagnostic, Severity};
 pub use crate::directive::Directive;
-pub use crate::error::Error;
-pub use crate::macros::{MacroCall, MacroDef};
-pub use crate::preprocessor::Preprocessor;
+pub use crate::error::{DisplayWithSource, Error, SourceLine, SourceMap};
+pub use crate::expander::MacroExpander;
+pub use crate::macros::{MacroCall, MacroDef, MacroEnv, MacroMap, NativeMacro};
+pub use crate::observer::ExpansionObserver;
+pub use crate::options::{preprocess, Options};
+pub use crate::origin::{MacroFrame, OriginMap, TokenOrigin};
+pub use crate::preprocessor::{
+    CrossFileConditional, EnvSnapshot, Forms, IncludeIsolation, IncludedFile, LeakedMacro,
+    MacroRedefinition, Preprocessor, RedefinitionPolicy, SkippedRegion, WarningDirective,
+};
+#[cfg(feature = "rayon")]
+pub use crate::project::par_preprocess_files;
+#[cfg(feature = "fs")]
+pub use crate::project::Project;
+pub use crate::project::{PreprocessedFile, ProjectReport};
+#[cfg(feature = "fs")]
+pub use crate::resolver::FileSystemResolver;
+pub use crate::resolver::{IncludeKind, IncludeResolver, MemoryIncludes};
+pub use crate::text::TextWriter;
+pub use crate::xref::{MacroCallSite, MacroXref, MacroXrefEntry};
 
+#[cfg(feature = "codespan")]
+pub mod codespan;
+#[cfg(feature = "config")]
+pub mod config;
 pub mod directives;
+#[cfg(feature = "json")]
+pub mod json;
+#[cfg(feature = "otp-compat-tests")]
+pub mod otp_compat;
+pub mod token_reader;
 pub mod types;
+pub mod workspace;
 
+#[cfg(feature = "async")]
+mod async_preprocessor;
+mod condition;
+mod diagnostic;
 mod directive;
 mod error;
+mod expander;
+mod graph;
 mod macros;
+mod observer;
+mod options;
+mod origin;
 mod preprocessor;
-mod token_reader;
+mod project;
+mod resolver;
+#[cfg(feature = "serde")]
+mod serialize;
+mod text;
 mod util;
+mod xref;
 
 /// This crate specific `Result` type.
 pub type Result<T> = std::result::Result<T, Error>;
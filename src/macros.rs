@@ -1,27 +1,507 @@
-use erl_tokenize::tokens::{SymbolToken, VariableToken};
+use erl_tokenize::tokens::{AtomToken, IntegerToken, StringToken, SymbolToken, VariableToken};
 use erl_tokenize::values::Symbol;
-use erl_tokenize::{LexicalToken, Position, PositionRange};
+use erl_tokenize::{Lexer, LexicalToken, Position, PositionRange};
 use std::fmt;
+use std::sync::Arc;
 
 use crate::directives::Define;
 use crate::token_reader::{ReadFrom, TokenReader};
-use crate::types::{MacroArgs, MacroName};
-use crate::Result;
+use crate::types::{MacroArgs, MacroName, ToTokens};
+use crate::util::{HashMap, StableHasher};
+use crate::{Error, Result};
+
+/// Macro table which maps macro names and arities to their definitions.
+///
+/// As in epp, macros which have the same name but different arities can coexist
+/// (e.g., `-define(FOO, a).` and `-define(FOO(X), b).`).
+///
+/// If the `fxhash` feature is enabled, the table uses the (faster but not DoS resistant)
+/// FxHash algorithm instead of the standard one.
+#[derive(Debug, Clone, Default)]
+pub struct MacroMap {
+    map: HashMap<String, Vec<MacroDef>>,
+}
+impl MacroMap {
+    /// Makes a new empty `MacroMap` instance.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Inserts a macro definition.
+    ///
+    /// If a macro which has the same name and arity exists, it is replaced and returned.
+    pub fn insert(&mut self, name: String, definition: MacroDef) -> Option<MacroDef> {
+        let definitions = self.map.entry(name).or_default();
+        if let Some(d) = definitions
+            .iter_mut()
+            .find(|d| d.arity() == definition.arity())
+        {
+            Some(std::mem::replace(d, definition))
+        } else {
+            definitions.push(definition);
+            None
+        }
+    }
+
+    /// Returns the definition of the macro which has the given name and arity.
+    ///
+    /// `arity` is `None` for macros defined without parentheses (e.g., `-define(FOO, a).`).
+    pub fn get(&self, name: &str, arity: Option<usize>) -> Option<&MacroDef> {
+        self.definitions(name).iter().find(|d| d.arity() == arity)
+    }
+
+    /// Returns the definitions of the macros which have the given name.
+    pub fn definitions(&self, name: &str) -> &[MacroDef] {
+        self.map.get(name).map_or(&[], |d| &d[..])
+    }
+
+    /// Returns `true` if a macro which has the given name is defined (regardless of the arity).
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.map.contains_key(name)
+    }
+
+    /// Removes all the macros which have the given name (as `-undef` directive does).
+    ///
+    /// The removed definitions are returned.
+    pub fn remove(&mut self, name: &str) -> Vec<MacroDef> {
+        self.map.remove(name).unwrap_or_default()
+    }
+
+    /// Returns an iterator over the names and the definitions of the macros.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &MacroDef)> {
+        self.map
+            .iter()
+            .flat_map(|(name, defs)| defs.iter().map(move |d| (name.as_str(), d)))
+    }
+
+    /// Returns the number of the macro definitions.
+    pub fn len(&self) -> usize {
+        self.map.values().map(Vec::len).sum()
+    }
+
+    /// Returns `true` if there are no macro definitions.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Renders the definitions as `-define` directives ordered by the names and the arities.
+    ///
+    /// Native macros cannot be rendered, so they are written as comments.
+    pub(crate) fn to_hrl(&self) -> String {
+        let mut macros = self.iter().collect::<Vec<_>>();
+        macros.sort_by_key(|&(name, d)| (name, d.arity()));
+
+        let mut hrl = String::new();
+        for (name, definition) in macros {
+            let (variables, replacement) = match *definition {
+                MacroDef::Static(ref d) => (
+                    d.variables
+                        .as_ref()
+                        .map(|v| v.iter().map(|v| v.text().to_owned()).collect()),
+                    d.replacement.to_vec(),
+                ),
+                MacroDef::Dynamic(ref replacement) => (None, replacement.to_vec()),
+                MacroDef::DynamicWithVariables {
+                    ref variables,
+                    ref replacement,
+                } => (Some(variables.clone()), replacement.to_vec()),
+                MacroDef::Native(ref m) => {
+                    hrl.push_str(&format!(
+                        "%% ?{}/{} is a native macro.\n",
+                        macro_name_text(name),
+                        m.arity()
+                    ));
+                    continue;
+                }
+            };
+            hrl.push_str(&format!(
+                "-define({}{}, {}).\n",
+                macro_name_text(name),
+                variables.map_or_else(String::new, |v: Vec<String>| format!("({})", v.join(", "))),
+                render_tokens(&replacement)
+            ));
+        }
+        hrl
+    }
+}
+
+/// Returns the textual representation of a macro name (quoted if needed).
+fn macro_name_text(name: &str) -> String {
+    let mut chars = name.chars();
+    let plain = chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_' || c == '@');
+    if plain {
+        name.to_owned()
+    } else {
+        format!("'{}'", name.replace('\\', "\\\\").replace('\'', "\\'"))
+    }
+}
+
+/// Joins the texts of the tokens so that the result is lexed into the same tokens.
+fn render_tokens(tokens: &[LexicalToken]) -> String {
+    let symbol = |t: &LexicalToken| t.as_symbol_token().map(|t| t.value());
+    let mut text = String::new();
+    for (i, token) in tokens.iter().enumerate() {
+        if i > 0 {
+            // Note that a dot followed by a whitespace is regarded as the end of a form.
+            let no_space_after = matches!(
+                symbol(&tokens[i - 1]),
+                Some(Symbol::OpenParen)
+                    | Some(Symbol::OpenSquare)
+                    | Some(Symbol::OpenBrace)
+                    | Some(Symbol::Question)
+                    | Some(Symbol::DoubleQuestion)
+                    | Some(Symbol::Dot)
+            );
+            let no_space_before = matches!(
+                symbol(token),
+                Some(Symbol::CloseParen)
+                    | Some(Symbol::CloseSquare)
+                    | Some(Symbol::CloseBrace)
+                    | Some(Symbol::Comma)
+            ) || (symbol(token) == Some(Symbol::OpenParen)
+                && (tokens[i - 1].as_atom_token().is_some()
+                    || tokens[i - 1].as_variable_token().is_some()));
+            if !(no_space_after || no_space_before) {
+                text.push(' ');
+            }
+        }
+        text.push_str(token.text());
+    }
+    text
+}
+
+/// Names of the predefined macros.
+pub(crate) const PREDEFINED_MACROS: &[&str] = &["FILE", "LINE", "MACHINE"];
+
+/// Names of the macros which cannot be defined or undefined by directives.
+pub(crate) const RESERVED_MACROS: &[&str] = &[
+    "FILE",
+    "LINE",
+    "MODULE",
+    "MODULE_STRING",
+    "MACHINE",
+    "FUNCTION_NAME",
+    "FUNCTION_ARITY",
+    "OTP_RELEASE",
+    "FEATURE_AVAILABLE",
+    "FEATURE_ENABLED",
+];
+
+/// Names of the predefined macros which take arguments.
+pub(crate) const PREDEFINED_MACROS_WITH_ARGS: &[&str] = &["FEATURE_AVAILABLE", "FEATURE_ENABLED"];
+
+/// Macro environment which consists of the predefined macros and the user defined macros.
+///
+/// A [Preprocessor](crate::Preprocessor) holds one internally,
+/// and this can also be used by external tools independently
+/// (e.g., via [evaluate_condition](crate::evaluate_condition)).
+#[derive(Debug, Clone, Default)]
+pub struct MacroEnv {
+    macros: MacroMap,
+    otp_release: Option<u32>,
+    macro_fns: HashMap<String, MacroFn>,
+}
+impl MacroEnv {
+    /// Makes a new empty `MacroEnv` instance.
+    ///
+    /// Note that the predefined macros (e.g., `?LINE`) are always regarded as defined.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns `true` if the macro is defined (or predefined), otherwise `false`.
+    pub fn is_defined(&self, name: &str) -> bool {
+        PREDEFINED_MACROS.contains(&name)
+            || (name == "OTP_RELEASE" && self.otp_release.is_some())
+            || self.macro_fns.contains_key(name)
+            || self.macros.contains_key(name)
+    }
+
+    /// Registers a predefined macro which is expanded by calling the given function.
+    ///
+    /// This is useful for macros whose values are computed lazily (e.g., `?BUILD_TIME`).
+    /// The registered macros take precedence over the user defined ones.
+    /// If the macro is called with arguments (e.g., `?GIT_SHA(short)`),
+    /// they are available via `MacroCall::args`.
+    pub fn register_macro_fn<F>(&mut self, name: &str, f: F)
+    where
+        F: Fn(&MacroCall) -> Result<Vec<LexicalToken>> + Send + Sync + 'static,
+    {
+        self.macro_fns.insert(name.to_owned(), MacroFn(Arc::new(f)));
+    }
+
+    /// Returns `true` if a macro function which has the given name is registered.
+    pub(crate) fn has_macro_fn(&self, name: &str) -> bool {
+        self.macro_fns.contains_key(name)
+    }
+
+    pub(crate) fn expand_macro_fn(&self, call: &MacroCall) -> Result<Option<Vec<LexicalToken>>> {
+        match self.macro_fns.get(call.name.value()) {
+            None => Ok(None),
+            Some(f) => (f.0)(call).map(Some),
+        }
+    }
+
+    /// Returns the value of the `?OTP_RELEASE` predefined macro.
+    pub fn otp_release(&self) -> Option<u32> {
+        self.otp_release
+    }
+
+    /// Sets the value of the `?OTP_RELEASE` predefined macro.
+    ///
+    /// `?OTP_RELEASE` is undefined unless this method is called,
+    /// so callers evaluating conditions such as `?OTP_RELEASE >= 23` must set it beforehand.
+    pub fn set_otp_release(&mut self, release: u32) {
+        self.otp_release = Some(release);
+    }
+
+    /// Returns the definition of the user defined macro which has the given name and arity.
+    pub fn get(&self, name: &str, arity: Option<usize>) -> Option<&MacroDef> {
+        self.macros.get(name, arity)
+    }
+
+    /// Defines a macro as `-define` directive does.
+    ///
+    /// If a macro which has the same name and arity has already been defined,
+    /// the old definition is replaced.
+    pub fn define(&mut self, define: Define) {
+        self.macros.insert(
+            define.name.value().to_owned(),
+            MacroDef::Static(Arc::new(define)),
+        );
+    }
+
+    /// Defines a macro from a string in the form of `erlc -D` option (i.e., `NAME` or `NAME=VALUE`).
+    ///
+    /// If the value is omitted, the macro is defined as `true`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use erl_pp::MacroEnv;
+    ///
+    /// let mut env = MacroEnv::new();
+    /// env.define_from_str("DEBUG").unwrap();
+    /// env.define_from_str(r#"VSN="1.2.3""#).unwrap();
+    /// assert!(env.is_defined("DEBUG"));
+    /// assert!(env.is_defined("VSN"));
+    /// ```
+    pub fn define_from_str(&mut self, definition: &str) -> Result<()> {
+        let (name, value) = match definition.find('=') {
+            Some(i) => (&definition[..i], Some(&definition[i + 1..])),
+            None => (definition, None),
+        };
+        let name = match Lexer::new(name)
+            .collect::<erl_tokenize::Result<Vec<_>>>()?
+            .as_slice()
+        {
+            [LexicalToken::Atom(t)] => t.value().to_owned(),
+            [LexicalToken::Variable(t)] => t.value().to_owned(),
+            _ => return Err(Error::invalid_macro_definition(definition)),
+        };
+        let replacement = match value {
+            None => vec![AtomToken::from_value("true", Position::new()).into()],
+            Some(value) => Lexer::new(value).collect::<erl_tokenize::Result<Vec<_>>>()?,
+        };
+        self.macros
+            .insert(name, MacroDef::Dynamic(replacement.into()));
+        Ok(())
+    }
+
+    /// Undefines a macro as `-undef` directive does.
+    ///
+    /// All the definitions which have the given name are removed and returned.
+    pub fn undef(&mut self, name: &str) -> Vec<MacroDef> {
+        self.macros.remove(name)
+    }
+
+    /// Returns a reference to the map containing the macro definitions of this environment.
+    pub fn macros(&self) -> &MacroMap {
+        &self.macros
+    }
+
+    /// Returns a mutable reference to the map containing the macro definitions of this environment.
+    pub fn macros_mut(&mut self) -> &mut MacroMap {
+        &mut self.macros
+    }
+
+    /// Feeds the contents of this environment to `hasher` in a deterministic order.
+    pub(crate) fn hash_into(&self, hasher: &mut StableHasher) {
+        let mut macros = self.macros.iter().collect::<Vec<_>>();
+        macros.sort_by_key(|&(name, d)| (name, d.arity()));
+        hasher.write_u64(macros.len() as u64);
+        for (name, definition) in macros {
+            hasher.write_str(name);
+            hasher.write_u64(definition.arity().map_or(u64::MAX, |n| n as u64));
+            let replacement = match *definition {
+                MacroDef::Static(ref d) => d.tokens(),
+                MacroDef::Dynamic(ref replacement) => replacement.to_vec(),
+                MacroDef::DynamicWithVariables {
+                    ref variables,
+                    ref replacement,
+                } => {
+                    variables.iter().for_each(|v| hasher.write_str(v));
+                    replacement.to_vec()
+                }
+                // The bodies of native macros cannot be inspected.
+                MacroDef::Native(_) => Vec::new(),
+            };
+            hasher.write_u64(replacement.len() as u64);
+            for token in &replacement {
+                hasher.write_str(token.text());
+            }
+        }
+        hasher.write_u64(self.otp_release.map_or(u64::MAX, u64::from));
+        let mut macro_fns = self.macro_fns.keys().collect::<Vec<_>>();
+        macro_fns.sort();
+        for name in macro_fns {
+            hasher.write_str(name);
+        }
+    }
+
+    pub(crate) fn expand_predefined_macro(&self, call: &MacroCall) -> Result<Option<LexicalToken>> {
+        let expanded = match call.name.value() {
+            "FILE" => {
+                let current = call.start_position();
+                let file = current
+                    .filepath()
+                    .and_then(|f| f.to_str())
+                    .ok_or_else(|| Error::file_not_set(call.clone()))?;
+                StringToken::from_value(file, call.start_position()).into()
+            }
+            "LINE" => {
+                let line = call.start_position().line();
+                IntegerToken::from_value(line.into(), call.start_position()).into()
+            }
+            "MACHINE" => AtomToken::from_value("BEAM", call.start_position()).into(),
+            "OTP_RELEASE" => match self.otp_release {
+                None => return Ok(None),
+                Some(release) => {
+                    IntegerToken::from_value(release.into(), call.start_position()).into()
+                }
+            },
+            _ => return Ok(None),
+        };
+        Ok(Some(expanded))
+    }
+}
+impl From<MacroMap> for MacroEnv {
+    fn from(macros: MacroMap) -> Self {
+        MacroEnv {
+            macros,
+            otp_release: None,
+            macro_fns: HashMap::default(),
+        }
+    }
+}
+
+type MacroFnBody = dyn Fn(&MacroCall) -> Result<Vec<LexicalToken>> + Send + Sync;
+
+#[derive(Clone)]
+struct MacroFn(Arc<MacroFnBody>);
+impl fmt::Debug for MacroFn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MacroFn(..)")
+    }
+}
+
+type NativeMacroBody = dyn Fn(&MacroArgs) -> Result<Vec<LexicalToken>> + Send + Sync;
+
+/// Macro which is expanded by calling a Rust function.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate erl_pp;
+/// # extern crate erl_tokenize;
+/// use erl_pp::{MacroDef, NativeMacro, Preprocessor};
+/// use erl_tokenize::{Lexer, PositionRange};
+/// use erl_tokenize::tokens::IntegerToken;
+///
+/// # fn main() {
+/// let count = NativeMacro::new(1, |args| {
+///     let n = args.iter().next().unwrap().tokens.len();
+///     Ok(vec![IntegerToken::from_value(n.into(), args.start_position()).into()])
+/// });
+///
+/// let mut pp = Preprocessor::new(Lexer::new("?COUNT(a + b)."));
+/// pp.macros_mut().insert("COUNT".to_string(), MacroDef::Native(count));
+/// let tokens = pp.collect::<Result<Vec<_>, _>>().unwrap();
+///
+/// assert_eq!(tokens.iter().map(|t| t.text()).collect::<Vec<_>>(), ["3", "."]);
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct NativeMacro {
+    arity: usize,
+    f: Arc<NativeMacroBody>,
+}
+impl NativeMacro {
+    /// Makes a new `NativeMacro` instance which takes `arity` arguments.
+    ///
+    /// Native macros are always called with parentheses (e.g., `?FOO()`).
+    /// Use `MacroEnv::register_macro_fn()` for computed macros without parentheses.
+    pub fn new<F>(arity: usize, f: F) -> Self
+    where
+        F: Fn(&MacroArgs) -> Result<Vec<LexicalToken>> + Send + Sync + 'static,
+    {
+        NativeMacro {
+            arity,
+            f: Arc::new(f),
+        }
+    }
+
+    /// Returns the number of the arguments of this macro.
+    pub fn arity(&self) -> usize {
+        self.arity
+    }
+
+    /// Expands this macro with the given arguments.
+    pub fn expand(&self, args: &MacroArgs) -> Result<Vec<LexicalToken>> {
+        (self.f)(args)
+    }
+}
+impl fmt::Debug for NativeMacro {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NativeMacro {{ arity: {}, .. }}", self.arity)
+    }
+}
 
 /// Macro definition.
+///
+/// The definitions and the replacement tokens are reference-counted,
+/// so cloning a definition (or a whole `MacroEnv`) does not copy the tokens.
 #[derive(Debug, Clone)]
 #[allow(missing_docs)]
-#[allow(clippy::large_enum_variant)]
 pub enum MacroDef {
-    Static(Define),
-    Dynamic(Vec<LexicalToken>),
+    Static(Arc<Define>),
+    Dynamic(Arc<[LexicalToken]>),
+    DynamicWithVariables {
+        variables: Vec<String>,
+        replacement: Arc<[LexicalToken]>,
+    },
+    Native(NativeMacro),
 }
 impl MacroDef {
+    /// Returns the number of the variables of this macro.
+    ///
+    /// If this macro is defined without parentheses, this returns `None`.
+    pub fn arity(&self) -> Option<usize> {
+        match *self {
+            MacroDef::Static(ref d) => d.variables.as_ref().map(|v| v.len()),
+            MacroDef::Dynamic(_) => None,
+            MacroDef::DynamicWithVariables { ref variables, .. } => Some(variables.len()),
+            MacroDef::Native(ref m) => Some(m.arity()),
+        }
+    }
+
     /// Returns `true` if this macro has variables, otherwise `false`.
     pub fn has_variables(&self) -> bool {
         match *self {
             MacroDef::Static(ref d) => d.variables.is_some(),
             MacroDef::Dynamic(_) => false,
+            MacroDef::DynamicWithVariables { .. } | MacroDef::Native(_) => true,
         }
     }
 }
@@ -57,6 +537,15 @@ impl fmt::Display for MacroCall {
         )
     }
 }
+impl ToTokens for MacroCall {
+    fn push_tokens(&self, tokens: &mut Vec<LexicalToken>) {
+        tokens.push(self._question.clone().into());
+        self.name.push_tokens(tokens);
+        if let Some(ref args) = self.args {
+            args.push_tokens(tokens);
+        }
+    }
+}
 impl ReadFrom for MacroCall {
     fn read_from<T>(reader: &mut TokenReader<T>) -> Result<Self>
     where
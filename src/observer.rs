@@ -0,0 +1,39 @@
+use erl_tokenize::LexicalToken;
+use std::fmt;
+use std::path::Path;
+
+use crate::{Directive, MacroCall};
+
+/// Observer of the events occurred during preprocessing.
+///
+/// This makes it possible to build logging, coverage or debugging features
+/// on top of [Preprocessor](crate::Preprocessor).
+/// All the methods do nothing by default.
+///
+/// Note that an observer is owned by the preprocessor, so use shared state
+/// (e.g., `Arc<Mutex<_>>`) if you want to retrieve the observed data later.
+pub trait ExpansionObserver: fmt::Debug + Send {
+    /// Called when a directive is read.
+    ///
+    /// Note that this is also called for the directives in skipped conditional branches.
+    fn on_directive(&mut self, directive: &Directive) {
+        let _ = directive;
+    }
+
+    /// Called when a top level macro call is expanded.
+    ///
+    /// `output` is the result of the expansion (nested macro calls are already expanded).
+    fn on_macro_expanded(&mut self, call: &MacroCall, output: &[LexicalToken]) {
+        let _ = (call, output);
+    }
+
+    /// Called when the preprocessor starts reading an included file.
+    fn on_include_start(&mut self, path: &Path) {
+        let _ = path;
+    }
+
+    /// Called when the preprocessor finishes reading an included file.
+    fn on_include_end(&mut self, path: &Path) {
+        let _ = path;
+    }
+}
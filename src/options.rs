@@ -0,0 +1,64 @@
+use erl_tokenize::{Lexer, LexicalToken};
+use std::path::PathBuf;
+
+use crate::{Preprocessor, Result};
+
+/// Options for `preprocess()`.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// The path of the source file.
+    ///
+    /// This is used as the file path of the resulting tokens and the error messages.
+    pub filepath: Option<PathBuf>,
+
+    /// Include directories searched by `-include` directives (see `Preprocessor::include_paths()`).
+    pub include_paths: Vec<PathBuf>,
+
+    /// Code paths searched by `-include_lib` directives (see `Preprocessor::code_paths()`).
+    pub code_paths: Vec<PathBuf>,
+
+    /// Macro definitions in the form of `erlc -D` option (i.e., `NAME` or `NAME=VALUE`).
+    pub defines: Vec<String>,
+}
+impl Options {
+    /// Makes a new `Options` instance with the default values.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Preprocesses the given Erlang source code and returns the resulting tokens.
+///
+/// This is a shorthand of making a `Preprocessor` instance configured by `options`
+/// and collecting its output.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate erl_pp;
+/// use erl_pp::{preprocess, Options};
+///
+/// # fn main() {
+/// let mut options = Options::new();
+/// options.defines.push("BAR=bar".to_owned());
+///
+/// let tokens = preprocess("-define(FOO, foo). [?FOO, ?BAR].", &options).unwrap();
+/// assert_eq!(tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+///            ["[", "foo", ",", "bar", "]", "."]);
+/// # }
+/// ```
+pub fn preprocess(src: &str, options: &Options) -> Result<Vec<LexicalToken>> {
+    let mut lexer = Lexer::new(src);
+    if let Some(ref path) = options.filepath {
+        lexer.set_filepath(path);
+    }
+    let mut pp = Preprocessor::new(lexer);
+    pp.include_paths_mut()
+        .extend(options.include_paths.iter().cloned());
+    pp.code_paths_mut()
+        .extend(options.code_paths.iter().cloned());
+    for definition in &options.defines {
+        pp.define_from_str(definition)?;
+    }
+    pp.collect()
+}
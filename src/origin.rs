@@ -0,0 +1,145 @@
+use erl_tokenize::{Position, PositionRange};
+use std::path::Path;
+use std::slice;
+
+use crate::types::MacroArgs;
+use crate::MacroCall;
+
+/// A macro call in the chain of the expansions which produced a token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MacroFrame {
+    /// The name of the macro.
+    pub name: String,
+
+    /// The number of the arguments of the macro call (`None` if the call has no parentheses).
+    pub arity: Option<usize>,
+
+    /// The start position of the macro call.
+    pub position: Position,
+}
+impl MacroFrame {
+    pub(crate) fn new(call: &MacroCall) -> Self {
+        MacroFrame {
+            name: call.name.value().to_owned(),
+            arity: call.args.as_ref().map(MacroArgs::len),
+            position: call.start_position(),
+        }
+    }
+}
+
+/// Origin of a token returned by a `Preprocessor`.
+#[derive(Debug, Clone)]
+pub struct TokenOrigin {
+    /// The start position of the token.
+    ///
+    /// For tokens produced by a macro expansion, this points to the macro definition
+    /// (or the macro argument) from which the token was copied.
+    pub start: Position,
+
+    /// The end position of the token.
+    pub end: Position,
+
+    /// The start and end positions of the macro call which produced the token.
+    ///
+    /// This is `None` if the token does not come from a macro expansion.
+    pub expansion: Option<(Position, Position)>,
+
+    /// The chain of the macro calls which produced the token (outermost first).
+    ///
+    /// This is empty if the token does not come from a macro expansion.
+    pub macro_chain: Vec<MacroFrame>,
+
+    /// The positions of the include directives through which
+    /// the file containing the token (or the macro call) was included (innermost first).
+    pub include_chain: Vec<Position>,
+}
+impl TokenOrigin {
+    /// Returns the path of the file in which the token appears.
+    ///
+    /// For tokens produced by a macro expansion, this is the file containing the macro call.
+    pub fn file(&self) -> Option<&Path> {
+        self.source_span().0.filepath().map(|p| p.as_path())
+    }
+
+    /// Returns `true` if the token comes from a macro expansion, otherwise `false`.
+    pub fn is_expanded(&self) -> bool {
+        self.expansion.is_some()
+    }
+
+    /// Returns the start and end positions of the range which the token replaces
+    /// in the original source.
+    ///
+    /// This is the range of the macro call for tokens produced by a macro expansion,
+    /// otherwise the range of the token itself.
+    pub fn source_span(&self) -> (&Position, &Position) {
+        match self.expansion {
+            Some((ref start, ref end)) => (start, end),
+            None => (&self.start, &self.end),
+        }
+    }
+}
+
+/// Map from the tokens returned by a `Preprocessor` to their origins in the original source.
+///
+/// The `i`-th entry corresponds to the `i`-th token returned by the preprocessor.
+///
+/// See also: [Preprocessor::set_record_origins()](crate::Preprocessor::set_record_origins).
+#[derive(Debug, Clone, Default)]
+pub struct OriginMap {
+    origins: Vec<TokenOrigin>,
+}
+impl OriginMap {
+    /// Makes a new empty `OriginMap` instance.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of the recorded tokens.
+    pub fn len(&self) -> usize {
+        self.origins.len()
+    }
+
+    /// Returns `true` if no tokens have been recorded, otherwise `false`.
+    pub fn is_empty(&self) -> bool {
+        self.origins.is_empty()
+    }
+
+    /// Returns the origin of the `index`-th output token.
+    pub fn get(&self, index: usize) -> Option<&TokenOrigin> {
+        self.origins.get(index)
+    }
+
+    /// Returns an iterator over the origins of the output tokens.
+    pub fn iter(&self) -> slice::Iter<TokenOrigin> {
+        self.origins.iter()
+    }
+
+    /// Returns the indices of the output tokens whose source spans (see `TokenOrigin::source_span()`)
+    /// contain `position`.
+    ///
+    /// Multiple indices are returned if `position` is in a macro call.
+    pub fn output_indices_at<'a>(
+        &'a self,
+        position: &'a Position,
+    ) -> impl 'a + Iterator<Item = usize> {
+        self.origins.iter().enumerate().filter_map(move |(i, o)| {
+            let (start, end) = o.source_span();
+            let contained = start.filepath() == position.filepath()
+                && start.offset() <= position.offset()
+                && position.offset() < end.offset();
+            if contained {
+                Some(i)
+            } else {
+                None
+            }
+        })
+    }
+
+    pub(crate) fn push(&mut self, origin: TokenOrigin) {
+        self.origins.push(origin);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.origins.clear();
+    }
+}
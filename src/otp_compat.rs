@@ -0,0 +1,429 @@
+//! Differential testing against OTP's `epp`.
+//!
+//! This module is available only if the `otp-compat-tests` feature is enabled.
+//! It preprocesses the same file with this crate and with `epp:scan_file/2` (via `escript`),
+//! and reports the positions where the resulting token streams diverge.
+//! An Erlang/OTP installation (OTP 24 or later) is required at runtime.
+//!
+//! Note that the tokens are compared by their kinds and values (e.g., `16#FF` equals to `255`),
+//! and `-file` attributes are emitted on both sides.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # extern crate erl_pp;
+//! use erl_pp::otp_compat::EppHarness;
+//!
+//! # fn main() {
+//! let mut harness = EppHarness::new();
+//! harness.include_paths_mut().push("include".into());
+//! let comparison = harness.compare("src/foo.erl").unwrap();
+//! for d in &comparison.divergences {
+//!     println!("{:?}", d);
+//! }
+//! # }
+//! ```
+use erl_tokenize::{Lexer, LexicalToken, Position, PositionRange};
+use std::env;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+use crate::preprocessor::file_attribute_tokens;
+use crate::util;
+use crate::{Error, Preprocessor};
+
+const SCRIPT: &str = r#"
+main([File | Args]) ->
+    {Includes, Macros} = parse_args(Args, [], []),
+    case epp:scan_file(File, [{includes, Includes}, {macros, Macros}]) of
+        {ok, Forms, _Extra} ->
+            lists:foreach(fun (Form) -> lists:foreach(fun print_token/1, Form) end, Forms);
+        {error, Reason} ->
+            io:format(standard_error, "~p~n", [Reason]),
+            halt(1)
+    end.
+
+parse_args(["-I", Dir | Rest], Is, Ms) -> parse_args(Rest, [Dir | Is], Ms);
+parse_args(["-D", Def | Rest], Is, Ms) -> parse_args(Rest, Is, [define(Def) | Ms]);
+parse_args([], Is, Ms) -> {lists:reverse(Is), lists:reverse(Ms)}.
+
+define(Def) ->
+    case string:split(Def, "=") of
+        [Name] ->
+            {list_to_atom(Name), true};
+        [Name, Value] ->
+            {ok, Tokens, _} = erl_scan:string(Value),
+            {ok, Term} = erl_parse:parse_term(Tokens ++ [{dot, 1}]),
+            {list_to_atom(Name), Term}
+    end.
+
+print_token({eof, _}) ->
+    ok;
+print_token(Token) ->
+    Line = erl_anno:line(element(2, Token)),
+    {Kind, Value} =
+        case Token of
+            {atom, _, A} -> {atom, atom_to_list(A)};
+            {var, _, V} -> {variable, atom_to_list(V)};
+            {string, _, S} -> {string, S};
+            {char, _, C} -> {char, integer_to_list(C)};
+            {integer, _, I} -> {integer, integer_to_list(I)};
+            {float, _, F} -> {float, float_to_list(F, [short])};
+            {dot, _} -> {symbol, "."};
+            _ -> {symbol, atom_to_list(element(1, Token))}
+        end,
+    Hex = binary:encode_hex(unicode:characters_to_binary(Value)),
+    io:format("~s ~w ~s~n", [Kind, Line, Hex]).
+"#;
+
+/// A token normalized for comparison.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompatToken {
+    /// The kind of the token (`"atom"`, `"char"`, `"float"`, `"integer"`, `"string"`,
+    /// `"symbol"` or `"variable"`).
+    ///
+    /// Keywords are regarded as symbols as `erl_scan` does.
+    pub kind: &'static str,
+
+    /// The value of the token (e.g., `"255"` for `16#FF`, and the code point for characters).
+    pub value: String,
+
+    /// The line number of the token.
+    pub line: usize,
+}
+impl CompatToken {
+    /// Makes a `CompatToken` instance from a lexical token.
+    pub fn new(token: &LexicalToken) -> Self {
+        let (kind, value) = match *token {
+            LexicalToken::Atom(ref t) => ("atom", t.value().to_owned()),
+            LexicalToken::Char(ref t) => ("char", u32::from(t.value()).to_string()),
+            LexicalToken::Float(ref t) => ("float", t.value().to_string()),
+            LexicalToken::Integer(ref t) => ("integer", t.value().to_string()),
+            LexicalToken::Keyword(ref t) => ("symbol", t.text().to_owned()),
+            LexicalToken::String(ref t) => ("string", t.value().to_owned()),
+            LexicalToken::Symbol(ref t) => ("symbol", t.text().to_owned()),
+            LexicalToken::Variable(ref t) => ("variable", t.value().to_owned()),
+        };
+        CompatToken {
+            kind,
+            value,
+            line: token.start_position().line(),
+        }
+    }
+
+    /// Returns `true` if this token has the same kind and value as `other` (regardless of the lines).
+    pub fn is_equivalent(&self, other: &Self) -> bool {
+        if self.kind != other.kind {
+            return false;
+        }
+        if self.kind == "float" {
+            if let (Ok(a), Ok(b)) = (self.value.parse::<f64>(), other.value.parse::<f64>()) {
+                return a == b;
+            }
+        }
+        self.value == other.value
+    }
+
+    fn parse_line(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(3, ' ');
+        let kind = match fields.next()? {
+            "atom" => "atom",
+            "char" => "char",
+            "float" => "float",
+            "integer" => "integer",
+            "string" => "string",
+            "symbol" => "symbol",
+            "variable" => "variable",
+            _ => return None,
+        };
+        let line = fields.next()?.parse().ok()?;
+        let hex = fields.next().unwrap_or("");
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| {
+                hex.get(i..i + 2)
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(CompatToken {
+            kind,
+            value: String::from_utf8(bytes).ok()?,
+            line,
+        })
+    }
+}
+
+/// A position where the token streams diverge.
+///
+/// The streams are compared form by form (i.e., each form ends with a dot),
+/// so at most one divergence is reported per form.
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    /// The index of the token in the stream of this crate.
+    pub erl_pp_index: usize,
+
+    /// The index of the token in the stream of `epp`.
+    pub epp_index: usize,
+
+    /// The token produced by this crate (`None` if the form has ended or has no counterpart).
+    pub erl_pp: Option<CompatToken>,
+
+    /// The token produced by `epp` (`None` if the form has ended or has no counterpart).
+    pub epp: Option<CompatToken>,
+}
+
+/// The result of `EppHarness::compare()`.
+#[derive(Debug, Clone)]
+pub struct EppComparison {
+    /// The tokens produced by this crate.
+    pub erl_pp: Vec<CompatToken>,
+
+    /// The tokens produced by `epp`.
+    pub epp: Vec<CompatToken>,
+
+    /// The divergences of the streams.
+    ///
+    /// The forms of the streams are aligned with each other before the comparison,
+    /// so a form missing in one stream does not make the following forms diverge.
+    pub divergences: Vec<Divergence>,
+}
+impl EppComparison {
+    /// Makes a new `EppComparison` instance by comparing the given token streams.
+    pub fn new(erl_pp: Vec<CompatToken>, epp: Vec<CompatToken>) -> Self {
+        let divergences = diff(&erl_pp, &epp);
+        EppComparison {
+            erl_pp,
+            epp,
+            divergences,
+        }
+    }
+
+    /// Returns `true` if the token streams are equivalent, otherwise `false`.
+    pub fn is_compatible(&self) -> bool {
+        self.divergences.is_empty()
+    }
+}
+
+/// Possible errors of `EppHarness`.
+#[derive(Debug, thiserror::Error)]
+pub enum CompatError {
+    /// Cannot run `escript`.
+    #[error("cannot run escript: {0}")]
+    Io(#[from] io::Error),
+
+    /// `epp` failed to preprocess the file.
+    #[error("epp failed: {0}")]
+    Epp(String),
+
+    /// This crate failed to preprocess the file.
+    #[error(transparent)]
+    ErlPp(Box<Error>),
+}
+impl From<Error> for CompatError {
+    fn from(f: Error) -> Self {
+        CompatError::ErlPp(Box::new(f))
+    }
+}
+
+/// Harness which compares the outputs of this crate and `epp`.
+#[derive(Debug, Clone)]
+pub struct EppHarness {
+    escript: PathBuf,
+    include_paths: Vec<PathBuf>,
+    defines: Vec<String>,
+}
+impl EppHarness {
+    /// Makes a new `EppHarness` instance which runs `escript` found in `PATH`.
+    pub fn new() -> Self {
+        EppHarness {
+            escript: PathBuf::from("escript"),
+            include_paths: Vec::new(),
+            defines: Vec::new(),
+        }
+    }
+
+    /// Sets the path of the `escript` executable.
+    pub fn set_escript<P: AsRef<Path>>(&mut self, path: P) {
+        self.escript = path.as_ref().to_path_buf();
+    }
+
+    /// Returns a mutable reference to the include directories passed to both preprocessors.
+    pub fn include_paths_mut(&mut self) -> &mut Vec<PathBuf> {
+        &mut self.include_paths
+    }
+
+    /// Returns a mutable reference to the macro definitions passed to both preprocessors
+    /// (in the form of `erlc -D` option).
+    pub fn defines_mut(&mut self) -> &mut Vec<String> {
+        &mut self.defines
+    }
+
+    /// Preprocesses the file with this crate.
+    ///
+    /// Like `epp`, the result starts with the `-file` attribute of the file itself.
+    pub fn run_erl_pp<P: AsRef<Path>>(&self, path: P) -> Result<Vec<CompatToken>, CompatError> {
+        let path = path.as_ref();
+        let text = util::read_file(path).map_err(|source| Error::SourceFileError {
+            source,
+            path: path.to_path_buf(),
+        })?;
+        let mut lexer = Lexer::new(text);
+        lexer.set_filepath(path);
+        let mut pp = Preprocessor::new(lexer);
+        pp.set_emit_file_attributes(true);
+        pp.include_paths_mut()
+            .extend(self.include_paths.iter().cloned());
+        for definition in &self.defines {
+            pp.define_from_str(definition)?;
+        }
+        let mut tokens = file_attribute_tokens(&path.to_string_lossy(), 1, &Position::new())
+            .iter()
+            .map(CompatToken::new)
+            .collect::<Vec<_>>();
+        for token in pp {
+            tokens.push(CompatToken::new(&token?));
+        }
+        Ok(tokens)
+    }
+
+    /// Preprocesses the file with `epp`.
+    pub fn run_epp<P: AsRef<Path>>(&self, path: P) -> Result<Vec<CompatToken>, CompatError> {
+        let script = env::temp_dir().join(format!("erl_pp_epp_{}.escript", process::id()));
+        fs::write(&script, format!("#!/usr/bin/env escript\n{}", SCRIPT))?;
+
+        let mut command = Command::new(&self.escript);
+        command.arg(&script).arg(path.as_ref());
+        for dir in &self.include_paths {
+            command.arg("-I").arg(dir);
+        }
+        for definition in &self.defines {
+            command.arg("-D").arg(definition);
+        }
+        let output = command.output();
+        let _ = fs::remove_file(&script);
+        let output = output?;
+        if !output.status.success() {
+            return Err(CompatError::Epp(
+                String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+            ));
+        }
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|line| {
+                CompatToken::parse_line(line)
+                    .ok_or_else(|| CompatError::Epp(format!("unexpected output: {:?}", line)))
+            })
+            .collect()
+    }
+
+    /// Preprocesses the file with both preprocessors and compares the results.
+    pub fn compare<P: AsRef<Path>>(&self, path: P) -> Result<EppComparison, CompatError> {
+        let erl_pp = self.run_erl_pp(path.as_ref())?;
+        let epp = self.run_epp(path.as_ref())?;
+        Ok(EppComparison::new(erl_pp, epp))
+    }
+}
+impl Default for EppHarness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn diff(erl_pp: &[CompatToken], epp: &[CompatToken]) -> Vec<Divergence> {
+    let a = split_forms(erl_pp);
+    let b = split_forms(epp);
+    let same = |i: usize, j: usize| is_equivalent_form(&erl_pp[a[i].clone()], &epp[b[j].clone()]);
+
+    // Aligns the forms by the longest common subsequence of the equivalent forms.
+    let mut lcs = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if same(i, j) {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    // The unaligned forms between the aligned ones are compared in order.
+    let mut divergences = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    let (mut unaligned_a, mut unaligned_b) = (Vec::<usize>::new(), Vec::<usize>::new());
+    loop {
+        let aligned = i < a.len() && j < b.len() && same(i, j);
+        if aligned || i == a.len() && j == b.len() {
+            let end_a = a.get(i).map_or(erl_pp.len(), |r| r.start);
+            let end_b = b.get(j).map_or(epp.len(), |r| r.start);
+            for k in 0..unaligned_a.len().max(unaligned_b.len()) {
+                let form_a = unaligned_a.get(k).map(|&x| a[x].clone());
+                let form_b = unaligned_b.get(k).map(|&y| b[y].clone());
+                divergences.extend(diff_form(
+                    erl_pp,
+                    form_a.unwrap_or(end_a..end_a),
+                    epp,
+                    form_b.unwrap_or(end_b..end_b),
+                ));
+            }
+            unaligned_a.clear();
+            unaligned_b.clear();
+            if !aligned {
+                break;
+            }
+            i += 1;
+            j += 1;
+        } else if j == b.len() || (i < a.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            unaligned_a.push(i);
+            i += 1;
+        } else {
+            unaligned_b.push(j);
+            j += 1;
+        }
+    }
+    divergences
+}
+
+/// Returns the first divergence of the given forms.
+fn diff_form(
+    erl_pp: &[CompatToken],
+    a: Range<usize>,
+    epp: &[CompatToken],
+    b: Range<usize>,
+) -> Option<Divergence> {
+    (0..a.len().max(b.len())).find_map(|k| {
+        let x = erl_pp[a.clone()].get(k);
+        let y = epp[b.clone()].get(k);
+        match (x, y) {
+            (Some(x), Some(y)) if x.is_equivalent(y) => None,
+            _ => Some(Divergence {
+                erl_pp_index: a.start + k.min(a.len()),
+                epp_index: b.start + k.min(b.len()),
+                erl_pp: x.cloned(),
+                epp: y.cloned(),
+            }),
+        }
+    })
+}
+
+/// Splits `tokens` into the ranges of the forms (the last one may not end with a dot).
+fn split_forms(tokens: &[CompatToken]) -> Vec<Range<usize>> {
+    let mut forms = Vec::new();
+    let mut start = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        if token.kind == "symbol" && token.value == "." {
+            forms.push(start..i + 1);
+            start = i + 1;
+        }
+    }
+    if start < tokens.len() {
+        forms.push(start..tokens.len());
+    }
+    forms
+}
+
+fn is_equivalent_form(a: &[CompatToken], b: &[CompatToken]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(x, y)| x.is_equivalent(y))
+}
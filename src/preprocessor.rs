@@ -1,13 +1,34 @@
-use erl_tokenize::tokens::{AtomToken, IntegerToken, StringToken, VariableToken};
+use erl_tokenize::tokens::{
+    AtomToken, CharToken, FloatToken, IntegerToken, KeywordToken, StringToken, SymbolToken,
+    VariableToken,
+};
 use erl_tokenize::values::Symbol;
 use erl_tokenize::{self, LexicalToken, Position, PositionRange};
-use std::collections::{BTreeMap, HashMap, VecDeque};
-use std::path::PathBuf;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt;
+use std::mem;
+use std::path::{Path, PathBuf};
 
-use crate::macros::Stringify;
+use crate::condition;
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::expander::{ExpansionTrace, MacroExpander, DEFAULT_MAX_EXPANSION_DEPTH};
+use crate::graph;
+use crate::macros::{NoArgsMacroCall, RESERVED_MACROS};
+use crate::observer::ExpansionObserver;
+use crate::origin::{OriginMap, TokenOrigin};
+#[cfg(feature = "fs")]
+use crate::resolver::FileSystemResolver;
+use crate::resolver::{IncludeKind, IncludeResolver};
+use crate::text::TextWriter;
 use crate::token_reader::TokenReader;
-use crate::types::{MacroArgs, MacroVariables};
-use crate::{Directive, Error, MacroCall, MacroDef, Result};
+use crate::types::{MacroName, MacroVariables, ToTokens};
+use crate::util::{self, Arena, EnvVars, HashSet, StableHasher};
+use crate::workspace::Workspace;
+use crate::xref::{MacroXref, ObservedCall};
+use crate::{
+    Directive, Error, MacroCall, MacroDef, MacroEnv, MacroMap, Result, SourceLine, SourceMap,
+};
 
 /// Erlang source code [preprocessor][Preprocessor].
 ///
@@ -35,63 +56,515 @@ use crate::{Directive, Error, MacroCall, MacroDef, Result};
 ///            ["[", "1", ",", "1", "]", "."]);
 /// # }
 /// ```
+///
+/// Unlike `erlc`, the preprocessor cannot know the target OTP release,
+/// so `?OTP_RELEASE` is undefined until the caller sets it
+/// via [set_otp_release](Preprocessor::set_otp_release).
 #[derive(Debug)]
 pub struct Preprocessor<T> {
     reader: TokenReader<T>,
     can_directive_start: bool,
     directives: BTreeMap<Position, Directive>,
+    include_paths: Vec<PathBuf>,
     code_paths: VecDeque<PathBuf>,
+    env_vars: EnvVars,
+    workspace: Workspace,
+    include_resolver: Box<dyn IncludeResolver>,
+    source_map: Option<BoxedSourceMap>,
+    defer_includes: bool,
+    pending_include: Option<PendingInclude>,
+    observer: Option<Box<dyn ExpansionObserver>>,
+    observed_includes: Vec<PathBuf>,
     branches: Vec<Branch>,
-    macros: HashMap<String, MacroDef>,
+    env: MacroEnv,
+    initial_env: Option<MacroEnv>,
+    include_isolation: IncludeIsolation,
+    include_scopes: Vec<IncludeScope>,
+    leaked_macros: Vec<LeakedMacro>,
+    redefinition_policy: RedefinitionPolicy,
+    macro_redefinitions: Vec<MacroRedefinition>,
+    fail_on_error_directive: bool,
+    warn_cross_file_conditionals: bool,
+    cross_file_conditionals: Vec<CrossFileConditional>,
+    warnings: Vec<WarningDirective>,
+    diagnostics: Vec<Diagnostic>,
+    included_files: Vec<IncludedFile>,
+    skipped_regions: Vec<SkippedRegion>,
+    skipping_since: Option<(Position, Position)>,
     macro_calls: BTreeMap<Position, MacroCall>,
+    expanded_macros: RefCell<HashSet<String>>,
+    undefined_macro_placeholder: Option<String>,
+    placeholder_calls: RefCell<Vec<MacroCall>>,
+    skipped_macro_refs: BTreeMap<Position, MacroName>,
+    conditional_macro_refs: BTreeMap<Position, MacroName>,
+    defined_test_refs: BTreeMap<Position, MacroName>,
+    defined_macro_names: HashSet<String>,
     expanded_tokens: VecDeque<LexicalToken>,
+    passthrough_tokens: VecDeque<LexicalToken>,
+    arena: Arena,
+    lent_token: Option<LexicalToken>,
+    max_expansion_depth: usize,
+    emit_directives: bool,
+    execute_includes: bool,
+    expand_macros: bool,
+    record_history: bool,
+    streaming: bool,
+    error_recovery: bool,
+    recovered_errors: Vec<Error>,
+    emit_file_attributes: bool,
+    file_returns: Vec<FileReturn>,
+    directive_tokens: VecDeque<LexicalToken>,
+    features: BTreeMap<String, bool>,
+    available_features: BTreeSet<String>,
+    function: FunctionTracker,
+    module: ModuleTracker,
+    in_directive_region: bool,
+    in_expansion_region: bool,
+    expansion_origin: Option<(Position, Position)>,
+    record_origins: bool,
+    origins: OriginMap,
+    remap_expanded_positions: bool,
+    expansion_trace: ExpansionTrace,
+    record_macro_xref: bool,
+    macro_xref: MacroXref,
+    observed_calls: RefCell<Vec<ObservedCall>>,
 }
 impl<T> Preprocessor<T>
 where
     T: Iterator<Item = erl_tokenize::Result<LexicalToken>>,
 {
     /// Makes a new `Preprocessor` instance.
+    ///
+    /// The input is a sequence of lexical tokens (e.g., `erl_tokenize::Lexer`).
+    /// Since `LexicalToken` has no variants for whitespaces and comments,
+    /// they are never contained in the input nor the output (including macro replacements);
+    /// use `erl_tokenize::Lexer` instead of `erl_tokenize::Tokenizer` to drop them from a source text.
     pub fn new(tokens: T) -> Self {
         Preprocessor {
             reader: TokenReader::new(tokens),
             can_directive_start: true,
             directives: BTreeMap::new(),
+            include_paths: Vec::new(),
             code_paths: VecDeque::new(),
+            env_vars: EnvVars::default(),
+            workspace: Workspace::new(),
+            include_resolver: default_include_resolver(),
+            source_map: None,
+            defer_includes: false,
+            pending_include: None,
+            observer: None,
+            observed_includes: Vec::new(),
             branches: Vec::new(),
-            macros: HashMap::new(),
+            env: MacroEnv::new(),
+            initial_env: None,
+            include_isolation: IncludeIsolation::Disabled,
+            include_scopes: Vec::new(),
+            leaked_macros: Vec::new(),
+            redefinition_policy: RedefinitionPolicy::AllowOverride,
+            macro_redefinitions: Vec::new(),
+            fail_on_error_directive: false,
+            warn_cross_file_conditionals: false,
+            cross_file_conditionals: Vec::new(),
+            warnings: Vec::new(),
+            diagnostics: Vec::new(),
+            included_files: Vec::new(),
+            skipped_regions: Vec::new(),
+            skipping_since: None,
             macro_calls: BTreeMap::new(),
+            expanded_macros: RefCell::new(HashSet::default()),
+            undefined_macro_placeholder: None,
+            placeholder_calls: RefCell::new(Vec::new()),
+            skipped_macro_refs: BTreeMap::new(),
+            conditional_macro_refs: BTreeMap::new(),
+            defined_test_refs: BTreeMap::new(),
+            defined_macro_names: HashSet::default(),
             expanded_tokens: VecDeque::new(),
+            passthrough_tokens: VecDeque::new(),
+            arena: Arena::new(),
+            lent_token: None,
+            max_expansion_depth: DEFAULT_MAX_EXPANSION_DEPTH,
+            emit_directives: false,
+            execute_includes: true,
+            expand_macros: true,
+            record_history: true,
+            streaming: false,
+            error_recovery: false,
+            recovered_errors: Vec::new(),
+            emit_file_attributes: false,
+            file_returns: Vec::new(),
+            directive_tokens: VecDeque::new(),
+            features: BTreeMap::new(),
+            available_features: BTreeSet::new(),
+            function: FunctionTracker::default(),
+            module: ModuleTracker::default(),
+            in_directive_region: false,
+            in_expansion_region: false,
+            expansion_origin: None,
+            record_origins: false,
+            remap_expanded_positions: false,
+            origins: OriginMap::new(),
+            expansion_trace: RefCell::new(BTreeMap::new()),
+            record_macro_xref: false,
+            macro_xref: MacroXref::new(),
+            observed_calls: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Resets this preprocessor to process the given new tokens.
+    ///
+    /// The internal buffers are cleared but their allocated capacity is retained,
+    /// so this is cheaper than making a new `Preprocessor` instance for each file.
+    ///
+    /// The code paths, the workspace and the settings of this preprocessor are kept.
+    /// The macro table is restored to the state just before the first token was read
+    /// (i.e., macros added via `macros_mut()` beforehand are kept, and
+    /// the ones defined by the preprocessed source are discarded).
+    /// Clear them explicitly via `include_paths_mut()`, `code_paths_mut()` and `macros_mut()`
+    /// if needed.
+    pub fn reset(&mut self, tokens: T) {
+        self.reader.reset(tokens);
+        self.can_directive_start = true;
+        self.directives.clear();
+        self.branches.clear();
+        if let Some(env) = self.initial_env.take() {
+            self.env = env;
+        }
+        self.include_scopes.clear();
+        self.leaked_macros.clear();
+        self.macro_redefinitions.clear();
+        self.cross_file_conditionals.clear();
+        self.warnings.clear();
+        self.diagnostics.clear();
+        self.recovered_errors.clear();
+        self.included_files.clear();
+        self.skipped_regions.clear();
+        self.skipping_since = None;
+        self.macro_calls.clear();
+        self.expanded_macros.borrow_mut().clear();
+        self.placeholder_calls.borrow_mut().clear();
+        self.macro_xref.clear();
+        self.observed_calls.borrow_mut().clear();
+        self.skipped_macro_refs.clear();
+        self.conditional_macro_refs.clear();
+        self.defined_test_refs.clear();
+        self.defined_macro_names.clear();
+        self.expanded_tokens.clear();
+        self.passthrough_tokens.clear();
+        self.lent_token = None;
+        self.directive_tokens.clear();
+        self.file_returns.clear();
+        self.pending_include = None;
+        self.observed_includes.clear();
+        self.features.clear();
+        self.function = FunctionTracker::default();
+        self.module = ModuleTracker::default();
+        self.in_directive_region = false;
+        self.in_expansion_region = false;
+        self.expansion_origin = None;
+        self.origins.clear();
+        self.expansion_trace.borrow_mut().clear();
+    }
+
+    /// Consumes this preprocessor and returns the underlying token source
+    /// together with the tokens which have been read from it (or included files) but not consumed yet.
+    ///
+    /// The returned tokens should be processed before the ones of the token source
+    /// to resume raw tokenization.
+    /// Note that the unread tokens of the files being included are discarded.
+    pub fn into_inner(self) -> (T, Vec<LexicalToken>) {
+        self.reader.into_inner()
+    }
+
+    /// Reads the next token and returns a reference to it.
+    ///
+    /// This is a lending alternative to `Iterator::next`.
+    /// The returned token is kept in a buffer owned by this preprocessor until the next call,
+    /// so consumers that only inspect tokens (e.g., counters and searchers) can
+    /// process the stream without taking ownership of each token.
+    pub fn next_ref(&mut self) -> Option<Result<&LexicalToken>> {
+        self.lent_token = None;
+        match self.next_token() {
+            Err(e) => Some(Err(self.with_error_context(e))),
+            Ok(None) => None,
+            Ok(Some(token)) => Some(Ok(self.lent_token.get_or_insert(token))),
+        }
+    }
+
+    /// Expands the given macro call under the current state of this preprocessor
+    /// (i.e., the macros, features and function defined so far).
+    ///
+    /// This is useful for features like "expand macro under cursor" of editors.
+    /// The state of this preprocessor is not changed.
+    pub fn expand_call(&self, call: &MacroCall) -> Result<Vec<LexicalToken>> {
+        self.snapshot_expander()
+            .expand_call(call.clone())
+            .map_err(|e| self.with_error_context(e))
+    }
+
+    /// Preprocesses the remaining tokens and returns the resulting text
+    /// which preserves the layout (i.e., whitespaces, newlines and comments)
+    /// of the original source as much as possible.
+    ///
+    /// `sources` is used to retrieve the text of the source given to this preprocessor.
+    /// The text of included files is retrieved from this preprocessor itself.
+    /// See [TextWriter](crate::TextWriter) for the details of the layout reconstruction.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate erl_pp;
+    /// # extern crate erl_tokenize;
+    /// use erl_pp::Preprocessor;
+    /// use erl_tokenize::Lexer;
+    ///
+    /// # fn main() {
+    /// let src = "-define(FOO, 1 + 2).\n%% comment\nfoo() ->\n    ?FOO.";
+    /// let text = Preprocessor::new(Lexer::new(src)).preprocessed_text(src).unwrap();
+    /// assert_eq!(text, "\n%% comment\nfoo() ->\n    1 + 2.");
+    /// # }
+    /// ```
+    pub fn preprocessed_text<S>(&mut self, sources: &S) -> Result<String>
+    where
+        S: SourceMap + ?Sized,
+    {
+        let mut writer = TextWriter::new(String::new(), sources);
+        while let Some(token) = self.next() {
+            let token = token?;
+            if let Some(path) = token.start_position().filepath() {
+                if let Some(text) = self.reader.included_text(path) {
+                    writer.add_text(path, text);
+                }
+            }
+            if self.in_expansion_region {
+                writer.write_generated_token(&token, self.expansion_origin.as_ref().map(|o| &o.0))
+            } else {
+                writer.write_token(&token)
+            }
+            .expect("never fails");
+        }
+        Ok(writer.finish().expect("never fails"))
+    }
+
+    /// Returns an iterator which groups the resulting tokens by forms (i.e., each item ends with a dot).
+    ///
+    /// This is the unit handed to parsers (e.g., the `erl_parse` crate).
+    /// The `examples/erl_parse_bridge/` companion crate in the repository uses this
+    /// to return the forms parsed by `erl_parse` together with the preprocessing metadata
+    /// (it is a separate crate since `erl_parse` depends on `erl_pp`).
+    /// The metadata of the preprocessing (e.g., `included_files()` and `warnings()`)
+    /// can be retrieved from this preprocessor after the iteration.
+    /// If an error occurs, the rest of the form is skipped.
+    /// The last item may not end with a dot if the source is truncated.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate erl_pp;
+    /// # extern crate erl_tokenize;
+    /// use erl_pp::Preprocessor;
+    /// use erl_tokenize::Lexer;
+    ///
+    /// # fn main() {
+    /// let mut pp = Preprocessor::new(Lexer::new("-module(m). -define(A, 1). f() -> ?A."));
+    /// let forms = pp.forms().collect::<Result<Vec<_>, _>>().unwrap();
+    /// assert_eq!(forms.len(), 2);
+    /// assert_eq!(forms[1].iter().map(|t| t.text()).collect::<Vec<_>>(),
+    ///            ["f", "(", ")", "->", "1", "."]);
+    /// # }
+    /// ```
+    pub fn forms(&mut self) -> Forms<'_, T> {
+        Forms {
+            pp: self,
+            failed: false,
+        }
+    }
+
+    pub(crate) fn with_error_context(&self, mut e: Error) -> Error {
+        e.set_include_stack(self.reader.include_stack());
+        let position = match e.position() {
+            None => return e,
+            Some(position) => position,
+        };
+        let path = position.filepath().map(|p| p.as_path());
+        let line = if let Some(text) = path.and_then(|p| self.reader.included_text(p)) {
+            SourceLine::new(text, &position)
+        } else {
+            // The files which are not held by the reader (e.g., the main source file) are never
+            // read from the filesystem here, since their texts may differ from the lexed ones.
+            self.source_map
+                .as_ref()
+                .and_then(|s| s.0.source_text(path))
+                .and_then(|text| SourceLine::new(&text, &position))
+        };
+        if let Some(line) = line {
+            e.set_source_line(line);
         }
+        e
     }
 
     fn ignore(&self) -> bool {
         self.branches.iter().any(|b| !b.entered)
     }
     fn next_token(&mut self) -> Result<Option<LexicalToken>> {
+        let mut token = self.read_next_token()?;
+        if let Some(ref token) = token {
+            if !self.in_directive_region {
+                self.function.observe(token);
+                if let Some(module) = self.module.observe(token) {
+                    self.define_module_macros(module);
+                }
+            }
+            if self.record_origins {
+                let expansion = self
+                    .expansion_origin
+                    .clone()
+                    .filter(|_| self.in_expansion_region);
+                let macro_chain = if expansion.is_some() {
+                    let trace = self.expansion_trace.borrow();
+                    trace
+                        .get(&token.start_position())
+                        .cloned()
+                        .unwrap_or_default()
+                } else {
+                    Vec::new()
+                };
+                self.origins.push(TokenOrigin {
+                    start: token.start_position(),
+                    end: token.end_position(),
+                    expansion,
+                    macro_chain,
+                    include_chain: self.reader.include_stack(),
+                });
+            }
+        }
+        if self.remap_expanded_positions && self.in_expansion_region {
+            if let Some((ref start, _)) = self.expansion_origin {
+                token = token.map(|t| relocate_token(t, start.clone()));
+            }
+        }
+        Ok(token)
+    }
+    fn read_next_token(&mut self) -> Result<Option<LexicalToken>> {
+        self.in_directive_region = false;
+        self.in_expansion_region = false;
+        if self.initial_env.is_none() {
+            self.initial_env = Some(self.env.clone());
+        }
         loop {
+            if let Some(token) = self.directive_tokens.pop_front() {
+                self.in_directive_region = true;
+                return Ok(Some(token));
+            }
             if let Some(token) = self.expanded_tokens.pop_front() {
+                self.in_expansion_region = true;
+                return Ok(Some(token));
+            }
+            self.expansion_origin = None;
+            if let Some(token) = self.passthrough_tokens.pop_front() {
                 return Ok(Some(token));
             }
+            self.leave_finished_includes()?;
+            if !self.passthrough_tokens.is_empty() {
+                continue;
+            }
+            if !self.ignore() {
+                // Fast path: forwards tokens which can start neither directives nor macro calls.
+                match self.reader.try_read_token()? {
+                    None => break,
+                    Some(token) => {
+                        if !self.can_start_directive_or_macro_call(&token) {
+                            self.can_directive_start = is_dot(&token);
+                            return Ok(Some(token));
+                        }
+                        self.reader.unread_token(token);
+                    }
+                }
+            }
             if self.can_directive_start {
-                if let Some(d) = self.try_read_directive()? {
-                    self.directives.insert(d.start_position(), d);
+                let directive = match self.try_read_directive() {
+                    Err(e) => {
+                        self.recover(Err(e), ())?;
+                        continue;
+                    }
+                    Ok(directive) => directive,
+                };
+                if let Some(d) = directive {
+                    if self.emit_directives {
+                        self.directive_tokens.extend(d.tokens());
+                    } else if let Directive::Feature(_) = d {
+                        // The compiler needs `-feature` attributes, so they are kept in the output.
+                        if !self.ignore() {
+                            self.passthrough_tokens.extend(d.tokens());
+                        }
+                    }
+                    if self.record_history {
+                        self.directives.insert(d.start_position(), d);
+                    }
+                    if self.pending_include.is_some() {
+                        // Suspends until the included file is provided via `provide_include()`.
+                        return Ok(None);
+                    }
                     continue;
                 }
             }
             if !self.ignore() {
-                if let Some(m) = self.reader.try_read_macro_call(&self.macros)? {
-                    self.macro_calls.insert(m.start_position(), m.clone());
-                    self.expanded_tokens = self.expand_macro(m)?;
+                let call = match self.reader.try_read_macro_call(&self.env) {
+                    Err(e) => {
+                        self.recover(Err(e), ())?;
+                        continue;
+                    }
+                    Ok(call) => call,
+                };
+                if let Some(m) = call {
+                    if !self.expand_macros {
+                        self.passthrough_tokens.extend(m.tokens());
+                        self.record_macro_call(m);
+                        continue;
+                    }
+                    let origin = (m.start_position(), m.end_position());
+                    self.expansion_trace.get_mut().clear();
+                    let expanded = self
+                        .expander()
+                        .expand_macro(&self.arena, &m, &mut Vec::new())
+                        .map(|tokens| tokens.into_iter().collect());
+                    self.finish_expansion();
+                    let expanded = match self.recover(expanded.map(Some), None) {
+                        Err(e) => {
+                            self.record_macro_call(m);
+                            return Err(e);
+                        }
+                        Ok(expanded) => expanded,
+                    };
+                    match expanded {
+                        Some(expanded) => {
+                            self.expanded_tokens = expanded;
+                            self.expansion_origin = Some(origin);
+                        }
+                        None => {
+                            // The macro call is emitted as it is.
+                            self.passthrough_tokens.extend(m.tokens());
+                            self.record_macro_call(m);
+                            continue;
+                        }
+                    }
+                    if let Some(observer) = self.observer.as_mut() {
+                        observer.on_macro_expanded(&m, self.expanded_tokens.make_contiguous());
+                    }
+                    self.record_macro_call(m);
                     continue;
                 }
+            } else if let Some(m) = self.reader.try_read::<NoArgsMacroCall>()? {
+                if !self.streaming {
+                    self.skipped_macro_refs
+                        .insert(m._question.start_position(), m.name);
+                }
+                continue;
             }
             if let Some(token) = self.reader.try_read_token()? {
                 if self.ignore() {
                     continue;
                 }
-                self.can_directive_start = token
-                    .as_symbol_token()
-                    .map_or(false, |s| s.value() == Symbol::Dot);
+                self.can_directive_start = is_dot(&token);
                 return Ok(Some(token));
             } else {
                 break;
@@ -99,132 +572,522 @@ where
         }
         Ok(None)
     }
-    fn expand_macro(&self, call: MacroCall) -> Result<VecDeque<LexicalToken>> {
-        if let Some(expanded) = self.try_expand_predefined_macro(&call)? {
-            Ok(vec![expanded].into())
-        } else {
-            self.expand_userdefined_macro(call)
-        }
+    fn can_start_directive_or_macro_call(&self, token: &LexicalToken) -> bool {
+        token.as_symbol_token().is_some_and(|s| match s.value() {
+            Symbol::Question => true,
+            Symbol::Hyphen => self.can_directive_start,
+            _ => false,
+        })
     }
-    fn try_expand_predefined_macro(&self, call: &MacroCall) -> Result<Option<LexicalToken>> {
-        let expanded = match call.name.value() {
-            "FILE" => {
-                let current = call.start_position();
-                let file = current
-                    .filepath()
-                    .and_then(|f| f.to_str())
-                    .ok_or_else(|| Error::file_not_set(call.clone()))?;
-                StringToken::from_value(file, call.start_position()).into()
+    fn enter_include(&mut self, path: PathBuf) {
+        let saved_macros = match self.include_isolation {
+            IncludeIsolation::Disabled => return,
+            IncludeIsolation::Outward => self.env.macros().clone(),
+            IncludeIsolation::Full => {
+                // Only the user defined macros are hidden from the included file
+                // (`?MODULE`, `?OTP_RELEASE` and the registered macro functions remain visible).
+                let saved = mem::take(self.env.macros_mut());
+                for name in ["MODULE", "MODULE_STRING"] {
+                    for definition in saved.definitions(name) {
+                        self.env
+                            .macros_mut()
+                            .insert(name.to_owned(), definition.clone());
+                    }
+                }
+                saved
             }
-            "LINE" => {
-                let line = call.start_position().line();
-                IntegerToken::from_value(line.into(), call.start_position()).into()
+        };
+        self.include_scopes.push(IncludeScope {
+            path,
+            depth: self.reader.include_depth(),
+            saved_macros,
+            defined: Vec::new(),
+        });
+    }
+    fn resolve_include(
+        &mut self,
+        kind: IncludeKind,
+        path: PathBuf,
+        search_dirs: Vec<PathBuf>,
+        directive: Directive,
+    ) -> Result<()> {
+        let include = PendingInclude {
+            kind,
+            path,
+            search_dirs,
+            directive,
+        };
+        if self.defer_includes {
+            self.pending_include = Some(include);
+            return Ok(());
+        }
+        let resolved =
+            self.include_resolver
+                .resolve(include.kind, &include.path, &include.search_dirs);
+        self.provide_include(include, resolved)
+    }
+    pub(crate) fn provide_include(
+        &mut self,
+        include: PendingInclude,
+        resolved: std::io::Result<(PathBuf, String)>,
+    ) -> Result<()> {
+        let (path, mut text) = match resolved {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                return Err(Error::include_file_error(
+                    e,
+                    &include.directive,
+                    include.path,
+                ))
             }
-            "MACHINE" => AtomToken::from_value("BEAM", call.start_position()).into(),
-            _ => return Ok(None),
         };
-        Ok(Some(expanded))
-    }
-    fn expand_userdefined_macro(&self, call: MacroCall) -> Result<VecDeque<LexicalToken>> {
-        let definition = self
-            .macros
-            .get(call.name.value())
-            .ok_or_else(|| Error::undefined_macro(call.clone()))?;
-        match *definition {
-            MacroDef::Dynamic(ref replacement) => Ok(replacement.clone().into()),
-            MacroDef::Static(ref definition) => {
-                if call.args.as_ref().map(MacroArgs::len)
-                    != definition.variables.as_ref().map(MacroVariables::len)
-                {
-                    return Err(Error::macro_args_mismatched(
-                        call.clone(),
-                        MacroDef::Static(definition.clone()),
+        // The positions in the file are relative to the text without the byte order mark.
+        let bom = util::strip_bom(&mut text);
+        self.reader
+            .add_included_text(&path, text, include.directive.start_position());
+        self.included_files.push(IncludedFile {
+            path: path.clone(),
+            kind: include.kind,
+            position: include.directive.start_position(),
+            bom,
+        });
+        if let Some(observer) = self.observer.as_mut() {
+            observer.on_include_start(&path);
+            self.observed_includes.push(path.clone());
+        }
+        self.enter_file(&path, &include.directive)?;
+        self.enter_include(path);
+        Ok(())
+    }
+    #[cfg(feature = "async")]
+    pub(crate) fn set_defer_includes(&mut self, defer: bool) {
+        self.defer_includes = defer;
+    }
+    #[cfg(feature = "async")]
+    pub(crate) fn take_pending_include(&mut self) -> Option<PendingInclude> {
+        self.pending_include.take()
+    }
+    pub(crate) fn next_item(&mut self) -> Option<Result<LexicalToken>> {
+        match self.next_token() {
+            Err(e) => Some(Err(self.with_error_context(e))),
+            Ok(None) => None,
+            Ok(Some(token)) => Some(Ok(token)),
+        }
+    }
+    // As `epp`, `?MODULE` and `?MODULE_STRING` are defined by the `-module` attribute.
+    fn define_module_macros(&mut self, module: AtomToken) {
+        let string = StringToken::from_value(module.value(), module.start_position());
+        let macros = self.env.macros_mut();
+        macros.insert(
+            "MODULE_STRING".to_owned(),
+            MacroDef::Dynamic(vec![string.into()].into()),
+        );
+        macros.insert(
+            "MODULE".to_owned(),
+            MacroDef::Dynamic(vec![module.into()].into()),
+        );
+    }
+    fn record_macro_call(&mut self, call: MacroCall) {
+        // The call is moved into the history (if recorded) instead of being cloned.
+        if self.record_history {
+            self.macro_calls.insert(call.start_position(), call);
+        }
+    }
+    fn enter_file(&mut self, path: &Path, directive: &impl PositionRange) -> Result<()> {
+        if !self.emit_file_attributes {
+            return Ok(());
+        }
+        let path = path.to_str().ok_or_else(|| Error::non_utf8_path(path))?;
+        let position = directive.start_position();
+        self.passthrough_tokens
+            .extend(file_attribute_tokens(path, 1, &position));
+        self.file_returns.push(FileReturn {
+            depth: self.reader.include_depth(),
+            line: directive.end_position().line(),
+            position,
+        });
+        Ok(())
+    }
+    fn leave_finished_includes(&mut self) -> Result<()> {
+        if self.include_scopes.is_empty()
+            && self.file_returns.is_empty()
+            && self.observed_includes.is_empty()
+        {
+            return Ok(());
+        }
+
+        // Reads ahead a token to pop exhausted included files.
+        if let Some(token) = self.reader.try_read_token()? {
+            self.reader.unread_token(token);
+        }
+        while self
+            .include_scopes
+            .last()
+            .is_some_and(|s| s.depth > self.reader.include_depth())
+        {
+            let scope = self.include_scopes.pop().expect("unreachable");
+            let inner_macros = mem::replace(self.env.macros_mut(), scope.saved_macros);
+            for (i, (name, position)) in scope.defined.iter().enumerate() {
+                let redefined_later = scope.defined[i + 1..].iter().any(|(n, _)| n == name);
+                if inner_macros.contains_key(name) && !redefined_later {
+                    self.diagnostics.push(Diagnostic::new(
+                        Severity::Warning,
+                        "EPP1003",
+                        format!(
+                            "macro {:?} defined in {:?} does not leak out of the file",
+                            name, scope.path
+                        ),
+                        position.clone(),
+                        position.clone(),
                     ));
+                    self.leaked_macros.push(LeakedMacro {
+                        name: name.clone(),
+                        file: scope.path.clone(),
+                        position: position.clone(),
+                    });
                 }
-                let bindings = definition
-                    .variables
-                    .as_ref()
-                    .iter()
-                    .flat_map(|i| i.iter().map(VariableToken::value))
-                    .zip(
-                        call.args
-                            .iter()
-                            .flat_map(|i| i.iter().map(|a| &a.tokens[..])),
-                    )
-                    .collect::<HashMap<_, _>>();
-                let expanded = self.expand_replacement(bindings, &definition.replacement)?;
-                Ok(expanded)
-            }
-        }
-    }
-    fn expand_replacement(
-        &self,
-        bindings: HashMap<&str, &[LexicalToken]>,
-        replacement: &[LexicalToken],
-    ) -> Result<VecDeque<LexicalToken>> {
-        let mut expanded = VecDeque::new();
-        let mut reader: TokenReader<_> =
-            TokenReader::new(replacement.iter().map(|t| Ok(t.clone())));
-        loop {
-            if let Some(call) = reader.try_read_macro_call(&self.macros)? {
-                let nested = self.expand_macro(call)?;
-                for token in nested.into_iter().rev() {
-                    reader.unread_token(token);
+            }
+        }
+        while self.observed_includes.len() > self.reader.include_depth() {
+            let path = self.observed_includes.pop().expect("unreachable");
+            if let Some(observer) = self.observer.as_mut() {
+                observer.on_include_end(&path);
+            }
+        }
+        while self
+            .file_returns
+            .last()
+            .is_some_and(|r| r.depth > self.reader.include_depth())
+        {
+            let r = self.file_returns.pop().expect("unreachable");
+            if let Some(path) = r.position.filepath() {
+                let path = path.to_str().ok_or_else(|| Error::non_utf8_path(path))?;
+                self.passthrough_tokens
+                    .extend(file_attribute_tokens(path, r.line, &r.position));
+            }
+        }
+        Ok(())
+    }
+    fn evaluate_condition(&mut self, condition: &[LexicalToken]) -> Result<bool> {
+        let result = self
+            .expand_directive_args(condition)
+            .and_then(|tokens| condition::evaluate(&tokens, |name| self.env.is_defined(name)));
+
+        // In the error recovery mode, invalid conditions are regarded as `false`.
+        self.recover(result, false)
+    }
+    fn recover<V>(&mut self, result: Result<V>, fallback: V) -> Result<V> {
+        match result {
+            Err(e) if self.error_recovery && e.is_recoverable() => {
+                self.record_recovered_error(e);
+                Ok(fallback)
+            }
+            result => result,
+        }
+    }
+    fn record_recovered_error(&mut self, e: Error) {
+        let e = self.with_error_context(e);
+        self.diagnostics.push(Diagnostic::from_error(&e));
+        self.recovered_errors.push(e);
+    }
+    fn read_directive(&mut self) -> Result<Option<Directive>> {
+        if !self.error_recovery {
+            return self.reader.try_read();
+        }
+        match self.reader.try_read_token()? {
+            None => return Ok(None),
+            Some(token) => {
+                let hyphen = is_hyphen(&token);
+                self.reader.unread_token(token);
+                if !hyphen {
+                    return Ok(None);
+                }
+            }
+        }
+        match self.reader.read::<Directive>() {
+            Ok(directive) => Ok(Some(directive)),
+            Err(Error::UnexpectedToken { token, .. }) if is_hyphen(&token) => {
+                // Not a directive (e.g., `-module(foo).`).
+                self.reader.unread_token(token);
+                Ok(None)
+            }
+            Err(e @ Error::UnexpectedEof) => {
+                // The directive is truncated, so the preprocessing stops after recording the error.
+                self.record_recovered_error(e);
+                Ok(None)
+            }
+            Err(e) => {
+                // Skips the rest of the malformed directive.
+                let at_dot = match e {
+                    Error::UnexpectedToken { ref token, .. } => is_dot(token),
+                    Error::UnexpectedDotInMacroDef { .. } => true,
+                    _ => false,
+                };
+                if !at_dot {
+                    while let Some(token) = self.reader.try_read_token()? {
+                        if is_dot(&token) {
+                            break;
+                        }
+                    }
                 }
-            } else if let Some(stringify) = reader.try_read::<Stringify>()? {
-                let tokens = bindings
-                    .get(stringify.name.value())
-                    .ok_or_else(|| Error::undefined_macro_var(stringify.name.value().to_owned()))?;
-                let string = tokens.iter().map(LexicalToken::text).collect::<String>();
-                let token = StringToken::from_value(&string, tokens[0].start_position());
-                expanded.push_back(token.into());
-            } else if let Some(token) = reader.try_read_token()? {
-                if let Some(value) = token
-                    .as_variable_token()
-                    .and_then(|v| bindings.get(v.value()))
+                self.can_directive_start = true;
+                Err(e)
+            }
+        }
+    }
+    fn expand_directive_args(&mut self, args: &[LexicalToken]) -> Result<Vec<LexicalToken>> {
+        let tokens = self.expander().expand_in(&self.arena, args);
+        self.finish_expansion();
+        tokens
+    }
+    fn finish_expansion(&mut self) {
+        self.arena.reset();
+        for call in self.placeholder_calls.get_mut().drain(..) {
+            self.diagnostics.push(Diagnostic::new(
+                Severity::Warning,
+                "EPP1005",
+                format!(
+                    "undefined macro ?{} is replaced with a placeholder",
+                    call.name.value()
+                ),
+                call.start_position(),
+                call.end_position(),
+            ));
+        }
+        for call in self.observed_calls.get_mut().drain(..) {
+            self.macro_xref.record_call(call);
+        }
+    }
+    fn expander(&self) -> MacroExpander<'_> {
+        let mut expander = self.snapshot_expander();
+        expander.set_expanded_macros(&self.expanded_macros);
+        expander.set_placeholder_calls(&self.placeholder_calls);
+        if self.record_origins {
+            expander.set_trace(&self.expansion_trace);
+        }
+        if self.record_macro_xref {
+            expander.set_observed_calls(&self.observed_calls);
+        }
+        expander
+    }
+    fn snapshot_expander(&self) -> MacroExpander<'_> {
+        let mut expander = MacroExpander::new(&self.env);
+        expander.set_max_expansion_depth(self.max_expansion_depth);
+        expander.set_features(&self.features);
+        expander.set_available_features(&self.available_features);
+        if let Some(ref placeholder) = self.undefined_macro_placeholder {
+            expander.set_undefined_macro_placeholder(placeholder);
+        }
+        if let Some((ref name, arity)) = self.function.current {
+            expander.set_function(name, arity);
+        }
+        expander
+    }
+    fn check_cross_file_conditional(&mut self, open: Position, close: Position) {
+        if self.warn_cross_file_conditionals && open.filepath() != close.filepath() {
+            self.diagnostics.push(Diagnostic::new(
+                Severity::Warning,
+                "EPP1004",
+                format!(
+                    "conditional directive opened at {} is closed in a different file",
+                    open
+                ),
+                close.clone(),
+                close.clone(),
+            ));
+            self.cross_file_conditionals
+                .push(CrossFileConditional { open, close });
+        }
+    }
+    fn collect_defined_tests(&mut self, condition: &[LexicalToken]) {
+        for tokens in condition.windows(4) {
+            let name = match tokens {
+                [LexicalToken::Atom(f), open, name, close]
+                    if f.value() == "defined"
+                        && open.as_symbol_token().map(|t| t.value()) == Some(Symbol::OpenParen)
+                        && close.as_symbol_token().map(|t| t.value())
+                            == Some(Symbol::CloseParen) =>
                 {
-                    let nested = self.expand_replacement(HashMap::new(), value)?;
-                    expanded.extend(nested);
-                } else {
-                    expanded.push_back(token);
+                    match name {
+                        LexicalToken::Atom(t) => MacroName::Atom(t.clone()),
+                        LexicalToken::Variable(t) => MacroName::Variable(t.clone()),
+                        _ => continue,
+                    }
                 }
-            } else {
-                break;
+                _ => continue,
+            };
+            if !self.streaming {
+                self.defined_test_refs.insert(name.start_position(), name);
             }
         }
-        Ok(expanded)
     }
     fn try_read_directive(&mut self) -> Result<Option<Directive>> {
-        let directive: Directive = if let Some(directive) = self.reader.try_read()? {
+        let directive: Directive = if let Some(directive) = self.read_directive()? {
             directive
         } else {
             return Ok(None);
         };
+        if let Some(observer) = self.observer.as_mut() {
+            observer.on_directive(&directive);
+        }
 
         let ignore = self.ignore();
         match directive {
-            Directive::Include(ref d) if !ignore => {
-                let (path, text) = d.include()?;
-                self.reader.add_included_text(path, text);
+            Directive::Define(ref d) => {
+                self.defined_macro_names.insert(d.name.value().to_string());
+            }
+            Directive::Ifdef(ref d) if !self.streaming => {
+                self.conditional_macro_refs
+                    .insert(d.start_position(), d.name.clone());
             }
-            Directive::IncludeLib(ref d) if !ignore => {
-                let (path, text) = d.include_lib(&self.code_paths)?;
-                self.reader.add_included_text(path, text);
+            Directive::Ifndef(ref d) if !self.streaming => {
+                self.conditional_macro_refs
+                    .insert(d.start_position(), d.name.clone());
+            }
+            Directive::If(ref d) => self.collect_defined_tests(&d.condition),
+            Directive::Elif(ref d) => self.collect_defined_tests(&d.condition),
+            _ => {}
+        }
+        match directive {
+            Directive::Include(ref d) if !ignore && self.execute_includes => {
+                let mut d = d.clone();
+                d.path = self.expand_directive_args(&d.path)?;
+                let current = d.start_position();
+                let mut include_dirs = self.include_paths.clone();
+                include_dirs.extend(
+                    self.workspace
+                        .include_dirs(current.filepath().map(AsRef::as_ref)),
+                );
+                let path = d.target_path_with(&mut self.env_vars)?;
+                self.resolve_include(
+                    IncludeKind::Include,
+                    path,
+                    include_dirs,
+                    Directive::Include(d),
+                )?;
+            }
+            Directive::IncludeLib(ref d) if !ignore && self.execute_includes => {
+                let mut d = d.clone();
+                d.path = self.expand_directive_args(&d.path)?;
+                let current = d.start_position();
+                let mut code_paths = self.code_paths.iter().cloned().collect::<Vec<_>>();
+                code_paths.extend(
+                    self.workspace
+                        .code_paths(current.filepath().map(AsRef::as_ref)),
+                );
+                let path = d.target_path_with(&mut self.env_vars)?;
+                self.resolve_include(
+                    IncludeKind::IncludeLib,
+                    path,
+                    code_paths,
+                    Directive::IncludeLib(d),
+                )?;
             }
             Directive::Define(ref d) if !ignore => {
-                self.macros
-                    .insert(d.name.value().to_string(), MacroDef::Static(d.clone()));
+                if RESERVED_MACROS.contains(&d.name.value()) {
+                    return Err(Error::predefined_macro_directive(d.name.value(), d));
+                }
+                if let Some(scope) = self.include_scopes.last_mut() {
+                    scope
+                        .defined
+                        .push((d.name.value().to_string(), d.start_position()));
+                }
+                let arity = d.variables.as_ref().map(MacroVariables::len);
+                if let Some(old) = self.env.get(d.name.value(), arity) {
+                    let redefinition = MacroRedefinition {
+                        name: d.name.value().to_owned(),
+                        old_position: match *old {
+                            MacroDef::Static(ref old) => Some(old.start_position()),
+                            _ => None,
+                        },
+                        new_position: d.start_position(),
+                    };
+                    match self.redefinition_policy {
+                        RedefinitionPolicy::Error => {
+                            return Err(Error::macro_redefined(redefinition, d.end_position()));
+                        }
+                        RedefinitionPolicy::Warn => {
+                            self.diagnostics.push(Diagnostic::new(
+                                Severity::Warning,
+                                "EPP1002",
+                                format!("redefining macro {:?}", redefinition.name),
+                                d.start_position(),
+                                d.end_position(),
+                            ));
+                            self.macro_redefinitions.push(redefinition);
+                        }
+                        RedefinitionPolicy::AllowOverride => {}
+                    }
+                }
+                if self.record_macro_xref {
+                    self.macro_xref
+                        .define(d.name.value(), arity, d.start_position());
+                }
+                self.env.define(d.clone());
+            }
+            Directive::Feature(ref d) if !ignore => {
+                self.features.insert(d.name.value().to_owned(), d.enable());
             }
             Directive::Undef(ref d) if !ignore => {
-                self.macros.remove(d.name.value());
+                if RESERVED_MACROS.contains(&d.name.value()) {
+                    return Err(Error::predefined_macro_directive(d.name.value(), d));
+                }
+                self.env.undef(d.name.value());
+            }
+            Directive::Error(ref d) if !ignore && self.fail_on_error_directive => {
+                return Err(Error::error_directive(d.message.value(), d));
+            }
+            Directive::Error(ref d) if !ignore => {
+                self.diagnostics.push(Diagnostic::new(
+                    Severity::Error,
+                    "EPP0017",
+                    d.message.value().to_owned(),
+                    d.start_position(),
+                    d.end_position(),
+                ));
+            }
+            Directive::Warning(ref d) if !ignore => {
+                self.diagnostics.push(Diagnostic::new(
+                    Severity::Warning,
+                    "EPP1001",
+                    d.message.value().to_owned(),
+                    d.start_position(),
+                    d.end_position(),
+                ));
+                self.warnings.push(WarningDirective {
+                    message: d.message.value().to_owned(),
+                    position: d.start_position(),
+                });
             }
             Directive::Ifdef(ref d) => {
-                let entered = self.macros.contains_key(d.name.value());
-                self.branches.push(Branch::new(entered));
+                let entered = self.env.is_defined(d.name.value());
+                self.branches.push(Branch::new(entered, d.start_position()));
             }
             Directive::Ifndef(ref d) => {
-                let entered = !self.macros.contains_key(d.name.value());
-                self.branches.push(Branch::new(entered));
+                let entered = !self.env.is_defined(d.name.value());
+                self.branches.push(Branch::new(entered, d.start_position()));
+            }
+            Directive::If(ref d) => {
+                let entered = !ignore && self.evaluate_condition(&d.condition)?;
+                self.branches.push(Branch::new(entered, d.start_position()));
+            }
+            Directive::Elif(ref d) => {
+                let outer_ignored = self.branches.len() > 1
+                    && self.branches[..self.branches.len() - 1]
+                        .iter()
+                        .any(|b| !b.entered);
+                let b = self
+                    .branches
+                    .last()
+                    .ok_or_else(|| Error::missing_if_directive(directive.clone()))?;
+                if !b.then_branch {
+                    return Err(Error::missing_if_directive(directive));
+                }
+                let entered =
+                    !b.taken && !outer_ignored && self.evaluate_condition(&d.condition)?;
+                let b = self.branches.last_mut().expect("unreachable");
+                b.entered = entered;
+                b.taken |= entered;
             }
             Directive::Else(_) => {
                 let b = self
@@ -234,10 +1097,31 @@ where
                 if !b.switch_to_else_branch() {
                     return Err(Error::missing_if_directive(directive));
                 }
+                let open = b.position.clone();
+                self.check_cross_file_conditional(open, directive.start_position());
             }
             Directive::Endif(_) => {
-                if self.branches.pop().is_none() {
-                    return Err(Error::missing_if_directive(directive));
+                let b = self
+                    .branches
+                    .pop()
+                    .ok_or_else(|| Error::missing_if_directive(directive.clone()))?;
+                self.check_cross_file_conditional(b.position, directive.start_position());
+            }
+            _ => {}
+        }
+        match (ignore, self.ignore()) {
+            (false, true) => {
+                self.skipping_since = Some((directive.start_position(), directive.end_position()));
+            }
+            (true, false) => {
+                if let Some((directive_position, start)) = self.skipping_since.take() {
+                    if !self.streaming {
+                        self.skipped_regions.push(SkippedRegion {
+                            directive: directive_position,
+                            start,
+                            end: directive.start_position(),
+                        });
+                    }
                 }
             }
             _ => {}
@@ -246,6 +1130,26 @@ where
     }
 }
 impl<T> Preprocessor<T> {
+    /// Returns a reference to the include directory list which
+    /// will be used by this preprocessor for handling `include` directive.
+    ///
+    /// If an included file is not found by the path as it is
+    /// (i.e., relative to the current directory),
+    /// these directories are searched in order as `erlc -I` does.
+    ///
+    /// Environment variables in the paths of `include` and `include_lib` directives
+    /// (e.g., `$HOME` and `${ERL_TOP}`) are looked up only once per preprocessor,
+    /// so the substitution results do not change even if the environment does.
+    pub fn include_paths(&self) -> &[PathBuf] {
+        &self.include_paths
+    }
+
+    /// Returns a mutable reference to the include directory list which
+    /// will be used by this preprocessor for handling `include` directive.
+    pub fn include_paths_mut(&mut self) -> &mut Vec<PathBuf> {
+        &mut self.include_paths
+    }
+
     /// Returns a reference to the code path list which
     /// will be used by this preprocessor for handling `include_lib` directive.
     pub fn code_paths(&self) -> &VecDeque<PathBuf> {
@@ -258,6 +1162,557 @@ impl<T> Preprocessor<T> {
         &mut self.code_paths
     }
 
+    /// Appends the directories listed in the `ERL_LIBS` environment variable to the code path list.
+    ///
+    /// The variable is split by the platform path separator (i.e., `:` on Unix and `;` on Windows).
+    /// Nothing is appended if the variable is not set.
+    ///
+    /// This is available only if the `fs` feature is enabled.
+    #[cfg(feature = "fs")]
+    pub fn add_erl_libs_to_code_paths(&mut self) {
+        if let Some(libs) = std::env::var_os("ERL_LIBS") {
+            self.code_paths.extend(std::env::split_paths(&libs));
+        }
+    }
+
+    /// Appends the `lib` directory of the OTP installation to the code path list.
+    ///
+    /// This makes it possible to include the header files of the standard applications
+    /// (e.g., `-include_lib("kernel/include/logger.hrl").`).
+    ///
+    /// The directory is `$OTP_ROOT/lib` if the `OTP_ROOT` environment variable is set.
+    /// Otherwise, it is located from the `erl` executable found in `PATH`.
+    /// Returns `false` if no OTP installation is found.
+    ///
+    /// This is available only if the `fs` feature is enabled.
+    #[cfg(feature = "fs")]
+    pub fn add_otp_lib_to_code_paths(&mut self) -> bool {
+        if let Some(dir) = util::find_otp_lib_dir() {
+            self.code_paths.push_back(dir);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Sets the resolver of the files included by `-include` and `-include_lib` directives.
+    ///
+    /// The default resolver is `FileSystemResolver`
+    /// (or an empty `MemoryIncludes` if the `fs` feature is disabled).
+    pub fn set_include_resolver<R>(&mut self, resolver: R)
+    where
+        R: IncludeResolver + 'static,
+    {
+        self.include_resolver = Box::new(resolver);
+    }
+
+    /// Sets the provider of the texts of the files which are not read via the include resolver
+    /// (e.g., the main source file).
+    ///
+    /// The texts are used to attach the offending source lines to errors
+    /// (see [Error::source_line()](crate::Error::source_line)).
+    /// By default, no texts are provided, so the errors in such files have no source lines.
+    pub fn set_source_map<S>(&mut self, sources: S)
+    where
+        S: SourceMap + Send + 'static,
+    {
+        self.source_map = Some(BoxedSourceMap(Box::new(sources)));
+    }
+
+    /// Sets the observer of the events occurred during preprocessing.
+    pub fn set_observer<O>(&mut self, observer: O)
+    where
+        O: ExpansionObserver + 'static,
+    {
+        self.observer = Some(Box::new(observer));
+    }
+
+    /// Returns a reference to the workspace which
+    /// will be used by this preprocessor for handling `include` and `include_lib` directives.
+    ///
+    /// The include directories and the code paths of the workspace are searched
+    /// after `include_paths()` and `code_paths()` respectively.
+    pub fn workspace(&self) -> &Workspace {
+        &self.workspace
+    }
+
+    /// Returns a mutable reference to the workspace which
+    /// will be used by this preprocessor for handling `include` and `include_lib` directives.
+    pub fn workspace_mut(&mut self) -> &mut Workspace {
+        &mut self.workspace
+    }
+
+    /// Returns the macro isolation policy applied to included files.
+    pub fn include_isolation(&self) -> IncludeIsolation {
+        self.include_isolation
+    }
+
+    /// Sets the macro isolation policy applied to included files.
+    ///
+    /// The default value is `IncludeIsolation::Disabled`.
+    pub fn set_include_isolation(&mut self, isolation: IncludeIsolation) {
+        self.include_isolation = isolation;
+    }
+
+    /// Returns the macros which were defined in isolated included files
+    /// and would have leaked into the including files if the isolation were disabled.
+    pub fn leaked_macros(&self) -> &[LeakedMacro] {
+        &self.leaked_macros
+    }
+
+    /// Returns the policy applied when a macro is redefined.
+    pub fn redefinition_policy(&self) -> RedefinitionPolicy {
+        self.redefinition_policy
+    }
+
+    /// Sets the policy applied when a macro is redefined
+    /// (i.e., `-define` of a macro which has the same name and arity as an existing one).
+    ///
+    /// The default value is `RedefinitionPolicy::AllowOverride`.
+    pub fn set_redefinition_policy(&mut self, policy: RedefinitionPolicy) {
+        self.redefinition_policy = policy;
+    }
+
+    /// Returns the macro redefinitions reported under `RedefinitionPolicy::Warn`.
+    pub fn macro_redefinitions(&self) -> &[MacroRedefinition] {
+        &self.macro_redefinitions
+    }
+
+    /// Returns `true` if this preprocessor fails on `-error` directives, otherwise `false`.
+    pub fn fail_on_error_directive(&self) -> bool {
+        self.fail_on_error_directive
+    }
+
+    /// Sets whether this preprocessor fails on `-error` directives.
+    ///
+    /// If `true`, reaching an active `-error("...")` directive results in `Error::ErrorDirective`
+    /// as epp does. Otherwise the directive is just recorded and the preprocessing continues.
+    ///
+    /// The default value is `false`.
+    pub fn set_fail_on_error_directive(&mut self, fail: bool) {
+        self.fail_on_error_directive = fail;
+    }
+
+    /// Returns the files included by `-include` and `-include_lib` directives so far.
+    ///
+    /// This list is transitive (i.e., it also contains the files included by included files)
+    /// and ordered by the time of inclusion.
+    /// A file included multiple times appears multiple times.
+    pub fn included_files(&self) -> &[IncludedFile] {
+        &self.included_files
+    }
+
+    /// Returns a Makefile rule which makes `target` depend on the source file
+    /// and the files included so far (like `erlc -M`).
+    ///
+    /// Each file appears only once, in the order of the first inclusion.
+    /// Spaces and `$` in the paths are escaped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate erl_pp;
+    /// # extern crate erl_tokenize;
+    /// use erl_pp::{MemoryIncludes, Preprocessor};
+    /// use erl_tokenize::Lexer;
+    ///
+    /// # fn main() {
+    /// let mut includes = MemoryIncludes::new();
+    /// includes.insert("a.hrl", "-include(\"b.hrl\").");
+    /// includes.insert("b.hrl", "");
+    ///
+    /// let mut lexer = Lexer::new("-include(\"a.hrl\"). -include(\"b.hrl\").");
+    /// lexer.set_filepath("foo.erl");
+    /// let mut pp = Preprocessor::new(lexer);
+    /// pp.set_include_resolver(includes);
+    /// for result in &mut pp {
+    ///     result.unwrap();
+    /// }
+    /// assert_eq!(pp.makefile_deps("foo.beam"), "foo.beam: foo.erl a.hrl b.hrl\n");
+    /// # }
+    /// ```
+    pub fn makefile_deps(&self, target: &str) -> String {
+        let mut seen = HashSet::<&Path>::default();
+        let deps = self
+            .reader
+            .source_path()
+            .into_iter()
+            .chain(self.included_files.iter().map(|f| f.path.as_path()))
+            .filter(|path| seen.insert(*path))
+            .map(|path| escape_make_path(&path.to_string_lossy()))
+            .collect::<Vec<_>>();
+        let mut rule = format!("{}:", escape_make_path(target));
+        for dep in deps {
+            rule.push(' ');
+            rule.push_str(&dep);
+        }
+        rule.push('\n');
+        rule
+    }
+
+    /// Returns a stable hash of `source` (i.e., the text of the main source file),
+    /// the contents of the files included so far and the initial macro environment.
+    ///
+    /// The hash does not depend on the platform or the Rust version,
+    /// so incremental build systems can store it to decide whether re-preprocessing is needed.
+    /// Note that the bodies of native macros and macro functions are not hashed (only their names are).
+    ///
+    /// This should be called after the preprocessing finishes.
+    pub fn content_hash(&self, source: &str) -> u64 {
+        let mut hasher = StableHasher::new();
+        hasher.write_str(source);
+
+        let mut seen = HashSet::<&Path>::default();
+        for file in &self.included_files {
+            if !seen.insert(&file.path) {
+                continue;
+            }
+            hasher.write_str(&file.path.to_string_lossy());
+            if let Some(text) = self.reader.included_text(&file.path) {
+                hasher.write_str(text);
+            } else if let Some(text) = read_source_text(&file.path) {
+                hasher.write_str(&text);
+            } else {
+                hasher.write_u64(u64::MAX);
+            }
+        }
+
+        self.initial_env
+            .as_ref()
+            .unwrap_or(&self.env)
+            .hash_into(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Returns the include graph of the files included so far in Graphviz DOT format.
+    ///
+    /// Each node is labeled by the resolved path of a file (`<input>` for the source without a path),
+    /// and each edge is labeled by the kind and the position of the include directive.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate erl_pp;
+    /// # extern crate erl_tokenize;
+    /// use erl_pp::{MemoryIncludes, Preprocessor};
+    /// use erl_tokenize::Lexer;
+    ///
+    /// # fn main() {
+    /// let mut includes = MemoryIncludes::new();
+    /// includes.insert("a.hrl", "");
+    ///
+    /// let mut pp = Preprocessor::new(Lexer::new("-include(\"a.hrl\")."));
+    /// pp.set_include_resolver(includes);
+    /// for result in &mut pp {
+    ///     result.unwrap();
+    /// }
+    /// assert_eq!(pp.include_graph_dot(),
+    ///            concat!("digraph includes {\n",
+    ///                    "    n0 [label=\"<input>\"];\n",
+    ///                    "    n1 [label=\"a.hrl\"];\n",
+    ///                    "    n0 -> n1 [label=\"include 1:1\"];\n",
+    ///                    "}\n"));
+    /// # }
+    /// ```
+    pub fn include_graph_dot(&self) -> String {
+        let mut dot = String::new();
+        graph::write_dot(&mut dot, &self.included_files).expect("never fails");
+        dot
+    }
+
+    /// Returns the messages of the active `-warning` directives processed so far.
+    pub fn warnings(&self) -> &[WarningDirective] {
+        &self.warnings
+    }
+
+    /// Returns the non-fatal issues reported so far, in the order of occurrence.
+    ///
+    /// This contains the `-warning` directives, the `-error` directives
+    /// (unless `fail_on_error_directive()` is `true`), the macro redefinitions
+    /// (if `redefinition_policy()` is `RedefinitionPolicy::Warn`), the leaked macros,
+    /// the cross-file conditionals and the errors recovered in the error recovery mode.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// Returns `true` if this preprocessor reports conditional directives
+    /// closed in a different file from the one they were opened in, otherwise `false`.
+    pub fn warn_cross_file_conditionals(&self) -> bool {
+        self.warn_cross_file_conditionals
+    }
+
+    /// Sets whether this preprocessor reports conditional directives
+    /// closed in a different file from the one they were opened in.
+    ///
+    /// Such conditionals (e.g., `-ifdef` in a header and `-endif` in the including file)
+    /// are legal but almost always a bug.
+    /// The reported ones can be retrieved via `cross_file_conditionals()`.
+    ///
+    /// The default value is `false`.
+    pub fn set_warn_cross_file_conditionals(&mut self, warn: bool) {
+        self.warn_cross_file_conditionals = warn;
+    }
+
+    /// Returns the conditional directives which were closed in a different file
+    /// from the one they were opened in.
+    pub fn cross_file_conditionals(&self) -> &[CrossFileConditional] {
+        &self.cross_file_conditionals
+    }
+
+    /// Returns the atom to which undefined macros are expanded.
+    pub fn undefined_macro_placeholder(&self) -> Option<&str> {
+        self.undefined_macro_placeholder.as_deref()
+    }
+
+    /// Sets the atom to which undefined macros are expanded.
+    ///
+    /// This is useful for preprocessing partially available codebases (e.g., missing headers).
+    /// Each replaced macro call is reported via `diagnostics()` (with the code `EPP1005`).
+    /// Note that the arguments of an undefined macro call are left as they are
+    /// (e.g., `?FOO(1)` is expanded to `placeholder(1)`).
+    ///
+    /// The default value is `None` (i.e., expanding an undefined macro results in an error).
+    pub fn set_undefined_macro_placeholder(&mut self, placeholder: Option<String>) {
+        self.undefined_macro_placeholder = placeholder;
+    }
+
+    /// Returns the maximum depth of nested macro expansions.
+    pub fn max_expansion_depth(&self) -> usize {
+        self.max_expansion_depth
+    }
+
+    /// Sets the maximum depth of nested macro expansions.
+    ///
+    /// If a macro expansion exceeds this limit, `Error::MacroExpansionTooDeep` is returned.
+    /// Note that recursive (self-referential) expansions are always rejected
+    /// regardless of this limit.
+    ///
+    /// The default value is `256`.
+    pub fn set_max_expansion_depth(&mut self, depth: usize) {
+        self.max_expansion_depth = depth;
+    }
+
+    /// Returns `true` if this preprocessor emits `-file` attributes at include boundaries,
+    /// otherwise `false`.
+    pub fn emit_file_attributes(&self) -> bool {
+        self.emit_file_attributes
+    }
+
+    /// Sets whether this preprocessor emits `-file` attributes at include boundaries.
+    ///
+    /// If `true`, `-file("PATH", 1).` is inserted into the output when entering an included file,
+    /// and `-file("PATH", LINE).` (where `LINE` is the line of the include directive)
+    /// is inserted when returning to the including file, as epp does.
+    /// The latter is omitted if the path of the including file is unknown.
+    ///
+    /// The default value is `false`.
+    pub fn set_emit_file_attributes(&mut self, emit: bool) {
+        self.emit_file_attributes = emit;
+    }
+
+    /// Returns `true` if this preprocessor re-emits the tokens of the directives, otherwise `false`.
+    pub fn emit_directives(&self) -> bool {
+        self.emit_directives
+    }
+
+    /// Sets whether this preprocessor re-emits the tokens of the directives (lossless mode).
+    ///
+    /// If `true`, the directives are still processed as usual,
+    /// but their original tokens are also emitted in the resulting token stream.
+    /// Use `in_directive_region()` to distinguish such tokens from the others.
+    ///
+    /// The default value is `false`.
+    pub fn set_emit_directives(&mut self, emit: bool) {
+        self.emit_directives = emit;
+    }
+
+    /// Returns `true` if this preprocessor executes `-include` and `-include_lib` directives,
+    /// otherwise `false`.
+    pub fn execute_includes(&self) -> bool {
+        self.execute_includes
+    }
+
+    /// Sets whether this preprocessor executes `-include` and `-include_lib` directives.
+    ///
+    /// If `false`, these directives are recorded (and re-emitted if `emit_directives()` is `true`)
+    /// but the target files are not read.
+    /// This is useful for formatters and rewriters combined with `set_emit_directives(true)`.
+    /// Note that the macros defined in the target files are unavailable in this mode.
+    ///
+    /// The default value is `true`.
+    pub fn set_execute_includes(&mut self, execute: bool) {
+        self.execute_includes = execute;
+    }
+
+    /// Returns `true` if this preprocessor expands macro calls, otherwise `false`.
+    pub fn expand_macros(&self) -> bool {
+        self.expand_macros
+    }
+
+    /// Sets whether this preprocessor expands macro calls.
+    ///
+    /// If `false` (analysis-only mode), macro calls are still recorded in `macro_calls()`
+    /// but their original tokens (e.g., `?FOO(bar)`) are emitted as they are.
+    /// Macros used in directives (e.g., `-if(?FOO > 1).`) are expanded regardless of this flag.
+    ///
+    /// The default value is `true`.
+    pub fn set_expand_macros(&mut self, expand: bool) {
+        self.expand_macros = expand;
+    }
+
+    /// Returns `true` if the error recovery mode is enabled, otherwise `false`.
+    pub fn error_recovery(&self) -> bool {
+        self.error_recovery
+    }
+
+    /// Enables or disables the error recovery mode.
+    ///
+    /// If enabled, recoverable errors (e.g., undefined macros, malformed directives and
+    /// missing include files) are collected into `recovered_errors()`
+    /// instead of being returned from the iterator, and preprocessing continues on a best-effort basis:
+    ///
+    /// - Macro calls which cannot be expanded are emitted as they are
+    /// - Malformed directives are skipped until the next `.`
+    /// - A directive truncated by the end of the input is reported, and the preprocessing stops
+    /// - Invalid conditions of `-if` and `-elif` are regarded as `false`
+    ///
+    /// This is useful for editors and language servers.
+    ///
+    /// The default value is `false`.
+    pub fn set_error_recovery(&mut self, enable: bool) {
+        self.error_recovery = enable;
+    }
+
+    /// Returns the errors collected in the error recovery mode so far.
+    pub fn recovered_errors(&self) -> &[Error] {
+        &self.recovered_errors
+    }
+
+    /// Returns `true` if this preprocessor records the encountered directives and macro calls,
+    /// otherwise `false`.
+    pub fn record_history(&self) -> bool {
+        self.record_history
+    }
+
+    /// Sets whether this preprocessor records the encountered directives and macro calls.
+    ///
+    /// If `false`, `directives()` and `macro_calls()` are always empty.
+    /// This saves memory when preprocessing large files in a streaming manner.
+    ///
+    /// The default value is `true`.
+    pub fn set_record_history(&mut self, record: bool) {
+        self.record_history = record;
+    }
+
+    /// Returns `true` if the streaming profile is enabled, otherwise `false`.
+    pub fn streaming(&self) -> bool {
+        self.streaming
+    }
+
+    /// Enables or disables the streaming profile.
+    ///
+    /// If enabled, this preprocessor retains nothing which grows with the size of the source,
+    /// so the memory usage per form is bounded (except for the macro definitions
+    /// and the diagnostics, which are needed for or produced by the preprocessing itself):
+    ///
+    /// - `set_record_history(false)`, `set_record_origins(false)` and `set_record_macro_xref(false)` are applied
+    ///   (enabling them again afterwards makes this preprocessor record them)
+    /// - `skipped_regions()`, `skipped_macro_refs()`, `conditional_flags()` and the results derived from them
+    ///   (e.g., `macros_only_in_skipped_branches()`) are always empty
+    /// - The texts and the tokens of the included files are dropped after reading them
+    ///   (i.e., a file included multiple times is lexed every time)
+    ///
+    /// This is useful for very large (e.g., generated) modules.
+    ///
+    /// The default value is `false`.
+    pub fn set_streaming(&mut self, enable: bool) {
+        self.streaming = enable;
+        if enable {
+            self.record_history = false;
+            self.record_origins = false;
+            self.record_macro_xref = false;
+        }
+        self.reader.set_retain_lexed_files(!enable);
+    }
+
+    /// Returns `true` if the last token returned by this preprocessor is
+    /// a part of a re-emitted directive, otherwise `false`.
+    pub fn in_directive_region(&self) -> bool {
+        self.in_directive_region
+    }
+
+    /// Returns `true` if this preprocessor records the origins of the returned tokens,
+    /// otherwise `false`.
+    pub fn record_origins(&self) -> bool {
+        self.record_origins
+    }
+
+    /// Sets whether this preprocessor records the origins of the returned tokens.
+    ///
+    /// The recorded origins can be retrieved via `origins()`.
+    ///
+    /// The default value is `false`.
+    pub fn set_record_origins(&mut self, record: bool) {
+        self.record_origins = record;
+    }
+
+    /// Returns `true` if this preprocessor records the cross-reference of the macros,
+    /// otherwise `false`.
+    pub fn record_macro_xref(&self) -> bool {
+        self.record_macro_xref
+    }
+
+    /// Sets whether this preprocessor records the cross-reference from the macro definitions
+    /// to their call sites (including the calls in the bodies of other macros).
+    ///
+    /// The recorded cross-reference can be retrieved via `macro_xref()`.
+    ///
+    /// The default value is `false`.
+    pub fn set_record_macro_xref(&mut self, record: bool) {
+        self.record_macro_xref = record;
+    }
+
+    /// Returns the cross-reference of the macros defined or called so far.
+    ///
+    /// This is empty unless `record_macro_xref()` is `true`.
+    pub fn macro_xref(&self) -> &MacroXref {
+        &self.macro_xref
+    }
+
+    /// Returns the origins of the tokens returned so far.
+    ///
+    /// This is empty unless `record_origins()` is `true`.
+    pub fn origins(&self) -> &OriginMap {
+        &self.origins
+    }
+
+    /// Returns `true` if the positions of the tokens produced by macro expansions
+    /// are remapped to the macro calls, otherwise `false`.
+    pub fn remap_expanded_positions(&self) -> bool {
+        self.remap_expanded_positions
+    }
+
+    /// Sets whether the tokens produced by macro expansions are placed at the start positions
+    /// of the (outermost) macro calls instead of the macro definitions or arguments.
+    ///
+    /// This makes the error messages of downstream parsers point to the call sites.
+    /// Note that the texts of the remapped tokens are normalized (e.g., `16#FF` becomes `255`).
+    /// The original positions are available via `origins()` if `record_origins()` is `true`.
+    ///
+    /// The default value is `false`.
+    pub fn set_remap_expanded_positions(&mut self, remap: bool) {
+        self.remap_expanded_positions = remap;
+    }
+
+    /// Returns `true` if the last token returned by this preprocessor is the result of a macro expansion
+    /// rather than read from the source at its position, otherwise `false`.
+    ///
+    /// Tokens passed through as they are (e.g., `-feature` attributes, unexpanded macro calls
+    /// and `-file` attributes) are not regarded as expanded.
+    pub fn in_expansion_region(&self) -> bool {
+        self.in_expansion_region
+    }
+
     /// Returns a reference to the map containing the macro directives
     /// encountered by this preprocessor so far.
     ///
@@ -266,6 +1721,30 @@ impl<T> Preprocessor<T> {
         &self.directives
     }
 
+    /// Returns a reference to the map containing the features
+    /// enabled (`true`) or disabled (`false`) by `-feature` directives so far.
+    ///
+    /// Note that `-feature` directives are also kept in the resulting tokens
+    /// because the compiler needs them.
+    pub fn features(&self) -> &BTreeMap<String, bool> {
+        &self.features
+    }
+
+    /// Returns a reference to the set of the features available in the target compiler.
+    ///
+    /// This set is used to expand `?FEATURE_AVAILABLE(Feature)`.
+    /// Note that `?FEATURE_ENABLED(Feature)` is expanded according to `features()`.
+    pub fn available_features(&self) -> &BTreeSet<String> {
+        &self.available_features
+    }
+
+    /// Returns a mutable reference to the set of the features available in the target compiler.
+    ///
+    /// The set is empty by default.
+    pub fn available_features_mut(&mut self) -> &mut BTreeSet<String> {
+        &mut self.available_features
+    }
+
     /// Returns a reference to the map containing the macro calls
     /// encountered by this preprocessor so far.
     ///
@@ -277,14 +1756,179 @@ impl<T> Preprocessor<T> {
         &self.macro_calls
     }
 
+    /// Returns the regions skipped by the conditional directives so far.
+    ///
+    /// This is useful for greying out inactive code in editors.
+    /// Note that a region is reported only after the directive which ends it has been read.
+    pub fn skipped_regions(&self) -> &[SkippedRegion] {
+        &self.skipped_regions
+    }
+
+    /// Returns a reference to the map containing the macro references
+    /// found in skipped conditional branches so far.
+    ///
+    /// The keys of this map are starting positions of the corresponding references.
+    pub fn skipped_macro_refs(&self) -> &BTreeMap<Position, MacroName> {
+        &self.skipped_macro_refs
+    }
+
+    /// Returns the names of the macros which are referenced only in skipped conditional branches.
+    ///
+    /// These are the macros which would be needed if the skipped code were enabled
+    /// (e.g., by a different set of `-D` options).
+    pub fn macros_only_in_skipped_branches(&self) -> BTreeSet<&str> {
+        let expanded = self.expanded_macros.borrow();
+        self.skipped_macro_refs
+            .values()
+            .map(MacroName::value)
+            .filter(|name| !expanded.contains(*name))
+            .collect()
+    }
+
+    /// Returns the macro names tested by `-ifdef`, `-ifndef` and `defined(NAME)` in `-if`/`-elif` conditions
+    /// so far (including the ones in skipped conditional branches).
+    ///
+    /// These are the names of the `-D` options which can affect the output.
+    pub fn conditional_flags(&self) -> BTreeSet<&str> {
+        self.conditional_macro_refs
+            .values()
+            .chain(self.defined_test_refs.values())
+            .map(MacroName::value)
+            .collect()
+    }
+
+    /// Returns the macro names tested by `-ifdef`/`-ifndef` directives which are never defined.
+    ///
+    /// A name is regarded as defined if it is a predefined macro,
+    /// a macro given before preprocessing (e.g., via `-D` options),
+    /// a macro defined by a `-define` directive anywhere in the unit (including skipped branches),
+    /// or a name for which `is_known` returns `true` (e.g., a project wide macro database).
+    ///
+    /// Such names are frequently typos (e.g., `-ifdef(DEUBG).`) that silently disable code.
+    /// The results are ordered by the positions of the directives.
+    pub fn never_defined_conditional_macros<F>(&self, is_known: F) -> Vec<&MacroName>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let initial_env = self.initial_env.as_ref().unwrap_or(&self.env);
+        self.conditional_macro_refs
+            .values()
+            .filter(|name| {
+                let name = name.value();
+                !(initial_env.is_defined(name)
+                    || self.env.is_defined(name)
+                    || self.defined_macro_names.contains(name)
+                    || is_known(name))
+            })
+            .collect()
+    }
+
+    /// Captures the current macro environment, features, include directories and code paths.
+    ///
+    /// The captured state can be restored via `restore_env()`.
+    pub fn snapshot_env(&self) -> EnvSnapshot {
+        EnvSnapshot {
+            env: self.env.clone(),
+            features: self.features.clone(),
+            include_paths: self.include_paths.clone(),
+            code_paths: self.code_paths.clone(),
+        }
+    }
+
+    /// Restores the state captured by `snapshot_env()`.
+    ///
+    /// This is useful for preprocessing many files which share a prelude (e.g., a common header):
+    /// preprocess the prelude once, capture the state, and then call `reset()` followed by
+    /// `restore_env()` for each file.
+    pub fn restore_env(&mut self, snapshot: &EnvSnapshot) {
+        self.env = snapshot.env.clone();
+        self.initial_env = None;
+        self.features = snapshot.features.clone();
+        self.include_paths = snapshot.include_paths.clone();
+        self.code_paths = snapshot.code_paths.clone();
+    }
+
     /// Returns a reference to the map containing the current macro definitions.
-    pub fn macros(&self) -> &HashMap<String, MacroDef> {
-        &self.macros
+    pub fn macros(&self) -> &MacroMap {
+        self.env.macros()
+    }
+
+    /// Renders the current macro definitions as the text of an `.hrl` file
+    /// (i.e., a sequence of `-define` directives ordered by the names and the arities).
+    ///
+    /// This is useful for debugging complicated include chains
+    /// and for generating "effective configuration" headers.
+    /// Native macros cannot be rendered, so they are written as comments.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate erl_pp;
+    /// # extern crate erl_tokenize;
+    /// use erl_pp::Preprocessor;
+    /// use erl_tokenize::Lexer;
+    ///
+    /// # fn main() {
+    /// let src = "-define(FOO(X, Y), {X, [Y | bar]}). -define(BAR, ?FOO(1, 2) + 3).";
+    /// let mut pp = Preprocessor::new(Lexer::new(src));
+    /// pp.define_from_str("DEBUG").unwrap();
+    /// for result in &mut pp {
+    ///     result.unwrap();
+    /// }
+    /// assert_eq!(pp.dump_macros_as_hrl(),
+    ///            concat!("-define(BAR, ?FOO(1, 2) + 3).\n",
+    ///                    "-define(DEBUG, true).\n",
+    ///                    "-define(FOO(X, Y), {X, [Y | bar]}).\n"));
+    /// # }
+    /// ```
+    pub fn dump_macros_as_hrl(&self) -> String {
+        self.env.macros().to_hrl()
     }
 
     /// Returns a mutable reference to the map containing the current macro definitions.
-    pub fn macros_mut(&mut self) -> &mut HashMap<String, MacroDef> {
-        &mut self.macros
+    pub fn macros_mut(&mut self) -> &mut MacroMap {
+        self.env.macros_mut()
+    }
+
+    /// Defines a macro from a string in the form of `erlc -D` option (i.e., `NAME` or `NAME=VALUE`).
+    ///
+    /// See [MacroEnv::define_from_str] for details.
+    pub fn define_from_str(&mut self, definition: &str) -> Result<()> {
+        self.env.define_from_str(definition)
+    }
+
+    /// Registers a predefined macro which is expanded by calling the given function.
+    ///
+    /// See [MacroEnv::register_macro_fn] for details.
+    pub fn register_macro_fn<F>(&mut self, name: &str, f: F)
+    where
+        F: Fn(&MacroCall) -> Result<Vec<LexicalToken>> + Send + Sync + 'static,
+    {
+        self.env.register_macro_fn(name, f);
+    }
+
+    /// Returns the value of the `?OTP_RELEASE` predefined macro.
+    pub fn otp_release(&self) -> Option<u32> {
+        self.env.otp_release()
+    }
+
+    /// Sets the value of the `?OTP_RELEASE` predefined macro (e.g., `26`).
+    ///
+    /// `?OTP_RELEASE` is undefined unless this method is called.
+    /// Callers that preprocess code containing `-if(?OTP_RELEASE >= N).`
+    /// must set it beforehand, otherwise the condition fails with [Error::UndefinedMacro].
+    pub fn set_otp_release(&mut self, release: u32) {
+        self.env.set_otp_release(release);
+    }
+
+    /// Returns a reference to the current macro environment.
+    pub fn macro_env(&self) -> &MacroEnv {
+        &self.env
+    }
+
+    /// Returns a mutable reference to the current macro environment.
+    pub fn macro_env_mut(&mut self) -> &mut MacroEnv {
+        &mut self.env
     }
 }
 impl<T> Iterator for Preprocessor<T>
@@ -293,30 +1937,419 @@ where
 {
     type Item = Result<LexicalToken>;
     fn next(&mut self) -> Option<Self::Item> {
-        match self.next_token() {
-            Err(e) => Some(Err(e)),
-            Ok(None) => None,
-            Ok(Some(token)) => Some(Ok(token)),
+        self.next_item()
+    }
+}
+
+/// Iterator over the forms of the preprocessed tokens.
+///
+/// This is created by `Preprocessor::forms()`.
+#[derive(Debug)]
+pub struct Forms<'a, T> {
+    pp: &'a mut Preprocessor<T>,
+    failed: bool,
+}
+impl<'a, T> Iterator for Forms<'a, T>
+where
+    T: Iterator<Item = erl_tokenize::Result<LexicalToken>>,
+{
+    type Item = Result<Vec<LexicalToken>>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            // Skips the rest of the erroneous form.
+            self.failed = false;
+            for token in &mut *self.pp {
+                if token.as_ref().is_ok_and(is_dot) {
+                    break;
+                }
+            }
+        }
+        let mut form = Vec::new();
+        for token in &mut *self.pp {
+            let token = match token {
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+                Ok(token) => token,
+            };
+            let end = is_dot(&token);
+            form.push(token);
+            if end {
+                break;
+            }
+        }
+        if form.is_empty() {
+            None
+        } else {
+            Some(Ok(form))
+        }
+    }
+}
+
+/// Macro isolation policy applied to included files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IncludeIsolation {
+    /// Included and including files share the same macro environment (the epp behavior).
+    Disabled,
+
+    /// Macros defined in an included file do not leak into the including file.
+    Outward,
+
+    /// In addition to `Outward`, an included file cannot see the macros defined by the including file
+    /// (the predefined macros are still visible).
+    Full,
+}
+
+/// Policy applied when a macro is redefined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedefinitionPolicy {
+    /// Redefinition is an error (the epp behavior).
+    Error,
+
+    /// The new definition overrides the old one, and the redefinition is reported.
+    Warn,
+
+    /// The new definition silently overrides the old one.
+    AllowOverride,
+}
+
+/// A redefinition of a macro.
+#[derive(Debug, Clone)]
+pub struct MacroRedefinition {
+    /// The name of the macro.
+    pub name: String,
+
+    /// The start position of the old `-define` directive.
+    ///
+    /// This is `None` if the old definition was not given by a directive (e.g., `MacroDef::Dynamic`).
+    pub old_position: Option<Position>,
+
+    /// The start position of the new `-define` directive.
+    pub new_position: Position,
+}
+
+/// A macro which was prevented from leaking out of an included file.
+#[derive(Debug, Clone)]
+pub struct LeakedMacro {
+    /// The name of the macro.
+    pub name: String,
+
+    /// The path of the included file which defines the macro.
+    pub file: PathBuf,
+
+    /// The start position of the `-define` directive.
+    pub position: Position,
+}
+
+/// A region of the source code skipped by a conditional directive.
+#[derive(Debug, Clone)]
+pub struct SkippedRegion {
+    /// The start position of the conditional directive which caused the skip
+    /// (i.e., `-if`, `-ifdef`, `-ifndef`, `-elif` or `-else`).
+    pub directive: Position,
+
+    /// The end position of the directive which caused the skip.
+    pub start: Position,
+
+    /// The start position of the directive which ended the skip.
+    pub end: Position,
+}
+
+/// A snapshot of the state of a preprocessor taken by `Preprocessor::snapshot_env()`.
+#[derive(Debug, Clone)]
+pub struct EnvSnapshot {
+    env: MacroEnv,
+    features: BTreeMap<String, bool>,
+    include_paths: Vec<PathBuf>,
+    code_paths: VecDeque<PathBuf>,
+}
+impl EnvSnapshot {
+    /// Returns a reference to the captured macro environment.
+    pub fn env(&self) -> &MacroEnv {
+        &self.env
+    }
+}
+
+fn escape_make_path(path: &str) -> String {
+    path.replace(' ', "\\ ").replace('$', "$$")
+}
+
+/// A file included by an `-include` or `-include_lib` directive.
+#[derive(Debug, Clone)]
+pub struct IncludedFile {
+    /// The resolved path of the file.
+    pub path: PathBuf,
+
+    /// The kind of the directive which included the file.
+    pub kind: IncludeKind,
+
+    /// The start position of the directive which included the file.
+    pub position: Position,
+
+    /// Whether the file starts with a byte order mark.
+    ///
+    /// The mark is not a part of the file text seen by the lexer (i.e., the token positions
+    /// are relative to the text following the mark).
+    pub bom: bool,
+}
+
+/// A warning reported by a `-warning` directive.
+#[derive(Debug, Clone)]
+pub struct WarningDirective {
+    /// The message of the directive.
+    pub message: String,
+
+    /// The start position of the directive.
+    pub position: Position,
+}
+
+/// A conditional branch which was closed in a different file from the one it was opened in.
+#[derive(Debug, Clone)]
+pub struct CrossFileConditional {
+    /// The start position of the opening directive (i.e., `-if`, `-ifdef` or `-ifndef`).
+    pub open: Position,
+
+    /// The start position of the closing directive (i.e., `-else` or `-endif`).
+    pub close: Position,
+}
+
+/// An include directive waiting for the target file to be resolved.
+#[derive(Debug)]
+pub(crate) struct PendingInclude {
+    pub kind: IncludeKind,
+    pub path: PathBuf,
+    pub search_dirs: Vec<PathBuf>,
+    pub directive: Directive,
+}
+
+#[derive(Debug)]
+struct FileReturn {
+    depth: usize,
+    line: usize,
+    position: Position,
+}
+
+#[cfg(feature = "fs")]
+fn default_include_resolver() -> Box<dyn IncludeResolver> {
+    Box::new(FileSystemResolver::new())
+}
+
+#[cfg(not(feature = "fs"))]
+fn default_include_resolver() -> Box<dyn IncludeResolver> {
+    Box::new(crate::MemoryIncludes::new())
+}
+
+/// Reads the text of a file which has not been read through the include resolver
+/// (e.g., the main source file).
+#[cfg(feature = "fs")]
+fn read_source_text(path: &Path) -> Option<String> {
+    util::read_file(path).ok()
+}
+
+#[cfg(not(feature = "fs"))]
+fn read_source_text(_path: &Path) -> Option<String> {
+    None
+}
+
+struct BoxedSourceMap(Box<dyn SourceMap + Send>);
+impl fmt::Debug for BoxedSourceMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BoxedSourceMap(..)")
+    }
+}
+
+pub(crate) fn file_attribute_tokens(
+    path: &str,
+    line: usize,
+    position: &Position,
+) -> Vec<LexicalToken> {
+    vec![
+        SymbolToken::from_value(Symbol::Hyphen, position.clone()).into(),
+        AtomToken::from_value("file", position.clone()).into(),
+        SymbolToken::from_value(Symbol::OpenParen, position.clone()).into(),
+        StringToken::from_value(path, position.clone()).into(),
+        SymbolToken::from_value(Symbol::Comma, position.clone()).into(),
+        IntegerToken::from_value(line.into(), position.clone()).into(),
+        SymbolToken::from_value(Symbol::CloseParen, position.clone()).into(),
+        SymbolToken::from_value(Symbol::Dot, position.clone()).into(),
+    ]
+}
+
+/// Returns the token which has the same value as `token` and starts at `position`.
+fn relocate_token(token: LexicalToken, position: Position) -> LexicalToken {
+    match token {
+        LexicalToken::Atom(t) => AtomToken::from_value(t.value(), position).into(),
+        LexicalToken::Char(t) => CharToken::from_value(t.value(), position).into(),
+        LexicalToken::Float(t) => FloatToken::from_value(t.value(), position).into(),
+        LexicalToken::Integer(t) => IntegerToken::from_value(t.value().clone(), position).into(),
+        LexicalToken::Keyword(t) => KeywordToken::from_value(t.value(), position).into(),
+        LexicalToken::String(t) => StringToken::from_value(t.value(), position).into(),
+        LexicalToken::Symbol(t) => SymbolToken::from_value(t.value(), position).into(),
+        LexicalToken::Variable(t) => match VariableToken::from_value(t.value(), position) {
+            Ok(v) => v.into(),
+            Err(_) => t.into(),
+        },
+    }
+}
+
+#[derive(Debug)]
+struct IncludeScope {
+    path: PathBuf,
+    depth: usize,
+    saved_macros: MacroMap,
+    defined: Vec<(String, Position)>,
+}
+
+fn is_hyphen(token: &LexicalToken) -> bool {
+    token
+        .as_symbol_token()
+        .is_some_and(|s| s.value() == Symbol::Hyphen)
+}
+
+fn is_dot(token: &LexicalToken) -> bool {
+    token
+        .as_symbol_token()
+        .is_some_and(|s| s.value() == Symbol::Dot)
+}
+
+/// Tracker of the function clause head of the current form,
+/// which is used to expand `?FUNCTION_NAME` and `?FUNCTION_ARITY`.
+#[derive(Debug, Default)]
+struct FunctionTracker {
+    state: FunctionHeadState,
+    current: Option<(String, usize)>,
+}
+impl FunctionTracker {
+    fn observe(&mut self, token: &LexicalToken) {
+        if is_dot(token) {
+            self.state = FunctionHeadState::FormStart;
+            self.current = None;
+            return;
         }
+        let symbol = token.as_symbol_token().map(|s| s.value());
+        self.state = match mem::take(&mut self.state) {
+            FunctionHeadState::FormStart => match token.as_atom_token() {
+                Some(a) => FunctionHeadState::Name(a.value().to_owned()),
+                None => FunctionHeadState::Other,
+            },
+            FunctionHeadState::Name(name) if symbol == Some(Symbol::OpenParen) => {
+                FunctionHeadState::Args {
+                    name,
+                    depth: 1,
+                    commas: 0,
+                    empty: true,
+                }
+            }
+            FunctionHeadState::Args {
+                name,
+                mut depth,
+                mut commas,
+                mut empty,
+            } => {
+                match symbol {
+                    Some(Symbol::OpenParen)
+                    | Some(Symbol::OpenSquare)
+                    | Some(Symbol::OpenBrace)
+                    | Some(Symbol::DoubleLeftAngle) => depth += 1,
+                    Some(Symbol::CloseParen)
+                    | Some(Symbol::CloseSquare)
+                    | Some(Symbol::CloseBrace)
+                    | Some(Symbol::DoubleRightAngle) => depth -= 1,
+                    Some(Symbol::Comma) if depth == 1 => commas += 1,
+                    _ => {}
+                }
+                if depth == 0 {
+                    let arity = if empty { 0 } else { commas + 1 };
+                    self.current = Some((name, arity));
+                    FunctionHeadState::Other
+                } else {
+                    empty = false;
+                    FunctionHeadState::Args {
+                        name,
+                        depth,
+                        commas,
+                        empty,
+                    }
+                }
+            }
+            _ => FunctionHeadState::Other,
+        };
     }
 }
 
+/// Tracker of the `-module(Name).` attribute, which defines `?MODULE` and `?MODULE_STRING`.
+#[derive(Debug, Default)]
+struct ModuleTracker {
+    // The number of the matched tokens of the current form (`MODULE_ATTR_LEN` or more on mismatch).
+    matched: usize,
+    name: Option<AtomToken>,
+}
+impl ModuleTracker {
+    /// Returns the module name when the end of a `-module` attribute is observed.
+    fn observe(&mut self, token: &LexicalToken) -> Option<AtomToken> {
+        const MODULE_ATTR_LEN: usize = 5; // `-`, `module`, `(`, NAME and `)`
+        if is_dot(token) {
+            let matched = mem::take(&mut self.matched);
+            let name = self.name.take();
+            return name.filter(|_| matched == MODULE_ATTR_LEN);
+        }
+        let symbol = token.as_symbol_token().map(|s| s.value());
+        let ok = match self.matched {
+            0 => symbol == Some(Symbol::Hyphen),
+            1 => token.as_atom_token().is_some_and(|a| a.value() == "module"),
+            2 => symbol == Some(Symbol::OpenParen),
+            3 => {
+                self.name = token.as_atom_token().cloned();
+                self.name.is_some()
+            }
+            4 => symbol == Some(Symbol::CloseParen),
+            _ => false,
+        };
+        self.matched = if ok {
+            self.matched + 1
+        } else {
+            MODULE_ATTR_LEN + 1
+        };
+        None
+    }
+}
+
+#[derive(Debug, Default)]
+enum FunctionHeadState {
+    #[default]
+    FormStart,
+    Name(String),
+    Args {
+        name: String,
+        depth: usize,
+        commas: usize,
+        empty: bool,
+    },
+    Other,
+}
+
 #[derive(Debug)]
 struct Branch {
     pub then_branch: bool,
     pub entered: bool,
+    pub taken: bool,
+    pub position: Position,
 }
 impl Branch {
-    pub fn new(entered: bool) -> Self {
+    pub fn new(entered: bool, position: Position) -> Self {
         Branch {
             then_branch: true,
             entered,
+            taken: entered,
+            position,
         }
     }
     pub fn switch_to_else_branch(&mut self) -> bool {
         if self.then_branch {
             self.then_branch = false;
-            self.entered = !self.entered;
+            self.entered = !self.taken;
+            self.taken = true;
             true
         } else {
             false
@@ -0,0 +1,318 @@
+#[cfg(feature = "fs")]
+use erl_tokenize::Lexer;
+use erl_tokenize::LexicalToken;
+#[cfg(feature = "fs")]
+use std::collections::VecDeque;
+use std::collections::{BTreeMap, BTreeSet};
+#[cfg(feature = "fs")]
+use std::io;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "fs")]
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "fs")]
+use crate::resolver::{FileSystemResolver, IncludeKind, IncludeResolver};
+#[cfg(feature = "fs")]
+use crate::util::{self, HashMap};
+#[cfg(feature = "rayon")]
+use crate::Options;
+use crate::{Error, IncludedFile, WarningDirective};
+#[cfg(feature = "fs")]
+use crate::{Preprocessor, Result};
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+/// A set of source files preprocessed under the same configuration.
+///
+/// The files share the include directories, the code paths and the macro definitions
+/// given via `define_from_str()`.
+/// The included files are read only once and reused across the source files.
+///
+/// # Examples
+///
+/// ```no_run
+/// # extern crate erl_pp;
+/// use erl_pp::Project;
+///
+/// # fn main() {
+/// let mut project = Project::new();
+/// project.include_paths_mut().push("include".into());
+/// project.define_from_str("TEST");
+/// project.add_file("src/foo.erl");
+/// project.add_file("src/bar.erl");
+///
+/// let report = project.preprocess();
+/// for file in &report.files {
+///     println!("{:?}: {} tokens", file.path, file.tokens.len());
+/// }
+/// for (path, e) in &report.errors {
+///     eprintln!("{:?}: {}", path, e);
+/// }
+/// # }
+/// ```
+///
+/// This is available only if the `fs` feature is enabled.
+#[cfg(feature = "fs")]
+#[derive(Debug, Default)]
+pub struct Project {
+    files: Vec<PathBuf>,
+    include_paths: Vec<PathBuf>,
+    code_paths: VecDeque<PathBuf>,
+    defines: Vec<String>,
+    include_cache: Arc<Mutex<IncludeCache>>,
+}
+#[cfg(feature = "fs")]
+impl Project {
+    /// Makes a new empty `Project` instance.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a source file to this project.
+    pub fn add_file<P: Into<PathBuf>>(&mut self, path: P) {
+        self.files.push(path.into());
+    }
+
+    /// Returns the source files of this project.
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    /// Returns a mutable reference to the include directory list shared by the source files.
+    pub fn include_paths_mut(&mut self) -> &mut Vec<PathBuf> {
+        &mut self.include_paths
+    }
+
+    /// Returns a mutable reference to the code path list shared by the source files.
+    pub fn code_paths_mut(&mut self) -> &mut VecDeque<PathBuf> {
+        &mut self.code_paths
+    }
+
+    /// Adds a macro definition in the form of `erlc -D` option (i.e., `NAME` or `NAME=VALUE`).
+    ///
+    /// The definition is validated when preprocessing each file.
+    pub fn define_from_str(&mut self, definition: &str) {
+        self.defines.push(definition.to_owned());
+    }
+
+    /// Preprocesses all the source files of this project.
+    pub fn preprocess(&self) -> ProjectReport {
+        let mut report = ProjectReport::default();
+        for path in &self.files {
+            match self.preprocess_file(path) {
+                Ok(file) => report.files.push(file),
+                Err(e) => report.errors.push((path.clone(), e)),
+            }
+        }
+        report
+    }
+
+    /// Preprocesses all the source files of this project in parallel.
+    ///
+    /// The files are distributed over the global thread pool of `rayon`,
+    /// and the results are ordered as the files were added (i.e., the same as `preprocess()`).
+    ///
+    /// This is available only if the `rayon` feature is enabled.
+    #[cfg(feature = "rayon")]
+    pub fn par_preprocess(&self) -> ProjectReport {
+        let mut report = ProjectReport::default();
+        for (path, result) in self
+            .files
+            .iter()
+            .zip(self.par_preprocess_files(&self.files))
+        {
+            match result {
+                Ok(file) => report.files.push(file),
+                Err(e) => report.errors.push((path.clone(), e)),
+            }
+        }
+        report
+    }
+
+    /// Preprocesses the given source files under the configuration of this project in parallel.
+    ///
+    /// The `i`-th result corresponds to `paths[i]`.
+    ///
+    /// This is available only if the `rayon` feature is enabled.
+    #[cfg(feature = "rayon")]
+    pub fn par_preprocess_files<P>(&self, paths: &[P]) -> Vec<Result<PreprocessedFile>>
+    where
+        P: AsRef<Path> + Sync,
+    {
+        paths
+            .par_iter()
+            .map(|path| self.preprocess_file(path))
+            .collect()
+    }
+
+    /// Preprocesses a source file under the configuration of this project.
+    ///
+    /// The file does not need to be added to this project beforehand.
+    pub fn preprocess_file<P: AsRef<Path>>(&self, path: P) -> Result<PreprocessedFile> {
+        let path = path.as_ref();
+        let text = util::read_file(path).map_err(|source| Error::SourceFileError {
+            source,
+            path: path.to_path_buf(),
+        })?;
+        let mut lexer = Lexer::new(text);
+        lexer.set_filepath(path);
+
+        let mut pp = Preprocessor::new(lexer);
+        pp.include_paths_mut()
+            .extend(self.include_paths.iter().cloned());
+        pp.code_paths_mut().extend(self.code_paths.iter().cloned());
+        for definition in &self.defines {
+            pp.define_from_str(definition)?;
+        }
+        pp.set_include_resolver(CachedResolver {
+            cache: Arc::clone(&self.include_cache),
+        });
+        let tokens = pp.by_ref().collect::<Result<Vec<_>>>()?;
+        Ok(PreprocessedFile {
+            path: path.to_path_buf(),
+            tokens,
+            included_files: pp.included_files().to_vec(),
+            warnings: pp.warnings().to_vec(),
+            conditional_flags: pp
+                .conditional_flags()
+                .into_iter()
+                .map(ToOwned::to_owned)
+                .collect(),
+        })
+    }
+}
+
+/// Preprocesses the given source files in parallel.
+///
+/// The files are preprocessed independently under the include directories, the code paths
+/// and the macro definitions of `options` (`options.filepath` is ignored),
+/// while the resolutions of `-include` and `-include_lib` directives and the contents of
+/// the included files are shared among them.
+/// The `i`-th result corresponds to `paths[i]`.
+///
+/// See also: [Project::par_preprocess()].
+///
+/// This is available only if the `rayon` feature is enabled.
+///
+/// # Examples
+///
+/// ```no_run
+/// # extern crate erl_pp;
+/// use erl_pp::{par_preprocess_files, Options};
+///
+/// # fn main() {
+/// let mut options = Options::new();
+/// options.include_paths.push("include".into());
+///
+/// for result in par_preprocess_files(&["src/foo.erl", "src/bar.erl"], &options) {
+///     match result {
+///         Ok(file) => println!("{:?}: {} tokens", file.path, file.tokens.len()),
+///         Err(e) => eprintln!("{}", e),
+///     }
+/// }
+/// # }
+/// ```
+#[cfg(feature = "rayon")]
+pub fn par_preprocess_files<P>(paths: &[P], options: &Options) -> Vec<Result<PreprocessedFile>>
+where
+    P: AsRef<Path> + Sync,
+{
+    let mut project = Project::new();
+    project
+        .include_paths_mut()
+        .extend(options.include_paths.iter().cloned());
+    project
+        .code_paths_mut()
+        .extend(options.code_paths.iter().cloned());
+    for definition in &options.defines {
+        project.define_from_str(definition);
+    }
+    project.par_preprocess_files(paths)
+}
+
+/// The result of `Project::preprocess()`.
+#[derive(Debug, Default)]
+pub struct ProjectReport {
+    /// The files preprocessed successfully.
+    pub files: Vec<PreprocessedFile>,
+
+    /// The files which failed to be preprocessed and the errors.
+    pub errors: Vec<(PathBuf, Error)>,
+}
+impl ProjectReport {
+    /// Returns the map from each preprocessed source file to the files it includes (transitively).
+    ///
+    /// This is useful for dependency tracking of build tools.
+    pub fn dependencies(&self) -> BTreeMap<&Path, BTreeSet<&Path>> {
+        self.files
+            .iter()
+            .map(|file| {
+                let deps = file.included_files.iter().map(|f| f.path.as_path());
+                (file.path.as_path(), deps.collect())
+            })
+            .collect()
+    }
+
+    /// Returns the macro names tested by conditional directives in any of the files.
+    pub fn conditional_flags(&self) -> BTreeSet<&str> {
+        self.files
+            .iter()
+            .flat_map(|file| file.conditional_flags.iter().map(String::as_str))
+            .collect()
+    }
+
+    /// Returns the warnings reported by `-warning` directives in all the files.
+    pub fn warnings(&self) -> impl Iterator<Item = (&Path, &WarningDirective)> {
+        self.files
+            .iter()
+            .flat_map(|file| file.warnings.iter().map(move |w| (file.path.as_path(), w)))
+    }
+}
+
+/// A source file preprocessed by `Project`.
+#[derive(Debug, Clone)]
+pub struct PreprocessedFile {
+    /// The path of the source file.
+    pub path: PathBuf,
+
+    /// The resulting tokens.
+    pub tokens: Vec<LexicalToken>,
+
+    /// The files included by the source file.
+    pub included_files: Vec<IncludedFile>,
+
+    /// The warnings reported by `-warning` directives.
+    pub warnings: Vec<WarningDirective>,
+
+    /// The macro names tested by conditional directives (see `Preprocessor::conditional_flags()`).
+    pub conditional_flags: BTreeSet<String>,
+}
+
+#[cfg(feature = "fs")]
+type IncludeCache = HashMap<(IncludeKind, PathBuf, Vec<PathBuf>), (PathBuf, String)>;
+
+#[cfg(feature = "fs")]
+#[derive(Debug)]
+struct CachedResolver {
+    cache: Arc<Mutex<IncludeCache>>,
+}
+#[cfg(feature = "fs")]
+impl IncludeResolver for CachedResolver {
+    fn resolve(
+        &self,
+        kind: IncludeKind,
+        path: &Path,
+        search_dirs: &[PathBuf],
+    ) -> io::Result<(PathBuf, String)> {
+        let key = (kind, path.to_path_buf(), search_dirs.to_vec());
+        if let Some(resolved) = self.cache.lock().expect("poisoned").get(&key) {
+            return Ok(resolved.clone());
+        }
+        let resolved = FileSystemResolver::new().resolve(kind, path, search_dirs)?;
+        self.cache
+            .lock()
+            .expect("poisoned")
+            .insert(key, resolved.clone());
+        Ok(resolved)
+    }
+}
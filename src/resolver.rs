@@ -0,0 +1,254 @@
+#[cfg(feature = "fs")]
+use glob::glob;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+#[cfg(feature = "fs")]
+use std::path::Component;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "fs")]
+use crate::util;
+
+/// Kind of include directives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IncludeKind {
+    /// `-include` directive.
+    Include,
+
+    /// `-include_lib` directive.
+    IncludeLib,
+}
+
+/// Resolver of the files included by `-include` and `-include_lib` directives.
+///
+/// This makes it possible to preprocess sources without touching the real filesystem
+/// (e.g., virtual filesystems, network sources or caches).
+pub trait IncludeResolver: fmt::Debug + Send {
+    /// Resolves the target file of an include directive and returns its path and text.
+    ///
+    /// `path` is the path written in the directive (macros and environment variables are
+    /// already substituted), and `search_dirs` are the include directories
+    /// (for `IncludeKind::Include`) or the code paths (for `IncludeKind::IncludeLib`)
+    /// to be searched.
+    fn resolve(
+        &self,
+        kind: IncludeKind,
+        path: &Path,
+        search_dirs: &[PathBuf],
+    ) -> io::Result<(PathBuf, String)>;
+}
+
+/// The default `IncludeResolver` which reads files from the real filesystem.
+///
+/// This is available only if the `fs` feature (enabled by default) is enabled.
+#[cfg(feature = "fs")]
+#[derive(Debug, Clone, Default)]
+pub struct FileSystemResolver;
+#[cfg(feature = "fs")]
+impl FileSystemResolver {
+    /// Makes a new `FileSystemResolver` instance.
+    pub fn new() -> Self {
+        FileSystemResolver
+    }
+
+    /// Reads a source file in the same manner as the files included via this resolver.
+    ///
+    /// The encoding is detected from the `coding` comment (e.g., `%% coding: latin-1`) in the first
+    /// two lines, and the byte order mark is removed (so the token positions are relative to the result).
+    pub fn read_source<P: AsRef<Path>>(path: P) -> io::Result<String> {
+        util::read_file(path)
+    }
+
+    fn find_include(path: &Path, include_dirs: &[PathBuf]) -> PathBuf {
+        if path.is_relative() && !path.exists() {
+            if let Some(found) = include_dirs
+                .iter()
+                .map(|dir| dir.join(path))
+                .find(|p| p.exists())
+            {
+                return found;
+            }
+        }
+        path.to_path_buf()
+    }
+
+    fn find_include_lib(path: &Path, code_paths: &[PathBuf]) -> io::Result<PathBuf> {
+        let mut components = path.components();
+        let app_name = if let Some(Component::Normal(app_name)) = components.next() {
+            app_name
+                .to_str()
+                .ok_or_else(|| invalid_input(format!("non UTF-8 path: {:?}", app_name)))?
+        } else {
+            return Ok(path.to_path_buf());
+        };
+
+        let pattern = format!("{}-*", app_name);
+        let mut app_dir = None;
+        for root in code_paths {
+            let pattern = root.join(&pattern);
+            let pattern = pattern
+                .to_str()
+                .ok_or_else(|| invalid_input(format!("non UTF-8 path: {:?}", pattern)))?;
+            let mut entries = glob(pattern).map_err(invalid_input)?;
+            if let Some(entry) = entries.next() {
+                app_dir = Some(entry.map_err(io::Error::from)?);
+                break;
+            }
+        }
+        if app_dir.is_none() {
+            // e.g., `_build/default/lib/foo` of rebar3
+            app_dir = code_paths
+                .iter()
+                .map(|root| root.join(app_name))
+                .find(|dir| dir.is_dir());
+        }
+        if app_dir.is_none() {
+            app_dir = code_paths
+                .iter()
+                .find_map(|root| util::find_app_dir(root, app_name));
+        }
+        if let Some(mut path) = app_dir {
+            for c in components {
+                path.push(c.as_os_str());
+            }
+            Ok(path)
+        } else {
+            Ok(path.to_path_buf())
+        }
+    }
+}
+#[cfg(feature = "fs")]
+impl IncludeResolver for FileSystemResolver {
+    /// Resolves the target file as epp does.
+    ///
+    /// For `-include`, if the target file is not found by the path as it is,
+    /// each of `search_dirs` is searched in order.
+    ///
+    /// For `-include_lib`, the application directory is searched in `search_dirs`
+    /// by the `{APP_NAME}-*` pattern first.
+    /// If no such directory is found, directories which are named `{APP_NAME}` or
+    /// contain `ebin/{APP_NAME}.app` or `src/{APP_NAME}.app.src` are used instead.
+    fn resolve(
+        &self,
+        kind: IncludeKind,
+        path: &Path,
+        search_dirs: &[PathBuf],
+    ) -> io::Result<(PathBuf, String)> {
+        let path = match kind {
+            IncludeKind::Include => Self::find_include(path, search_dirs),
+            IncludeKind::IncludeLib => Self::find_include_lib(path, search_dirs)?,
+        };
+        // The byte order mark is removed (and recorded) by the preprocessor.
+        let text = util::read_file_with_bom(&path)?;
+        Ok((path, text))
+    }
+}
+
+/// An `IncludeResolver` which serves files from an in-memory map of paths to contents.
+///
+/// This is useful for unit tests and sandboxed environments (e.g., web playgrounds).
+/// If the `fs` feature is disabled, an empty `MemoryIncludes` is the default resolver of `Preprocessor`.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate erl_pp;
+/// # extern crate erl_tokenize;
+/// use erl_pp::{MemoryIncludes, Preprocessor};
+/// use erl_tokenize::Lexer;
+///
+/// # fn main() {
+/// let mut includes = MemoryIncludes::new();
+/// includes.insert("include/foo.hrl", "-define(FOO, foo).");
+///
+/// let src = r#"-include("include/foo.hrl"). ?FOO."#;
+/// let mut pp = Preprocessor::new(Lexer::new(src));
+/// pp.set_include_resolver(includes);
+/// let tokens = pp.collect::<Result<Vec<_>, _>>().unwrap();
+///
+/// assert_eq!(tokens.iter().map(|t| t.text()).collect::<Vec<_>>(), ["foo", "."]);
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MemoryIncludes {
+    files: BTreeMap<PathBuf, String>,
+}
+impl MemoryIncludes {
+    /// Makes a new empty `MemoryIncludes` instance.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a file.
+    ///
+    /// If a file which has the same path exists, it is replaced and the old contents is returned.
+    pub fn insert<P, S>(&mut self, path: P, text: S) -> Option<String>
+    where
+        P: Into<PathBuf>,
+        S: Into<String>,
+    {
+        self.files.insert(path.into(), text.into())
+    }
+
+    /// Removes a file.
+    pub fn remove<P: AsRef<Path>>(&mut self, path: P) -> Option<String> {
+        self.files.remove(path.as_ref())
+    }
+
+    /// Returns the contents of a file.
+    pub fn get<P: AsRef<Path>>(&self, path: P) -> Option<&str> {
+        self.files.get(path.as_ref()).map(|s| s.as_str())
+    }
+
+    fn find_include_lib(&self, path: &Path, code_paths: &[PathBuf]) -> Option<&Path> {
+        let mut components = path.components();
+        let app_name = components.next()?.as_os_str().to_str()?;
+        let rest = components.as_path();
+        let versioned_prefix = format!("{}-", app_name);
+        code_paths.iter().find_map(|root| {
+            self.files.keys().map(PathBuf::as_path).find(|file| {
+                let mut components = match file.strip_prefix(root) {
+                    Err(_) => return false,
+                    Ok(relative) => relative.components(),
+                };
+                let app_dir = components.next().and_then(|c| c.as_os_str().to_str());
+                app_dir.is_some_and(|d| d == app_name || d.starts_with(&versioned_prefix))
+                    && components.as_path() == rest
+            })
+        })
+    }
+}
+impl IncludeResolver for MemoryIncludes {
+    /// Resolves the target file in the same manner as `FileSystemResolver`,
+    /// except that the application metadata files are not consulted for `-include_lib`.
+    fn resolve(
+        &self,
+        kind: IncludeKind,
+        path: &Path,
+        search_dirs: &[PathBuf],
+    ) -> io::Result<(PathBuf, String)> {
+        let found = if self.files.contains_key(path) {
+            Some(path)
+        } else {
+            match kind {
+                IncludeKind::Include => search_dirs
+                    .iter()
+                    .map(|dir| dir.join(path))
+                    .find_map(|p| self.files.get_key_value(&p).map(|(k, _)| k.as_path())),
+                IncludeKind::IncludeLib => self.find_include_lib(path, search_dirs),
+            }
+        };
+        found
+            .map(|p| (p.to_path_buf(), self.files[p].clone()))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{:?}", path)))
+    }
+}
+
+#[cfg(feature = "fs")]
+fn invalid_input<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidInput, e)
+}
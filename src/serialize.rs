@@ -0,0 +1,232 @@
+//! `serde` support for the public types (available only if the `serde` feature is enabled).
+//!
+//! Tokens are serialized as objects which have the text and the position of the token.
+//! Syntactic values (e.g., directives and macro calls) are serialized as the sequences of their tokens,
+//! and deserialized by lexing the tokens at their original positions and parsing them again.
+use erl_tokenize::{Lexer, LexicalToken, Position, PositionRange};
+use serde::de::Error as _;
+use serde::ser::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::directives::Define;
+use crate::token_reader::{ReadFrom, TokenReader};
+use crate::types::{MacroArgs, MacroName, ToTokens};
+use crate::{Directive, Error, MacroCall, MacroDef};
+
+#[derive(Serialize, Deserialize)]
+struct SerdePosition {
+    file: Option<PathBuf>,
+    line: usize,
+    column: usize,
+    offset: usize,
+}
+impl SerdePosition {
+    fn new(position: &Position) -> Self {
+        SerdePosition {
+            file: position.filepath().cloned(),
+            line: position.line(),
+            column: position.column(),
+            offset: position.offset(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SerdeToken {
+    text: String,
+    #[serde(flatten)]
+    position: SerdePosition,
+}
+impl SerdeToken {
+    fn new(token: &LexicalToken) -> Self {
+        SerdeToken {
+            text: token.text().to_owned(),
+            position: SerdePosition::new(&token.start_position()),
+        }
+    }
+
+    fn into_token(self) -> Option<LexicalToken> {
+        let padding = padding(&self.position)?;
+        let mut lexer = Lexer::new(format!("{}{}", padding, self.text));
+        if let Some(ref file) = self.position.file {
+            lexer.set_filepath(file);
+        }
+        let token = lexer
+            .filter_map(|t| t.ok())
+            .find(|t| t.start_position().offset() >= padding.len())?;
+        if token.text() == self.text {
+            Some(token)
+        } else {
+            None
+        }
+    }
+}
+
+/// Returns the text which places the following token at `position`.
+fn padding(position: &SerdePosition) -> Option<String> {
+    let columns = position.column.checked_sub(1)?;
+    let newlines = position.line.checked_sub(1)?;
+    let mut padding = String::new();
+    if newlines == 0 {
+        // No-break spaces occupy two bytes (i.e., they adjust the offset without changing the column).
+        let extra = position.offset.checked_sub(columns)?;
+        if extra > columns {
+            return None;
+        }
+        padding.extend((0..extra).map(|_| '\u{a0}'));
+        padding.extend((extra..columns).map(|_| ' '));
+    } else {
+        let extra = position.offset.checked_sub(newlines + columns)?;
+        if extra > 0 {
+            padding.push('%');
+            padding.extend((1..extra).map(|_| '_'));
+        }
+        padding.extend((0..newlines).map(|_| '\n'));
+        padding.extend((0..columns).map(|_| ' '));
+    }
+    Some(padding)
+}
+
+fn serialize_tokens<S: Serializer>(
+    tokens: &[LexicalToken],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(tokens.iter().map(SerdeToken::new))
+}
+
+fn deserialize_tokens<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<LexicalToken>, D::Error> {
+    Vec::<SerdeToken>::deserialize(deserializer)?
+        .into_iter()
+        .map(|t| {
+            let text = t.text.clone();
+            t.into_token()
+                .ok_or_else(|| D::Error::custom(format!("cannot restore token: {:?}", text)))
+        })
+        .collect()
+}
+
+fn deserialize_value<'de, D, V>(deserializer: D) -> Result<V, D::Error>
+where
+    D: Deserializer<'de>,
+    V: ReadFrom,
+{
+    let tokens = deserialize_tokens(deserializer)?;
+    let mut reader = TokenReader::new(tokens.into_iter().map(Ok));
+    let value = reader.read().map_err(D::Error::custom)?;
+    if let Some(token) = reader.try_read_token().map_err(D::Error::custom)? {
+        return Err(D::Error::custom(format!(
+            "unexpected trailing token: {:?}",
+            token.text()
+        )));
+    }
+    Ok(value)
+}
+
+macro_rules! impl_serde_via_tokens {
+    ($($t:ty),*) => {
+        $(
+            impl Serialize for $t {
+                fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                    serialize_tokens(&self.tokens(), serializer)
+                }
+            }
+            impl<'de> Deserialize<'de> for $t {
+                fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                    deserialize_value(deserializer)
+                }
+            }
+        )*
+    };
+}
+impl_serde_via_tokens!(Directive, Define, MacroCall, MacroName, MacroArgs);
+
+#[derive(Serialize, Deserialize)]
+struct TokenSeq(
+    #[serde(
+        serialize_with = "serialize_tokens",
+        deserialize_with = "deserialize_tokens"
+    )]
+    Vec<LexicalToken>,
+);
+
+#[derive(Serialize, Deserialize)]
+#[allow(clippy::large_enum_variant)]
+enum SerdeMacroDef {
+    Static(Define),
+    Dynamic(TokenSeq),
+    DynamicWithVariables {
+        variables: Vec<String>,
+        replacement: TokenSeq,
+    },
+}
+
+/// Native macros cannot be serialized.
+impl Serialize for MacroDef {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let def = match *self {
+            MacroDef::Static(ref d) => SerdeMacroDef::Static(Define::clone(d)),
+            MacroDef::Dynamic(ref r) => SerdeMacroDef::Dynamic(TokenSeq(r.to_vec())),
+            MacroDef::DynamicWithVariables {
+                ref variables,
+                ref replacement,
+            } => SerdeMacroDef::DynamicWithVariables {
+                variables: variables.clone(),
+                replacement: TokenSeq(replacement.to_vec()),
+            },
+            MacroDef::Native(_) => {
+                return Err(S::Error::custom("native macros cannot be serialized"));
+            }
+        };
+        def.serialize(serializer)
+    }
+}
+impl<'de> Deserialize<'de> for MacroDef {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match SerdeMacroDef::deserialize(deserializer)? {
+            SerdeMacroDef::Static(d) => MacroDef::Static(Arc::new(d)),
+            SerdeMacroDef::Dynamic(r) => MacroDef::Dynamic(r.0.into()),
+            SerdeMacroDef::DynamicWithVariables {
+                variables,
+                replacement,
+            } => MacroDef::DynamicWithVariables {
+                variables,
+                replacement: replacement.0.into(),
+            },
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct SerdeError {
+    code: &'static str,
+    message: String,
+    span: Option<(SerdePosition, SerdePosition)>,
+    included_from: Vec<SerdePosition>,
+}
+
+/// Errors are serialized as objects which have the code, the message, the span
+/// and the include stack of the error.
+///
+/// Since errors may contain non-serializable values (e.g., `std::io::Error`),
+/// they cannot be deserialized.
+impl Serialize for Error {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SerdeError {
+            code: self.code(),
+            message: self.message(),
+            span: self
+                .span()
+                .map(|(start, end)| (SerdePosition::new(&start), SerdePosition::new(&end))),
+            included_from: self
+                .include_stack()
+                .iter()
+                .map(SerdePosition::new)
+                .collect(),
+        }
+        .serialize(serializer)
+    }
+}
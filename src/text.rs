@@ -0,0 +1,198 @@
+use erl_tokenize::{LexicalToken, Position, PositionRange, Tokenizer};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::SourceMap;
+
+/// Writer which reconstructs preprocessed source text from output tokens.
+///
+/// The whitespaces, newlines and comments between two tokens read from the same file
+/// are copied from the original text.
+/// If the original text between them contains other tokens (e.g., directives or macro calls),
+/// only the comments and newlines are kept so that the line numbers are preserved as much as possible.
+/// Generated tokens (e.g., the results of macro expansions) are inserted inline.
+///
+/// See also: [Preprocessor::preprocessed_text()](crate::Preprocessor::preprocessed_text).
+///
+/// # Examples
+///
+/// ```
+/// # extern crate erl_pp;
+/// # extern crate erl_tokenize;
+/// use erl_pp::TextWriter;
+/// use erl_tokenize::Lexer;
+///
+/// # fn main() {
+/// let src = "foo() -> % comment\n    bar.";
+/// let mut writer = TextWriter::new(String::new(), src);
+/// for token in Lexer::new(src) {
+///     writer.write_token(&token.unwrap()).unwrap();
+/// }
+/// assert_eq!(writer.finish().unwrap(), src);
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct TextWriter<'a, W, S: ?Sized> {
+    writer: W,
+    sources: &'a S,
+    texts: BTreeMap<Option<PathBuf>, Option<String>>,
+    last_offsets: BTreeMap<Option<PathBuf>, usize>,
+    first_path: Option<Option<PathBuf>>,
+    last_origin: Option<Position>,
+    needs_space: bool,
+}
+impl<'a, W, S> TextWriter<'a, W, S>
+where
+    W: fmt::Write,
+    S: SourceMap + ?Sized,
+{
+    /// Makes a new `TextWriter` instance which writes text to `writer`.
+    ///
+    /// `sources` is used to retrieve the original text of the files.
+    pub fn new(writer: W, sources: &'a S) -> Self {
+        TextWriter {
+            writer,
+            sources,
+            texts: BTreeMap::new(),
+            last_offsets: BTreeMap::new(),
+            first_path: None,
+            last_origin: None,
+            needs_space: false,
+        }
+    }
+
+    /// Writes a token read from the original text at its position.
+    pub fn write_token(&mut self, token: &LexicalToken) -> fmt::Result {
+        let start = token.start_position();
+        self.last_origin = None;
+        if !self.write_layout(&start)? && self.needs_space {
+            self.writer.write_char(' ')?;
+        }
+        self.last_offsets
+            .insert(start.filepath().cloned(), token.end_position().offset());
+        self.write_text(token.text())
+    }
+
+    /// Writes a generated token (e.g., the result of a macro expansion).
+    ///
+    /// `origin` is the position in the original text where the token is placed
+    /// (e.g., the start position of the macro call).
+    pub fn write_generated_token(
+        &mut self,
+        token: &LexicalToken,
+        origin: Option<&Position>,
+    ) -> fmt::Result {
+        let written = match origin {
+            Some(origin) if self.last_origin.as_ref() != Some(origin) => {
+                self.last_origin = Some(origin.clone());
+                self.write_layout(origin)?
+            }
+            _ => false,
+        };
+        if !written && self.needs_space {
+            self.writer.write_char(' ')?;
+        }
+        self.write_text(token.text())
+    }
+
+    pub(crate) fn add_text(&mut self, path: &Path, text: &str) {
+        self.texts
+            .entry(Some(path.to_path_buf()))
+            .or_insert_with(|| Some(text.to_owned()));
+    }
+
+    /// Writes the layout following the last token of the first written file
+    /// and returns the underlying writer.
+    pub fn finish(mut self) -> Result<W, fmt::Error> {
+        if let Some(path) = self.first_path.take() {
+            self.write_layout_until(path, None)?;
+        }
+        Ok(self.writer)
+    }
+
+    /// Returns a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    /// Consumes this writer and returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write_text(&mut self, text: &str) -> fmt::Result {
+        self.writer.write_str(text)?;
+        self.needs_space = true;
+        Ok(())
+    }
+
+    /// Writes the layout of the original text between the last written position and `position`.
+    ///
+    /// Returns `false` if the original text is unavailable or `position` precedes
+    /// the last written position, otherwise `true`.
+    fn write_layout(&mut self, position: &Position) -> Result<bool, fmt::Error> {
+        self.write_layout_until(position.filepath().cloned(), Some(position.offset()))
+    }
+
+    /// Same as `write_layout()` except that `None` offset means the end of the file.
+    fn write_layout_until(
+        &mut self,
+        path: Option<PathBuf>,
+        offset: Option<usize>,
+    ) -> Result<bool, fmt::Error> {
+        if self.first_path.is_none() {
+            self.first_path = Some(path.clone());
+        }
+        let sources = self.sources;
+        let text = self
+            .texts
+            .entry(path.clone())
+            .or_insert_with(|| sources.source_text(path.as_deref()).map(|t| t.into_owned()));
+        let offset = match (offset, text.as_ref()) {
+            (Some(offset), _) => offset,
+            (None, Some(text)) => text.len(),
+            (None, None) => return Ok(false),
+        };
+        let last_offset = self.last_offsets.get(&path).copied().unwrap_or(0);
+        if offset < last_offset {
+            return Ok(false);
+        }
+        self.last_offsets.insert(path, offset);
+
+        let gap = match text.as_deref().and_then(|t| t.get(last_offset..offset)) {
+            None => return Ok(false),
+            Some(gap) => gap,
+        };
+        if is_layout(gap) {
+            self.writer.write_str(gap)?;
+            return Ok(true);
+        }
+
+        // Other tokens (e.g., directives) are dropped but the comments and newlines are kept.
+        let mut written = false;
+        for token in Tokenizer::new(gap).filter_map(|t| t.ok()) {
+            if token.as_comment_token().is_some() {
+                if !written && self.needs_space {
+                    self.writer.write_char(' ')?;
+                }
+                self.writer.write_str(token.text())?;
+                written = true;
+            } else if token.as_whitespace_token().is_some() {
+                for _ in token.text().matches('\n') {
+                    self.writer.write_char('\n')?;
+                    written = true;
+                }
+            }
+        }
+        if !written && gap.ends_with(char::is_whitespace) {
+            self.writer.write_char(' ')?;
+        }
+        Ok(true)
+    }
+}
+
+/// Returns `true` if `text` consists only of whitespaces and comments.
+fn is_layout(text: &str) -> bool {
+    Tokenizer::new(text).all(|t| t.is_ok_and(|t| t.is_hidden_token()))
+}
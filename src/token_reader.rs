@@ -1,106 +1,256 @@
-use erl_tokenize::tokens::{AtomToken, StringToken, SymbolToken, VariableToken};
-use erl_tokenize::values::Symbol;
-use erl_tokenize::{Lexer, LexicalToken};
-use std::collections::{HashMap, VecDeque};
+//! Token reader which supports pushing tokens back.
+use erl_tokenize::tokens::{AtomToken, KeywordToken, StringToken, SymbolToken, VariableToken};
+use erl_tokenize::values::{Keyword, Symbol};
+use erl_tokenize::{Lexer, LexicalToken, Position, PositionRange};
+use std::collections::VecDeque;
 use std::fmt::Debug;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use crate::macros::NoArgsMacroCall;
-use crate::{Error, MacroCall, MacroDef, Result};
+use crate::macros::{NoArgsMacroCall, PREDEFINED_MACROS_WITH_ARGS};
+use crate::types::{MacroArgs, ToTokens};
+use crate::util::HashMap;
+use crate::{Error, MacroCall, MacroDef, MacroEnv, Result};
 
+/// Token reader which supports pushing tokens back (i.e., unlimited lookahead).
+///
+/// This is the layer used by this crate to parse directives and macro calls,
+/// and it is also useful for building other Erlang tools (e.g., attribute scanners).
+///
+/// # Examples
+///
+/// ```
+/// # extern crate erl_pp;
+/// # extern crate erl_tokenize;
+/// use erl_pp::token_reader::TokenReader;
+/// use erl_tokenize::Lexer;
+/// use erl_tokenize::tokens::{AtomToken, SymbolToken};
+/// use erl_tokenize::values::Symbol;
+///
+/// # fn main() {
+/// let mut reader = TokenReader::new(Lexer::new("-module(foo)."));
+/// reader.read_expected::<SymbolToken>(&Symbol::Hyphen).unwrap();
+/// assert!(reader.try_read_expected::<AtomToken>("export").unwrap().is_none());
+/// reader.read_expected::<AtomToken>("module").unwrap();
+/// reader.read_expected::<SymbolToken>(&Symbol::OpenParen).unwrap();
+/// assert_eq!(reader.read::<AtomToken>().unwrap().value(), "foo");
+/// # }
+/// ```
 #[derive(Debug)]
 pub struct TokenReader<T> {
     tokens: T,
-    included_tokens: Vec<Lexer<String>>,
+    source_path: Option<PathBuf>,
+    included_files: Vec<IncludedFile>,
+    lexed_files: HashMap<PathBuf, LexedFile>,
+    retain_lexed_files: bool,
     unread: VecDeque<LexicalToken>,
 }
+impl<T> TokenReader<T> {
+    /// Sets whether the texts and the tokens of the included files are kept after reading them
+    /// (i.e., whether a file included multiple times is lexed only once).
+    pub(crate) fn set_retain_lexed_files(&mut self, retain: bool) {
+        self.retain_lexed_files = retain;
+        if !retain {
+            self.lexed_files.clear();
+        }
+    }
+
+    /// Returns the path of the file which the underlying tokens were read from.
+    pub(crate) fn source_path(&self) -> Option<&Path> {
+        self.source_path.as_deref()
+    }
+
+    pub(crate) fn included_text(&self, path: &Path) -> Option<&str> {
+        self.included_files
+            .iter()
+            .rev()
+            .find(|f| f.path == path)
+            .map(|f| f.source.text())
+            .or_else(|| self.lexed_files.get(path).map(|f| &*f.text))
+    }
+}
 impl<T> TokenReader<T>
 where
     T: Iterator<Item = erl_tokenize::Result<LexicalToken>>,
 {
+    /// Makes a new `TokenReader` instance which reads the given tokens.
     pub fn new(tokens: T) -> Self {
         TokenReader {
             tokens,
-            included_tokens: Vec::new(),
+            source_path: None,
+            included_files: Vec::new(),
+            lexed_files: HashMap::default(),
+            retain_lexed_files: true,
             unread: VecDeque::new(),
         }
     }
 
-    pub fn add_included_text<P: AsRef<Path>>(&mut self, path: P, text: String) {
-        let mut lexer = Lexer::new(text);
-        lexer.set_filepath(path);
-        self.included_tokens.push(lexer);
+    pub(crate) fn reset(&mut self, tokens: T) {
+        self.tokens = tokens;
+        self.source_path = None;
+        self.included_files.clear();
+        self.lexed_files.clear();
+        self.unread.clear();
+    }
+
+    /// Consumes this reader and returns the underlying token source
+    /// together with the tokens pushed back but not read yet.
+    pub fn into_inner(self) -> (T, Vec<LexicalToken>) {
+        (self.tokens, self.unread.into())
+    }
+
+    pub(crate) fn add_included_text<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        text: String,
+        directive: Position,
+    ) {
+        let path = path.as_ref();
+
+        // A file included multiple times is lexed only once.
+        let source = match self.lexed_files.get(path) {
+            Some(lexed) if *lexed.text == *text => IncludedSource::Lexed {
+                text: Arc::clone(&lexed.text),
+                tokens: Arc::clone(&lexed.tokens),
+                index: 0,
+            },
+            _ => {
+                let mut lexer = Lexer::new(Arc::from(text));
+                lexer.set_filepath(path);
+                IncludedSource::Lexing {
+                    lexer,
+                    tokens: if self.retain_lexed_files {
+                        Some(Vec::new())
+                    } else {
+                        None
+                    },
+                }
+            }
+        };
+        self.included_files.push(IncludedFile {
+            path: path.to_path_buf(),
+            source,
+            directive,
+        });
     }
 
+    pub(crate) fn include_stack(&self) -> Vec<Position> {
+        self.included_files
+            .iter()
+            .rev()
+            .map(|f| f.directive.clone())
+            .collect()
+    }
+
+    pub(crate) fn include_depth(&self) -> usize {
+        self.included_files.len()
+    }
+
+    /// Reads a value of type `V`.
     pub fn read<V>(&mut self) -> Result<V>
     where
         V: ReadFrom,
     {
         V::read_from(self)
     }
+    /// Tries to read a value of type `V`.
+    ///
+    /// If the next tokens do not form a `V` value, `Ok(None)` is returned.
     pub fn try_read<V>(&mut self) -> Result<Option<V>>
     where
         V: ReadFrom,
     {
         V::try_read_from(self)
     }
-    pub fn try_read_macro_call(
-        &mut self,
-        macros: &HashMap<String, MacroDef>,
-    ) -> Result<Option<MacroCall>> {
+    /// Tries to read a macro call (e.g., `?FOO` or `?BAR(1, 2)`).
+    ///
+    /// Whether the call takes arguments is determined by the definitions in `env`.
+    pub fn try_read_macro_call(&mut self, env: &MacroEnv) -> Result<Option<MacroCall>> {
         if let Some(call) = self.try_read::<NoArgsMacroCall>()? {
             let mut call = MacroCall {
                 _question: call._question,
                 name: call.name,
                 args: None,
             };
-            if macros
-                .get(call.name.value())
-                .map_or(false, MacroDef::has_variables)
-            {
+            let definitions = env.macros().definitions(call.name.value());
+            if PREDEFINED_MACROS_WITH_ARGS.contains(&call.name.value()) {
                 call.args = Some(self.read()?);
+            } else if env.has_macro_fn(call.name.value()) {
+                call.args = self.try_read()?;
+            } else if definitions.iter().any(MacroDef::has_variables) {
+                if definitions.iter().all(MacroDef::has_variables) {
+                    call.args = Some(self.read()?);
+                } else if let Some(args) = self.try_read::<MacroArgs>()? {
+                    // There is also a definition without parentheses (e.g., `-define(FOO, bar).`),
+                    // which is used if no definition matches the arity of the call.
+                    if definitions.iter().any(|d| d.arity() == Some(args.len())) {
+                        call.args = Some(args);
+                    } else {
+                        for token in args.tokens().into_iter().rev() {
+                            self.unread_token(token);
+                        }
+                    }
+                }
             }
             Ok(Some(call))
         } else {
             Ok(None)
         }
     }
+    /// Reads a value of type `V` which equals to `expected`.
     pub fn read_expected<V>(&mut self, expected: &V::Value) -> Result<V>
     where
         V: ReadFrom + Expect + Into<LexicalToken>,
     {
         V::read_expected(self, expected)
     }
+    /// Tries to read a value of type `V` which equals to `expected`.
+    ///
+    /// If the next token is not such a value, it is pushed back and `Ok(None)` is returned.
     pub fn try_read_expected<V>(&mut self, expected: &V::Value) -> Result<Option<V>>
     where
         V: ReadFrom + Expect + Into<LexicalToken>,
     {
         V::try_read_expected(self, expected)
     }
+    /// Tries to read a token.
+    ///
+    /// `Ok(None)` is returned when reaching the end of the tokens.
     pub fn try_read_token(&mut self) -> Result<Option<LexicalToken>> {
         if let Some(token) = self.unread.pop_front() {
             Ok(Some(token))
-        } else if !self.included_tokens.is_empty() {
-            match self
-                .included_tokens
-                .last_mut()
-                .expect("unreachable")
-                .next()
-                .transpose()?
+        } else if let Some(file) = self.included_files.last_mut() {
+            if let Some(token) = file.source.next_token()? {
+                return Ok(Some(token));
+            }
+            let file = self.included_files.pop().expect("unreachable");
+            if let IncludedSource::Lexing {
+                lexer,
+                tokens: Some(tokens),
+            } = file.source
             {
-                None => {
-                    self.included_tokens.pop();
-                    self.try_read_token()
-                }
-                Some(t) => Ok(Some(t)),
+                let lexed = LexedFile {
+                    text: Arc::from(lexer.text()),
+                    tokens: Arc::from(tokens),
+                };
+                self.lexed_files.insert(file.path, lexed);
             }
+            self.try_read_token()
         } else {
             match self.tokens.next().transpose()? {
                 None => Ok(None),
-                Some(t) => Ok(Some(t)),
+                Some(t) => {
+                    if self.source_path.is_none() {
+                        self.source_path = t.start_position().filepath().cloned();
+                    }
+                    Ok(Some(t))
+                }
             }
         }
     }
+    /// Reads a token.
+    ///
+    /// `Error::UnexpectedEof` is returned when reaching the end of the tokens.
     pub fn read_token(&mut self) -> Result<LexicalToken> {
         if let Some(token) = self.try_read_token()? {
             Ok(token)
@@ -108,15 +258,79 @@ where
             Err(Error::UnexpectedEof)
         }
     }
+    /// Pushes back a token.
+    ///
+    /// The token will be returned by the next read.
     pub fn unread_token(&mut self, token: LexicalToken) {
         self.unread.push_front(token);
     }
 }
 
+#[derive(Debug)]
+struct IncludedFile {
+    path: PathBuf,
+    source: IncludedSource,
+
+    /// The start position of the directive which included this file.
+    directive: Position,
+}
+
+#[derive(Debug)]
+enum IncludedSource {
+    Lexing {
+        lexer: Lexer<Arc<str>>,
+        // `None` if the tokens are not kept for the later inclusions.
+        tokens: Option<Vec<LexicalToken>>,
+    },
+    Lexed {
+        text: Arc<str>,
+        tokens: Arc<[LexicalToken]>,
+        index: usize,
+    },
+}
+impl IncludedSource {
+    fn next_token(&mut self) -> Result<Option<LexicalToken>> {
+        match self {
+            IncludedSource::Lexing { lexer, tokens } => {
+                let token = lexer.next().transpose()?;
+                if let (Some(token), Some(tokens)) = (&token, tokens) {
+                    tokens.push(token.clone());
+                }
+                Ok(token)
+            }
+            IncludedSource::Lexed { tokens, index, .. } => {
+                let token = tokens.get(*index).cloned();
+                *index += 1;
+                Ok(token)
+            }
+        }
+    }
+
+    fn text(&self) -> &str {
+        match self {
+            IncludedSource::Lexing { lexer, .. } => lexer.text(),
+            IncludedSource::Lexed { text, .. } => text,
+        }
+    }
+}
+
+/// The tokens of an included file, which are replayed when the file is included again.
+#[derive(Debug)]
+struct LexedFile {
+    text: Arc<str>,
+    tokens: Arc<[LexicalToken]>,
+}
+
+/// Values which can be read from a `TokenReader`.
 pub trait ReadFrom: Sized {
+    /// Reads a value from `reader`.
     fn read_from<T>(reader: &mut TokenReader<T>) -> Result<Self>
     where
         T: Iterator<Item = erl_tokenize::Result<LexicalToken>>;
+    /// Tries to read a value from `reader`.
+    ///
+    /// The default implementation returns `Ok(None)` if `read_from()` fails
+    /// due to an unexpected token (which is pushed back) or the end of the tokens.
     fn try_read_from<T>(reader: &mut TokenReader<T>) -> Result<Option<Self>>
     where
         T: Iterator<Item = erl_tokenize::Result<LexicalToken>>,
@@ -132,6 +346,7 @@ pub trait ReadFrom: Sized {
             Err(e)
         })
     }
+    /// Reads a value which equals to `expected` from `reader`.
     fn read_expected<T>(reader: &mut TokenReader<T>, expected: &Self::Value) -> Result<Self>
     where
         T: Iterator<Item = erl_tokenize::Result<LexicalToken>>,
@@ -147,6 +362,7 @@ pub trait ReadFrom: Sized {
             Ok(token)
         })
     }
+    /// Tries to read a value which equals to `expected` from `reader`.
     fn try_read_expected<T>(
         reader: &mut TokenReader<T>,
         expected: &Self::Value,
@@ -178,6 +394,17 @@ impl ReadFrom for AtomToken {
             .map_err(|token| Error::unexpected_token(token, "atom"))
     }
 }
+impl ReadFrom for KeywordToken {
+    fn read_from<T>(reader: &mut TokenReader<T>) -> Result<Self>
+    where
+        T: Iterator<Item = erl_tokenize::Result<LexicalToken>>,
+    {
+        let token = reader.read_token()?;
+        token
+            .into_keyword_token()
+            .map_err(|token| Error::unexpected_token(token, "keyword"))
+    }
+}
 impl ReadFrom for VariableToken {
     fn read_from<T>(reader: &mut TokenReader<T>) -> Result<Self>
     where
@@ -212,8 +439,12 @@ impl ReadFrom for StringToken {
     }
 }
 
+/// Values which can be compared with expected values.
 pub trait Expect {
+    /// The type of expected values.
     type Value: PartialEq + Debug + ?Sized;
+
+    /// Returns `true` if this value equals to `expected`, otherwise `false`.
     fn expect(&self, expected: &Self::Value) -> bool;
 }
 impl Expect for AtomToken {
@@ -222,6 +453,12 @@ impl Expect for AtomToken {
         self.value() == expected
     }
 }
+impl Expect for KeywordToken {
+    type Value = Keyword;
+    fn expect(&self, expected: &Self::Value) -> bool {
+        self.value() == *expected
+    }
+}
 impl Expect for SymbolToken {
     type Value = Symbol;
     fn expect(&self, expected: &Self::Value) -> bool {
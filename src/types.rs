@@ -1,7 +1,8 @@
 //! Miscellaneous types.
 use erl_tokenize::tokens::{AtomToken, SymbolToken, VariableToken};
-use erl_tokenize::values::Symbol;
+use erl_tokenize::values::{Keyword, Symbol};
 use erl_tokenize::{LexicalToken, Position, PositionRange};
+use smallvec::SmallVec;
 use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::mem;
@@ -9,6 +10,55 @@ use std::mem;
 use crate::token_reader::{ReadFrom, TokenReader};
 use crate::{Error, Result};
 
+/// Token sequence which stores a few tokens inline (i.e., without heap allocation).
+///
+/// This is used for short sequences such as macro arguments and macro replacements.
+pub type TokenVec = SmallVec<[LexicalToken; 3]>;
+
+/// Conversion into the flat sequence of the original tokens.
+///
+/// This is implemented by the syntactic values (e.g., directives and macro calls),
+/// and useful for splicing them back into token streams or computing their byte ranges.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate erl_pp;
+/// # extern crate erl_tokenize;
+/// use erl_pp::types::ToTokens;
+/// use erl_pp::Preprocessor;
+/// use erl_tokenize::Lexer;
+///
+/// # fn main() {
+/// let mut pp = Preprocessor::new(Lexer::new("-define(FOO, foo)."));
+/// assert!(pp.next().is_none());
+/// let directive = pp.directives().values().next().unwrap();
+/// assert_eq!(directive.tokens().iter().map(|t| t.text()).collect::<Vec<_>>(),
+///            ["-", "define", "(", "FOO", ",", "foo", ")", "."]);
+/// # }
+/// ```
+pub trait ToTokens {
+    /// Appends the tokens of this value to `tokens`.
+    fn push_tokens(&self, tokens: &mut Vec<LexicalToken>);
+
+    /// Returns the tokens of this value.
+    fn tokens(&self) -> Vec<LexicalToken> {
+        let mut tokens = Vec::new();
+        self.push_tokens(&mut tokens);
+        tokens
+    }
+}
+impl ToTokens for LexicalToken {
+    fn push_tokens(&self, tokens: &mut Vec<LexicalToken>) {
+        tokens.push(self.clone());
+    }
+}
+impl ToTokens for VariableToken {
+    fn push_tokens(&self, tokens: &mut Vec<LexicalToken>) {
+        tokens.push(self.clone().into());
+    }
+}
+
 /// The list of tokens that can be used as a macro name.
 #[derive(Debug, Clone)]
 #[allow(missing_docs)]
@@ -63,6 +113,14 @@ impl fmt::Display for MacroName {
         write!(f, "{}", self.text())
     }
 }
+impl ToTokens for MacroName {
+    fn push_tokens(&self, tokens: &mut Vec<LexicalToken>) {
+        match *self {
+            MacroName::Atom(ref t) => tokens.push(t.clone().into()),
+            MacroName::Variable(ref t) => tokens.push(t.clone().into()),
+        }
+    }
+}
 impl ReadFrom for MacroName {
     fn read_from<T>(reader: &mut TokenReader<T>) -> Result<Self>
     where
@@ -114,6 +172,13 @@ impl fmt::Display for MacroVariables {
         write!(f, "({})", self.list)
     }
 }
+impl ToTokens for MacroVariables {
+    fn push_tokens(&self, tokens: &mut Vec<LexicalToken>) {
+        tokens.push(self._open_paren.clone().into());
+        self.list.push_tokens(tokens);
+        tokens.push(self._close_paren.clone().into());
+    }
+}
 impl ReadFrom for MacroVariables {
     fn read_from<T>(reader: &mut TokenReader<T>) -> Result<Self>
     where
@@ -164,6 +229,13 @@ impl fmt::Display for MacroArgs {
         write!(f, "({})", self.list)
     }
 }
+impl ToTokens for MacroArgs {
+    fn push_tokens(&self, tokens: &mut Vec<LexicalToken>) {
+        tokens.push(self._open_paren.clone().into());
+        self.list.push_tokens(tokens);
+        tokens.push(self._close_paren.clone().into());
+    }
+}
 impl ReadFrom for MacroArgs {
     fn read_from<T>(reader: &mut TokenReader<T>) -> Result<Self>
     where
@@ -183,7 +255,7 @@ pub struct MacroArg {
     /// Tokens which represent a macro argument.
     ///
     /// Note that this must not be empty.
-    pub tokens: Vec<LexicalToken>,
+    pub tokens: TokenVec,
 }
 impl PositionRange for MacroArg {
     fn start_position(&self) -> Position {
@@ -201,27 +273,33 @@ impl fmt::Display for MacroArg {
         Ok(())
     }
 }
+impl ToTokens for MacroArg {
+    fn push_tokens(&self, tokens: &mut Vec<LexicalToken>) {
+        tokens.extend(self.tokens.iter().cloned());
+    }
+}
 impl ReadFrom for MacroArg {
     fn read_from<T>(reader: &mut TokenReader<T>) -> Result<Self>
     where
         T: Iterator<Item = erl_tokenize::Result<LexicalToken>>,
     {
-        let mut stack = Vec::new();
-        let mut arg = Vec::new();
+        // Open brackets and keywords terminated by `end` (e.g., `case`).
+        let mut stack = SmallVec::<[LexicalToken; 4]>::new();
+        let mut arg = TokenVec::new();
         while let Some(token) = reader.try_read_token()? {
-            if let LexicalToken::Symbol(ref s) = token {
-                match s.value() {
+            match token {
+                LexicalToken::Symbol(ref s) => match s.value() {
                     Symbol::CloseParen if stack.is_empty() => {
                         reader.unread_token(s.clone().into());
                         return if arg.is_empty() {
-                            Err(Error::missing_macro_arg(s.start_position()))
+                            Err(Error::missing_macro_arg(s))
                         } else {
                             Ok(MacroArg { tokens: arg })
                         };
                     }
                     Symbol::Comma if stack.is_empty() => {
                         if arg.is_empty() {
-                            return Err(Error::missing_macro_arg(s.start_position()));
+                            return Err(Error::missing_macro_arg(s));
                         }
                         reader.unread_token(s.clone().into());
                         return Ok(MacroArg { tokens: arg });
@@ -230,28 +308,48 @@ impl ReadFrom for MacroArg {
                     | Symbol::OpenBrace
                     | Symbol::OpenSquare
                     | Symbol::DoubleLeftAngle => {
-                        stack.push(s.clone());
+                        stack.push(token.clone());
                     }
                     Symbol::CloseParen
                     | Symbol::CloseBrace
                     | Symbol::CloseSquare
                     | Symbol::DoubleRightAngle => {
-                        let last = stack
-                            .pop()
-                            .ok_or_else(|| Error::unbalanced_paren(None, s.clone()))?;
-                        let expected = match last.value() {
-                            Symbol::OpenParen => Symbol::CloseParen,
-                            Symbol::OpenBrace => Symbol::CloseBrace,
-                            Symbol::OpenSquare => Symbol::CloseSquare,
-                            Symbol::DoubleLeftAngle => Symbol::DoubleRightAngle,
-                            _ => unreachable!(),
+                        let last = match stack.pop() {
+                            None => return Err(Error::unbalanced_paren(None, s.clone())),
+                            Some(LexicalToken::Symbol(last)) => last,
+                            Some(_) => return Err(Error::unexpected_token(token, "end")),
                         };
-                        if s.value() != expected {
+                        if s.value() != closing_symbol(last.value()) {
                             return Err(Error::unbalanced_paren(Some(last), s.clone()));
                         }
                     }
                     _ => {}
-                }
+                },
+                LexicalToken::Keyword(ref k) => match k.value() {
+                    Keyword::Begin
+                    | Keyword::Case
+                    | Keyword::If
+                    | Keyword::Receive
+                    | Keyword::Try => {
+                        stack.push(token.clone());
+                    }
+                    Keyword::Fun if starts_fun_expr(reader)? => {
+                        stack.push(token.clone());
+                    }
+                    Keyword::End
+                        if stack.last().is_some_and(|t| t.as_keyword_token().is_some()) =>
+                    {
+                        stack.pop();
+                    }
+                    Keyword::End => {
+                        if let Some(open) = stack.last().and_then(LexicalToken::as_symbol_token) {
+                            let expected = closing_symbol(open.value());
+                            return Err(Error::unexpected_token(token, expected.as_str()));
+                        }
+                    }
+                    _ => {}
+                },
+                _ => {}
             }
             arg.push(token);
         }
@@ -259,6 +357,46 @@ impl ReadFrom for MacroArg {
     }
 }
 
+fn closing_symbol(open: Symbol) -> Symbol {
+    match open {
+        Symbol::OpenParen => Symbol::CloseParen,
+        Symbol::OpenBrace => Symbol::CloseBrace,
+        Symbol::OpenSquare => Symbol::CloseSquare,
+        Symbol::DoubleLeftAngle => Symbol::DoubleRightAngle,
+        _ => unreachable!(),
+    }
+}
+
+// Returns `true` if the following tokens start a fun expression terminated by `end`
+// (i.e., `fun (...) -> ... end` or `fun Name(...) -> ... end`) rather than `fun name/1`.
+fn starts_fun_expr<T>(reader: &mut TokenReader<T>) -> Result<bool>
+where
+    T: Iterator<Item = erl_tokenize::Result<LexicalToken>>,
+{
+    let is_open_paren = |t: &LexicalToken| {
+        t.as_symbol_token()
+            .is_some_and(|s| s.value() == Symbol::OpenParen)
+    };
+    let first = match reader.try_read_token()? {
+        None => return Ok(false),
+        Some(first) => first,
+    };
+    let starts = if is_open_paren(&first) {
+        true
+    } else if first.as_variable_token().is_some() {
+        let second = reader.try_read_token()?;
+        let starts = second.as_ref().is_some_and(is_open_paren);
+        if let Some(second) = second {
+            reader.unread_token(second);
+        }
+        starts
+    } else {
+        false
+    };
+    reader.unread_token(first);
+    Ok(starts)
+}
+
 /// Tail part of a linked list (cons cell).
 #[derive(Debug, Clone)]
 #[allow(missing_docs)]
@@ -280,6 +418,20 @@ impl<T: fmt::Display> fmt::Display for Tail<T> {
         }
     }
 }
+impl<T: ToTokens> ToTokens for Tail<T> {
+    fn push_tokens(&self, tokens: &mut Vec<LexicalToken>) {
+        if let Tail::Cons {
+            ref _comma,
+            ref head,
+            ref tail,
+        } = *self
+        {
+            tokens.push(_comma.clone().into());
+            head.push_tokens(tokens);
+            tail.push_tokens(tokens);
+        }
+    }
+}
 impl<U: ReadFrom> ReadFrom for Tail<U> {
     fn read_from<T>(reader: &mut TokenReader<T>) -> Result<Self>
     where
@@ -316,6 +468,14 @@ impl<T: fmt::Display> fmt::Display for List<T> {
         }
     }
 }
+impl<T: ToTokens> ToTokens for List<T> {
+    fn push_tokens(&self, tokens: &mut Vec<LexicalToken>) {
+        if let List::Cons { ref head, ref tail } = *self {
+            head.push_tokens(tokens);
+            tail.push_tokens(tokens);
+        }
+    }
+}
 impl<U: ReadFrom> ReadFrom for List<U> {
     fn read_from<T>(reader: &mut TokenReader<T>) -> Result<Self>
     where
@@ -1,27 +1,310 @@
+#[cfg(feature = "fs")]
+use erl_tokenize::values::Symbol;
+#[cfg(feature = "fs")]
+use erl_tokenize::Lexer;
+#[cfg(feature = "fs")]
+use erl_tokenize::LexicalToken;
+use erl_tokenize::Position;
+#[cfg(feature = "fs")]
 use std::env;
-use std::fs::File;
-use std::io::Read;
+#[cfg(feature = "fs")]
+use std::ffi::OsString;
+#[cfg(feature = "fs")]
+use std::fs::{self, File};
+#[cfg(feature = "fs")]
+use std::io::{self, Read};
 use std::path::{Path, PathBuf};
 
-pub fn substitute_path_variables<P: AsRef<Path>>(path: P) -> PathBuf {
+use crate::SourceMap;
+
+#[cfg(feature = "fxhash")]
+pub type HashMap<K, V> = rustc_hash::FxHashMap<K, V>;
+#[cfg(feature = "fxhash")]
+pub type HashSet<T> = rustc_hash::FxHashSet<T>;
+#[cfg(not(feature = "fxhash"))]
+pub use std::collections::{HashMap, HashSet};
+
+#[cfg(feature = "arena")]
+pub use bumpalo::Bump as Arena;
+#[cfg(feature = "arena")]
+pub type ArenaVec<'a, T> = bumpalo::collections::Vec<'a, T>;
+
+#[cfg(not(feature = "arena"))]
+#[derive(Debug, Default)]
+pub struct Arena;
+#[cfg(not(feature = "arena"))]
+impl Arena {
+    pub fn new() -> Self {
+        Arena
+    }
+    pub fn reset(&mut self) {}
+}
+#[cfg(not(feature = "arena"))]
+pub type ArenaVec<'a, T> = Vec<T>;
+
+#[cfg(feature = "arena")]
+pub fn arena_vec<T>(arena: &Arena) -> ArenaVec<'_, T> {
+    bumpalo::collections::Vec::new_in(arena)
+}
+#[cfg(not(feature = "arena"))]
+pub fn arena_vec<T>(_arena: &Arena) -> ArenaVec<'_, T> {
+    Vec::new()
+}
+
+/// Cache of the environment variables referred by include paths.
+///
+/// Each variable is looked up only once, so the substitution results are stable
+/// even if the environment changes afterwards.
+#[derive(Debug, Default, Clone)]
+pub struct EnvVars {
+    #[cfg(feature = "fs")]
+    vars: HashMap<String, Option<OsString>>,
+}
+impl EnvVars {
+    #[cfg(feature = "fs")]
+    fn get(&mut self, name: &str) -> Option<OsString> {
+        self.vars
+            .entry(name.to_owned())
+            .or_insert_with(|| env::var_os(name))
+            .clone()
+    }
+}
+
+#[cfg(feature = "fs")]
+pub fn substitute_path_variables<P: AsRef<Path>>(path: P, vars: &mut EnvVars) -> PathBuf {
     let mut new = PathBuf::new();
     for (i, c) in path.as_ref().components().enumerate() {
-        if let (0, Some(s)) = (i, c.as_os_str().to_str()) {
-            if s.as_bytes().get(0) == Some(&b'$') {
-                if let Ok(c) = env::var(s.split_at(1).1) {
-                    new.push(c);
+        match c.as_os_str().to_str() {
+            Some("~") if i == 0 => {
+                if let Some(home) = vars.get("HOME").or_else(|| vars.get("USERPROFILE")) {
+                    new.push(home);
                     continue;
                 }
             }
+            Some(s) if s.contains('$') => {
+                new.push(substitute_variables(s, vars));
+                continue;
+            }
+            _ => {}
         }
         new.push(c.as_os_str());
     }
     new
 }
 
-pub fn read_file<P: AsRef<Path>>(path: P) -> std::io::Result<String> {
-    let mut buf = String::new();
+// Environment variables are unavailable without the `fs` feature (e.g., on `wasm32-unknown-unknown`).
+#[cfg(not(feature = "fs"))]
+pub fn substitute_path_variables<P: AsRef<Path>>(path: P, _vars: &mut EnvVars) -> PathBuf {
+    path.as_ref().to_path_buf()
+}
+
+// Substitutes `$VAR` and `${VAR}` in the given text.
+//
+// Undefined variables are left as they are.
+#[cfg(feature = "fs")]
+fn substitute_variables(s: &str, vars: &mut EnvVars) -> OsString {
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut new = OsString::new();
+    let mut rest = s;
+    while let Some(i) = rest.find('$') {
+        new.push(&rest[..i]);
+        let after = &rest[i + 1..];
+        let (name, len) = if let Some(braced) = after.strip_prefix('{') {
+            match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => ("", 0),
+            }
+        } else {
+            let end = after.find(|c| !is_name_char(c)).unwrap_or(after.len());
+            (&after[..end], end)
+        };
+        match Some(name)
+            .filter(|name| !name.is_empty())
+            .and_then(|name| vars.get(name))
+        {
+            Some(value) => new.push(value),
+            None => new.push(&rest[i..i + 1 + len]),
+        }
+        rest = &after[len..];
+    }
+    new.push(rest);
+    new
+}
+
+/// Returns the original text between `start` and `end` which is taken from `sources`.
+///
+/// Returns `None` if the text is unavailable or the positions are not in the same file.
+pub fn source_slice<S>(sources: &S, start: &Position, end: &Position) -> Option<String>
+where
+    S: SourceMap + ?Sized,
+{
+    if start.filepath() != end.filepath() {
+        return None;
+    }
+    let text = sources.source_text(start.filepath().map(|p| p.as_path()))?;
+    text.get(start.offset()..end.offset())
+        .map(ToOwned::to_owned)
+}
+
+/// 64-bit FNV-1a hasher whose results do not depend on the platform or the Rust version.
+#[derive(Debug)]
+pub struct StableHasher(u64);
+impl StableHasher {
+    pub fn new() -> Self {
+        StableHasher(0xcbf2_9ce4_8422_2325)
+    }
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= u64::from(b);
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+    pub fn write_u64(&mut self, n: u64) {
+        self.write_bytes(&n.to_le_bytes());
+    }
+    pub fn write_str(&mut self, s: &str) {
+        self.write_u64(s.len() as u64);
+        self.write_bytes(s.as_bytes());
+    }
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Reads a source file in the encoding specified by its `coding` comment (as `epp` does).
+///
+/// The comment (e.g., `%% coding: latin-1`) is searched in the first two lines of the file.
+/// If it is not found, the file is read as UTF-8.
+/// The byte order mark at the beginning of the file is removed
+/// (use `read_file_with_bom()` to keep it).
+#[cfg(feature = "fs")]
+pub fn read_file<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    let mut text = read_file_with_bom(path)?;
+    strip_bom(&mut text);
+    Ok(text)
+}
+
+/// Same as `read_file()` except that the byte order mark is kept.
+#[cfg(feature = "fs")]
+pub fn read_file_with_bom<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    let mut buf = Vec::new();
     let mut file = File::open(&path)?;
-    file.read_to_string(&mut buf)?;
-    Ok(buf)
+    file.read_to_end(&mut buf)?;
+    // As `epp`, the byte order mark takes precedence over the `coding` comment.
+    if !buf.starts_with("\u{feff}".as_bytes()) && is_latin1(&buf) {
+        Ok(buf.iter().map(|&b| char::from(b)).collect())
+    } else {
+        String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// Removes the byte order mark at the beginning of `text`.
+///
+/// Returns `true` if it has been removed, otherwise `false`.
+pub fn strip_bom(text: &mut String) -> bool {
+    if text.starts_with('\u{feff}') {
+        text.drain(..'\u{feff}'.len_utf8());
+        true
+    } else {
+        false
+    }
+}
+
+// Returns `true` if the `coding` comment in the first two lines specifies latin-1.
+#[cfg(feature = "fs")]
+fn is_latin1(text: &[u8]) -> bool {
+    text.split(|&b| b == b'\n').take(2).any(|line| {
+        let comment = match line.iter().position(|&b| b == b'%') {
+            None => return false,
+            Some(i) => String::from_utf8_lossy(&line[i..]).to_ascii_lowercase(),
+        };
+        let after = match comment.find("coding") {
+            None => return false,
+            Some(i) => comment[i + "coding".len()..].trim_start(),
+        };
+        let encoding = match after.strip_prefix(|c| c == ':' || c == '=') {
+            None => return false,
+            Some(encoding) => encoding.trim_start(),
+        };
+        let end = encoding
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-'))
+            .unwrap_or(encoding.len());
+        matches!(&encoding[..end], "latin-1" | "latin1")
+    })
+}
+
+/// Finds the `lib` directory of the OTP installation.
+///
+/// `$OTP_ROOT/lib` is used if the environment variable is set.
+/// Otherwise, the installation root is located from the `erl` executable found in `PATH`.
+#[cfg(feature = "fs")]
+pub fn find_otp_lib_dir() -> Option<PathBuf> {
+    if let Some(root) = env::var_os("OTP_ROOT") {
+        let lib = Path::new(&root).join("lib");
+        return if lib.is_dir() { Some(lib) } else { None };
+    }
+
+    let paths = env::var_os("PATH")?;
+    let erl = env::split_paths(&paths)
+        .map(|dir| dir.join("erl"))
+        .find(|erl| erl.is_file())?;
+
+    // e.g., `/usr/bin/erl` -> `/usr/lib/erlang/bin/erl`
+    let erl = fs::canonicalize(erl).ok()?;
+    let root = erl.parent()?.parent()?;
+    [
+        root.join("lib"),
+        root.join("lib").join("erlang").join("lib"),
+    ]
+    .iter()
+    .find(|lib| lib.join("kernel").is_dir() || has_app_dir(lib, "kernel"))
+    .cloned()
+}
+
+#[cfg(feature = "fs")]
+fn has_app_dir(root: &Path, app_name: &str) -> bool {
+    let prefix = format!("{}-", app_name);
+    fs::read_dir(root).is_ok_and(|entries| {
+        entries.filter_map(|entry| entry.ok()).any(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.starts_with(&prefix))
+        })
+    })
+}
+
+#[cfg(feature = "fs")]
+pub fn find_app_dir<P: AsRef<Path>>(root: P, app_name: &str) -> Option<PathBuf> {
+    let mut dirs = fs::read_dir(root)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_dir())
+        .collect::<Vec<_>>();
+    dirs.sort();
+    dirs.into_iter().find(|dir| {
+        let app_file = dir.join("ebin").join(format!("{}.app", app_name));
+        let app_src_file = dir.join("src").join(format!("{}.app.src", app_name));
+        [app_file, app_src_file].iter().any(|f| {
+            read_file(f).is_ok_and(|text| read_app_name(&text).as_deref() == Some(app_name))
+        })
+    })
+}
+
+#[cfg(feature = "fs")]
+fn read_app_name(text: &str) -> Option<String> {
+    let tokens = Lexer::new(text)
+        .take(4)
+        .collect::<erl_tokenize::Result<Vec<_>>>()
+        .ok()?;
+    match tokens.as_slice() {
+        [LexicalToken::Symbol(open), LexicalToken::Atom(tag), LexicalToken::Symbol(comma), LexicalToken::Atom(name)]
+            if open.value() == Symbol::OpenBrace
+                && tag.value() == "application"
+                && comma.value() == Symbol::Comma =>
+        {
+            Some(name.value().to_owned())
+        }
+        _ => None,
+    }
 }
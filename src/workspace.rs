@@ -0,0 +1,141 @@
+//! Multi-root workspaces.
+#[cfg(feature = "fs")]
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A project root in a [Workspace].
+///
+/// Relative include directories and code paths are resolved against the root path.
+#[derive(Debug, Clone)]
+pub struct ProjectRoot {
+    path: PathBuf,
+    include_dirs: Vec<PathBuf>,
+    code_paths: Vec<PathBuf>,
+}
+impl ProjectRoot {
+    /// Makes a new `ProjectRoot` instance.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        ProjectRoot {
+            path: path.into(),
+            include_dirs: Vec::new(),
+            code_paths: Vec::new(),
+        }
+    }
+
+    /// Returns the path of this root.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns a reference to the include directory list of this root.
+    pub fn include_dirs(&self) -> &[PathBuf] {
+        &self.include_dirs
+    }
+
+    /// Returns a mutable reference to the include directory list of this root.
+    pub fn include_dirs_mut(&mut self) -> &mut Vec<PathBuf> {
+        &mut self.include_dirs
+    }
+
+    /// Returns a reference to the code path list of this root.
+    pub fn code_paths(&self) -> &[PathBuf] {
+        &self.code_paths
+    }
+
+    /// Returns a mutable reference to the code path list of this root.
+    pub fn code_paths_mut(&mut self) -> &mut Vec<PathBuf> {
+        &mut self.code_paths
+    }
+
+    /// Appends the library directories of the rebar3 project layout to the code path list.
+    ///
+    /// The following directories are appended in this order if they exist:
+    ///
+    /// 1. `_checkouts`
+    /// 2. `apps`
+    /// 3. `_build/<PROFILE>/lib` (the `default` profile comes first)
+    /// 4. `deps`
+    ///
+    /// This is available only if the `fs` feature is enabled.
+    #[cfg(feature = "fs")]
+    pub fn add_rebar3_code_paths(&mut self) {
+        let mut dirs = vec![PathBuf::from("_checkouts"), PathBuf::from("apps")];
+        let mut profiles = fs::read_dir(self.path.join("_build"))
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| entry.file_name())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        profiles.sort_by_key(|profile| (profile != "default", profile.clone()));
+        dirs.extend(
+            profiles
+                .into_iter()
+                .map(|profile| Path::new("_build").join(profile).join("lib")),
+        );
+        dirs.push(PathBuf::from("deps"));
+
+        for dir in dirs {
+            if self.path.join(&dir).is_dir() && !self.code_paths.contains(&dir) {
+                self.code_paths.push(dir);
+            }
+        }
+    }
+
+    /// Returns `true` if the given file is located under this root, otherwise `false`.
+    pub fn contains<P: AsRef<Path>>(&self, file: P) -> bool {
+        file.as_ref().starts_with(&self.path)
+    }
+}
+
+/// A set of project roots (e.g., the applications of an umbrella project).
+///
+/// When a file is included, the roots are searched in the following order:
+///
+/// 1. The roots which contain the including file
+/// 2. The other roots, in the order they were added
+#[derive(Debug, Clone, Default)]
+pub struct Workspace {
+    roots: Vec<ProjectRoot>,
+}
+impl Workspace {
+    /// Makes a new empty `Workspace` instance.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a project root to this workspace.
+    pub fn add_root(&mut self, root: ProjectRoot) {
+        self.roots.push(root);
+    }
+
+    /// Returns a reference to the project roots of this workspace.
+    pub fn roots(&self) -> &[ProjectRoot] {
+        &self.roots
+    }
+
+    /// Returns the include directories to be searched when `file` includes a file.
+    pub fn include_dirs(&self, file: Option<&Path>) -> Vec<PathBuf> {
+        self.ordered_roots(file)
+            .flat_map(|r| r.include_dirs.iter().map(move |d| r.path.join(d)))
+            .collect()
+    }
+
+    /// Returns the code paths to be searched when `file` includes a library file.
+    pub fn code_paths(&self, file: Option<&Path>) -> Vec<PathBuf> {
+        self.ordered_roots(file)
+            .flat_map(|r| r.code_paths.iter().map(move |d| r.path.join(d)))
+            .collect()
+    }
+
+    fn ordered_roots<'a>(
+        &'a self,
+        file: Option<&'a Path>,
+    ) -> impl Iterator<Item = &'a ProjectRoot> {
+        let is_owner = move |r: &ProjectRoot| file.is_some_and(|f| r.contains(f));
+        let owners = self.roots.iter().filter(move |r| is_owner(r));
+        let others = self.roots.iter().filter(move |r| !is_owner(r));
+        owners.chain(others)
+    }
+}
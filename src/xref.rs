@@ -0,0 +1,129 @@
+use erl_tokenize::Position;
+use std::path::Path;
+use std::slice;
+
+/// A macro definition and the calls of it observed by a `Preprocessor`.
+#[derive(Debug, Clone)]
+pub struct MacroXrefEntry {
+    /// The name of the macro.
+    pub name: String,
+
+    /// The arity of the macro (`None` for macros defined without parentheses).
+    pub arity: Option<usize>,
+
+    /// The start position of the `-define` directive.
+    ///
+    /// This is `None` if the macro is defined outside of the source
+    /// (e.g., via `Preprocessor::define_from_str()`).
+    pub position: Option<Position>,
+
+    /// The calls of the macro in the order of the expansions.
+    pub calls: Vec<MacroCallSite>,
+}
+impl MacroXrefEntry {
+    /// Returns the path of the file in which the macro is defined.
+    pub fn file(&self) -> Option<&Path> {
+        self.position
+            .as_ref()
+            .and_then(|p| p.filepath())
+            .map(|p| p.as_path())
+    }
+}
+
+/// A call of a macro.
+#[derive(Debug, Clone)]
+pub struct MacroCallSite {
+    /// The start position of the call.
+    ///
+    /// For calls in the bodies of other macros, this points to the macro definition.
+    pub position: Position,
+
+    /// The name of the macro whose body contains the call.
+    ///
+    /// This is `None` if the call appears in the source (or in an argument of a top level call).
+    pub caller: Option<String>,
+}
+
+/// Cross-reference report from the macro definitions to their call sites.
+///
+/// See also: [Preprocessor::set_record_macro_xref()](crate::Preprocessor::set_record_macro_xref).
+#[derive(Debug, Clone, Default)]
+pub struct MacroXref {
+    entries: Vec<MacroXrefEntry>,
+}
+impl MacroXref {
+    /// Makes a new empty `MacroXref` instance.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of the macro definitions.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns `true` if no macro definitions have been recorded, otherwise `false`.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns an iterator over the macro definitions in the order of the first observation.
+    pub fn iter(&self) -> slice::Iter<MacroXrefEntry> {
+        self.entries.iter()
+    }
+
+    /// Returns the definitions of the macros which have the given name.
+    ///
+    /// A macro redefined (or defined with different arities) has multiple entries.
+    pub fn definitions<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> impl 'a + Iterator<Item = &'a MacroXrefEntry> {
+        self.entries.iter().filter(move |e| e.name == name)
+    }
+
+    pub(crate) fn define(&mut self, name: &str, arity: Option<usize>, position: Position) {
+        self.entry(name, arity, Some(position));
+    }
+
+    pub(crate) fn record_call(&mut self, call: ObservedCall) {
+        self.entry(&call.name, call.arity, call.definition)
+            .calls
+            .push(call.site);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    fn entry(
+        &mut self,
+        name: &str,
+        arity: Option<usize>,
+        position: Option<Position>,
+    ) -> &mut MacroXrefEntry {
+        let i = self
+            .entries
+            .iter()
+            .rposition(|e| e.name == name && e.arity == arity && e.position == position);
+        let i = i.unwrap_or_else(|| {
+            self.entries.push(MacroXrefEntry {
+                name: name.to_owned(),
+                arity,
+                position,
+                calls: Vec::new(),
+            });
+            self.entries.len() - 1
+        });
+        &mut self.entries[i]
+    }
+}
+
+/// A macro call observed by a `MacroExpander`.
+#[derive(Debug)]
+pub(crate) struct ObservedCall {
+    pub name: String,
+    pub arity: Option<usize>,
+    pub definition: Option<Position>,
+    pub site: MacroCallSite,
+}
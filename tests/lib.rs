@@ -1,5 +1,11 @@
-use erl_pp::Preprocessor;
-use erl_tokenize::Lexer;
+use erl_pp::workspace::ProjectRoot;
+use erl_pp::{
+    evaluate_condition, IncludeIsolation, IncludeKind, IncludeResolver, MacroDef, MacroEnv,
+    MacroExpander, MemoryIncludes, Options, Preprocessor, Project, RedefinitionPolicy, Severity,
+};
+use erl_tokenize::tokens::AtomToken;
+use erl_tokenize::{Lexer, PositionRange};
+use std::path::{Path, PathBuf};
 
 fn pp(text: &str) -> Preprocessor<Lexer<&str>> {
     let lexer = Lexer::new(text);
@@ -121,6 +127,21 @@ fn error_and_warning_works() {
     );
 }
 
+#[test]
+fn warnings_are_collected() {
+    let src = r#"aaa. -warning("foo"). -ifdef(bar). -warning("bar"). -endif. bbb."#;
+    let mut pp = pp(src);
+    let tokens = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["aaa", ".", "bbb", "."]
+    );
+    assert_eq!(pp.warnings().len(), 1);
+    assert_eq!(pp.warnings()[0].message, "foo");
+    assert_eq!(pp.warnings()[0].position.offset(), 5);
+}
+
 #[test]
 fn include_works() {
     let src = r#"foo.-include("tests/bar.hrl").baz."#;
@@ -132,6 +153,41 @@ fn include_works() {
     );
 }
 
+#[test]
+fn included_files_works() {
+    let mut includes = MemoryIncludes::new();
+    includes.insert("a.hrl", r#"-include("b.hrl")."#);
+    includes.insert("b.hrl", "b.");
+    let src = r#"foo.-include("a.hrl")."#;
+    let mut pp = pp(src);
+    pp.set_include_resolver(includes);
+    let tokens = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<String>(),
+        "foo.b."
+    );
+
+    let files = pp.included_files();
+    assert_eq!(files.len(), 2);
+    assert_eq!(files[0].path, std::path::Path::new("a.hrl"));
+    assert_eq!(files[0].position.offset(), 4);
+    assert_eq!(files[1].path, std::path::Path::new("b.hrl"));
+    assert_eq!(files[1].position.offset(), 0);
+}
+
+#[test]
+fn preprocess_works() {
+    let mut options = Options::new();
+    options.include_paths.push("tests".into());
+    options.defines.push("DEBUG".to_owned());
+    let src = r#"-include("bar.hrl"). -ifdef(DEBUG). debug. -endif."#;
+    let tokens = erl_pp::preprocess(src, &options).unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["bar", ".", "debug", "."]
+    );
+}
+
 #[test]
 fn include_lib_works() {
     let src = r#"foo.-include_lib("tests/bar.hrl").baz."#;
@@ -221,3 +277,1826 @@ fn args_for_expanded_tokens_test() {
         ["-", "module", "(", "prog", ")", "."]
     );
 }
+
+#[test]
+fn add_erl_libs_to_code_paths_works() {
+    std::env::set_var(
+        "ERL_LIBS",
+        std::env::join_paths(&["tests/no_such_dir", "tests/libs"]).unwrap(),
+    );
+    let src = r#"foo.-include_lib("foo/include/foo.hrl").baz."#;
+    let mut pp = pp(src);
+    pp.add_erl_libs_to_code_paths();
+    assert_eq!(pp.code_paths().len(), 2);
+    let tokens = pp.collect::<Result<Vec<_>, _>>().unwrap();
+
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["foo", ".", "foo_hrl", ".", "baz", "."]
+    );
+}
+
+#[test]
+fn add_otp_lib_to_code_paths_works() {
+    std::env::set_var("OTP_ROOT", "tests/otp");
+    let src = r#"foo.-include_lib("kernel/include/logger.hrl").baz."#;
+    let mut pp = pp(src);
+    assert!(pp.add_otp_lib_to_code_paths());
+    let tokens = pp.collect::<Result<Vec<_>, _>>().unwrap();
+
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["foo", ".", "logger_hrl", ".", "baz", "."]
+    );
+}
+
+#[test]
+fn include_path_variables_works() {
+    std::env::set_var("ERL_PP_TEST_DIR", "tests");
+    std::env::set_var("ERL_PP_TEST_NAME", "ba");
+    let src = r#"-include("$ERL_PP_TEST_DIR/bar.hrl").-include("${ERL_PP_TEST_DIR}/bar.hrl").
+                 -include("./$ERL_PP_TEST_DIR/${ERL_PP_TEST_NAME}r.hrl")."#;
+    let tokens = pp(src).collect::<Result<Vec<_>, _>>().unwrap();
+
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["bar", ".", "bar", ".", "bar", "."]
+    );
+}
+
+#[test]
+fn include_path_macros_works() {
+    let src = r#"-define(HEADER, "tests/bar.hrl").
+                 -define(DIR, "tests").
+                 -define(LIB(Name), ?DIR "/" Name).
+                 -include(?HEADER).
+                 -include(?DIR "/bar.hrl").
+                 -include_lib(?LIB("bar.hrl"))."#;
+    let tokens = pp(src).collect::<Result<Vec<_>, _>>().unwrap();
+
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["bar", ".", "bar", ".", "bar", "."]
+    );
+}
+
+#[test]
+fn emit_file_attributes_works() {
+    let src = "foo.\n-include(\"tests/bar.hrl\").\nbaz.";
+    let mut lexer = Lexer::new(src);
+    lexer.set_filepath("main.erl");
+    let mut pp = Preprocessor::new(lexer);
+    pp.set_emit_file_attributes(true);
+    let tokens = pp.collect::<Result<Vec<_>, _>>().unwrap();
+
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        [
+            "foo",
+            ".",
+            "-",
+            "file",
+            "(",
+            r#""tests/bar.hrl""#,
+            ",",
+            "1",
+            ")",
+            ".",
+            "bar",
+            ".",
+            "-",
+            "file",
+            "(",
+            r#""main.erl""#,
+            ",",
+            "2",
+            ")",
+            ".",
+            "baz",
+            "."
+        ]
+    );
+}
+
+#[test]
+fn include_lib_by_app_metadata_works() {
+    let src = r#"foo.-include_lib("foo/include/foo.hrl").baz."#;
+    let mut pp = pp(src);
+    pp.code_paths_mut().push_back("tests/libs".into());
+    let tokens = pp.collect::<Result<Vec<_>, _>>().unwrap();
+
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["foo", ".", "foo_hrl", ".", "baz", "."]
+    );
+}
+
+#[test]
+fn workspace_include_dirs_works() {
+    let src = r#"foo.-include("foo.hrl").baz."#;
+    let mut pp = pp(src);
+    let mut root = ProjectRoot::new("tests/libs/foo_checkout");
+    root.include_dirs_mut().push("include".into());
+    pp.workspace_mut().add_root(root);
+    let tokens = pp.collect::<Result<Vec<_>, _>>().unwrap();
+
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["foo", ".", "foo_hrl", ".", "baz", "."]
+    );
+}
+
+#[test]
+fn include_paths_works() {
+    let src = r#"foo.-include("foo.hrl").baz."#;
+    let mut pp = pp(src);
+    pp.include_paths_mut()
+        .push("tests/libs/foo_checkout/include".into());
+    let tokens = pp.collect::<Result<Vec<_>, _>>().unwrap();
+
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["foo", ".", "foo_hrl", ".", "baz", "."]
+    );
+}
+
+#[test]
+fn rebar3_code_paths_works() {
+    let src = r#"-include_lib("bar/include/bar.hrl").-include_lib("baz/include/baz.hrl")."#;
+    let mut pp = pp(src);
+    let mut root = ProjectRoot::new("tests/rebar3_project");
+    root.add_rebar3_code_paths();
+    assert_eq!(
+        root.code_paths(),
+        [
+            std::path::Path::new("_checkouts"),
+            std::path::Path::new("_build/default/lib")
+        ]
+    );
+    pp.workspace_mut().add_root(root);
+    let tokens = pp.collect::<Result<Vec<_>, _>>().unwrap();
+
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["bar_hrl", ".", "baz_hrl", "."]
+    );
+}
+
+#[test]
+fn include_isolation_works() {
+    let src = r#"-include("tests/leaky.hrl").-ifdef(LEAKY).leaked.-endif.outer."#;
+    let mut pp = pp(src);
+    pp.set_include_isolation(IncludeIsolation::Outward);
+    let tokens = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["inner", ".", "outer", "."]
+    );
+    assert_eq!(
+        pp.leaked_macros()
+            .iter()
+            .map(|m| m.name.as_str())
+            .collect::<Vec<_>>(),
+        ["LEAKY"]
+    );
+}
+
+#[test]
+fn full_include_isolation_keeps_predefined_macros() {
+    let src = r#"-module(foo). -define(OUTER, outer). -include("a.hrl")."#;
+    let mut includes = MemoryIncludes::new();
+    includes.insert(
+        "a.hrl",
+        r#"-if(?OTP_RELEASE >= 23). new. -else. old. -endif.
+           -ifdef(OUTER). ?OUTER. -endif.
+           {?MODULE, ?VSN}."#,
+    );
+    let mut pp = pp(src);
+    pp.set_include_resolver(includes);
+    pp.set_otp_release(26);
+    pp.register_macro_fn("VSN", |call| {
+        Ok(vec![
+            AtomToken::from_value("v1", call.start_position()).into()
+        ])
+    });
+    pp.set_include_isolation(IncludeIsolation::Full);
+    let tokens = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<String>(),
+        "-module(foo).new.{foo,v1}."
+    );
+    assert!(pp.macro_env().is_defined("OUTER"));
+}
+
+#[test]
+fn macros_only_in_skipped_branches_works() {
+    let src = r#"-define(A, a).-ifdef(DEBUG).?A.?B(1).-else.?A.-endif."#;
+    let mut pp = pp(src);
+    let tokens = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["a", "."]
+    );
+    assert_eq!(pp.skipped_macro_refs().len(), 2);
+    assert_eq!(
+        pp.macros_only_in_skipped_branches()
+            .into_iter()
+            .collect::<Vec<_>>(),
+        ["B"]
+    );
+}
+
+#[test]
+fn next_ref_works() {
+    let src = r#"-define(foo, [bar, baz]). ?foo."#;
+    let mut pp = pp(src);
+    let mut texts = Vec::new();
+    while let Some(token) = pp.next_ref() {
+        texts.push(token.unwrap().text().to_owned());
+    }
+    assert_eq!(texts, ["[", "bar", ",", "baz", "]", "."]);
+}
+
+#[test]
+fn emit_directives_works() {
+    let src = r#"-define(foo(A), [A]). ?foo(1). -ifdef(foo). a. -endif."#;
+    let mut pp = pp(src);
+    pp.set_emit_directives(true);
+    let mut tokens = Vec::new();
+    while let Some(token) = pp.next() {
+        tokens.push((token.unwrap().text().to_owned(), pp.in_directive_region()));
+    }
+
+    let directive_texts = tokens
+        .iter()
+        .filter(|t| t.1)
+        .map(|t| t.0.as_str())
+        .collect::<String>();
+    assert_eq!(directive_texts, "-define(foo(A),[A]).-ifdef(foo).-endif.");
+
+    let other_texts = tokens
+        .iter()
+        .filter(|t| !t.1)
+        .map(|t| t.0.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(other_texts, ["[", "1", "]", ".", "a", "."]);
+}
+
+#[test]
+fn execute_includes_works() {
+    let src = r#"-include("no_such_file.hrl"). -include_lib("no_such_app/include/x.hrl"). a."#;
+    let mut pp = pp(src);
+    pp.set_emit_directives(true);
+    pp.set_execute_includes(false);
+    let tokens = pp.collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<String>(),
+        r#"-include("no_such_file.hrl").-include_lib("no_such_app/include/x.hrl").a."#
+    );
+}
+
+#[test]
+fn expand_macros_works() {
+    let src = r#"-define(FOO(A), {A}). foo(?FOO(1), ?UNDEFINED, ?LINE)."#;
+    let mut pp = pp(src);
+    pp.set_expand_macros(false);
+    let tokens = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<String>(),
+        "foo(?FOO(1),?UNDEFINED,?LINE)."
+    );
+    assert_eq!(
+        pp.macro_calls()
+            .values()
+            .map(|m| m.to_string())
+            .collect::<Vec<_>>(),
+        ["?FOO(1)", "?UNDEFINED", "?LINE"]
+    );
+}
+
+#[test]
+fn record_history_works() {
+    let src = r#"-define(FOO, foo). ?FOO."#;
+    let mut pp = pp(src);
+    pp.set_record_history(false);
+    let tokens = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(tokens.iter().map(|t| t.text()).collect::<String>(), "foo.");
+    assert!(pp.directives().is_empty());
+    assert!(pp.macro_calls().is_empty());
+}
+
+#[test]
+fn into_inner_works() {
+    let src = r#"-define(FOO, foo). ?FOO bar baz qux."#;
+    let mut pp = pp(src);
+    assert_eq!(
+        pp.next().map(|t| t.unwrap().text().to_owned()),
+        Some("foo".to_owned())
+    );
+    assert_eq!(
+        pp.next().map(|t| t.unwrap().text().to_owned()),
+        Some("bar".to_owned())
+    );
+
+    let (lexer, unread) = pp.into_inner();
+    let rest = unread
+        .into_iter()
+        .map(Ok)
+        .chain(lexer)
+        .map(|t| t.unwrap().text().to_owned())
+        .collect::<Vec<_>>();
+    assert_eq!(rest, ["baz", "qux", "."]);
+}
+
+#[test]
+fn macro_replacements_are_shared() {
+    let mut env = MacroEnv::new();
+    env.define_from_str("FOO=[1, 2, 3]").unwrap();
+    let cloned = env.clone();
+    match (env.get("FOO", None), cloned.get("FOO", None)) {
+        (Some(MacroDef::Dynamic(r0)), Some(MacroDef::Dynamic(r1))) => {
+            assert!(std::sync::Arc::ptr_eq(r0, r1));
+        }
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn dynamic_macro_with_variables_works() {
+    let mut pp = pp("?PAIR(a, [b]). ?PAIR(?LINE, ?PAIR(c, d)).");
+    let replacement = Lexer::new("{X, Y, ??Y}")
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    pp.macros_mut().insert(
+        "PAIR".to_string(),
+        MacroDef::DynamicWithVariables {
+            variables: vec!["X".to_string(), "Y".to_string()],
+            replacement: replacement.into(),
+        },
+    );
+    let tokens = pp.collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<String>(),
+        r#"{a,[b],"[b]"}.{1,{c,d,"d"},"?PAIR(c,d)"}."#
+    );
+}
+
+#[test]
+fn macro_expander_works() {
+    let mut env = MacroEnv::new();
+    env.define_from_str("FOO=[1]").unwrap();
+    let mut expander = MacroExpander::new(&env);
+    expander.set_function("foo", 2);
+
+    let tokens = Lexer::new("{?FUNCTION_NAME, ?FUNCTION_ARITY, ?FOO}")
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    let expanded = expander.expand(&tokens).unwrap();
+    assert_eq!(
+        expanded.iter().map(|t| t.text()).collect::<String>(),
+        "{foo,2,[1]}"
+    );
+    assert!(MacroExpander::new(&env).expand(&tokens).is_err());
+}
+
+#[test]
+fn preprocessor_expand_call_works() {
+    let src = r#"-define(FOO(X), {X, ?FUNCTION_NAME}). foo() -> ?FOO(1)."#;
+    let mut pp = pp(src);
+    for token in pp.by_ref().take(5) {
+        token.unwrap();
+    }
+    let call = pp.macro_calls().values().next().unwrap().clone();
+    let expanded = pp.expand_call(&call).unwrap();
+    assert_eq!(
+        expanded.iter().map(|t| t.text()).collect::<String>(),
+        "{1,foo}"
+    );
+}
+
+#[test]
+fn snapshot_env_works() {
+    let mut pp = pp("-define(PRELUDE, prelude). -feature(maybe_expr, enable).");
+    pp.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
+    let snapshot = pp.snapshot_env();
+
+    let files = [
+        ("-define(FOO, foo). ?PRELUDE ?FOO.", "prelude foo ."),
+        ("-ifdef(FOO). foo. -endif. ?PRELUDE.", "prelude ."),
+    ];
+    for (src, expected) in files {
+        pp.reset(Lexer::new(src));
+        pp.restore_env(&snapshot);
+        let tokens = pp.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(
+            tokens
+                .iter()
+                .map(|t| t.text())
+                .collect::<Vec<_>>()
+                .join(" "),
+            expected
+        );
+        assert_eq!(pp.features().get("maybe_expr"), Some(&true));
+    }
+}
+
+#[test]
+fn project_works() {
+    let mut project = Project::new();
+    project.include_paths_mut().push("tests".into());
+    project.define_from_str("TEST");
+    project.add_file("tests/project/foo.erl");
+    project.add_file("tests/project/baz.erl");
+    project.add_file("tests/project/no_such_file.erl");
+
+    let report = project.preprocess();
+    assert_eq!(report.files.len(), 2);
+    assert_eq!(
+        report.files[0]
+            .tokens
+            .iter()
+            .map(|t| t.text())
+            .collect::<String>(),
+        "-module(foo).bar."
+    );
+    assert_eq!(report.errors.len(), 1);
+    assert_eq!(report.warnings().count(), 1);
+
+    let deps = report.dependencies();
+    let bar = std::path::Path::new("tests/bar.hrl");
+    assert!(deps[std::path::Path::new("tests/project/foo.erl")].contains(bar));
+    assert!(deps[std::path::Path::new("tests/project/baz.erl")].contains(bar));
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn par_preprocess_files_works() {
+    let mut options = Options::new();
+    options.include_paths.push("tests".into());
+    options.defines.push("TEST".to_owned());
+
+    let paths = [
+        "tests/project/baz.erl",
+        "tests/project/no_such_file.erl",
+        "tests/project/foo.erl",
+    ];
+    let results = erl_pp::par_preprocess_files(&paths, &options);
+    assert_eq!(results.len(), 3);
+    assert_eq!(
+        results[0].as_ref().unwrap().path,
+        Path::new("tests/project/baz.erl")
+    );
+    assert!(results[1].is_err());
+    let foo = results[2].as_ref().unwrap();
+    assert_eq!(
+        foo.tokens.iter().map(|t| t.text()).collect::<String>(),
+        "-module(foo).bar."
+    );
+}
+
+#[test]
+fn error_include_stack_works() {
+    let mut includes = MemoryIncludes::new();
+    includes.insert("a.hrl", "\n\n-include(\"b.hrl\").");
+    includes.insert("b.hrl", "?UNDEFINED.");
+    let mut lexer = Lexer::new("foo.\n-include(\"a.hrl\").");
+    lexer.set_filepath("main.erl");
+    let mut pp = Preprocessor::new(lexer);
+    pp.set_include_resolver(includes);
+
+    let e = pp.collect::<Result<Vec<_>, _>>().err().unwrap();
+    let stack = e
+        .include_stack()
+        .iter()
+        .map(|p| p.line())
+        .collect::<Vec<_>>();
+    assert_eq!(stack, [3, 2]);
+    assert!(e
+        .to_string()
+        .contains(", included from a.hrl:3, included from main.erl:2"));
+}
+
+#[test]
+fn error_recovery_works() {
+    let src = r#"-module(foo).
+                 -define(FOO(X, ), x).
+                 a(?UNDEFINED, ?FOO(1)).
+                 -include("no_such_file.hrl").
+                 -if(?BAR). b. -else. c. -endif.
+                 d."#;
+    let mut pp = pp(src);
+    pp.set_error_recovery(true);
+    let tokens = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<String>(),
+        "-module(foo).a(?UNDEFINED,?FOO(1)).c.d."
+    );
+
+    let errors = pp.recovered_errors();
+    assert_eq!(errors.len(), 5, "{:?}", errors);
+    assert!(matches!(errors[0], erl_pp::Error::UnexpectedToken { .. }));
+    assert!(matches!(errors[1], erl_pp::Error::UndefinedMacro { .. }));
+    assert!(matches!(errors[2], erl_pp::Error::UndefinedMacro { .. }));
+    assert!(matches!(errors[3], erl_pp::Error::IncludeFileError { .. }));
+    assert!(matches!(errors[4], erl_pp::Error::UndefinedMacro { .. }));
+}
+
+#[test]
+fn truncated_directive_is_recovered() {
+    let mut pp = pp("a. -define(FOO, 1");
+    pp.set_error_recovery(true);
+    let tokens = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(tokens.iter().map(|t| t.text()).collect::<String>(), "a.");
+    let errors = pp.recovered_errors();
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(errors[0], erl_pp::Error::UnexpectedEof));
+    assert_eq!(pp.diagnostics().len(), 1);
+}
+
+#[test]
+fn error_span_works() {
+    let src = "foo(?BAR(1, 2)).";
+    let e = pp(src).collect::<Result<Vec<_>, _>>().err().unwrap();
+    let (start, end) = e.span().unwrap();
+    assert_eq!((start.offset(), end.offset()), (4, 8));
+
+    let src = "-define(FOO(X), ??Y). ?FOO(1).";
+    let e = pp(src).collect::<Result<Vec<_>, _>>().err().unwrap();
+    let (start, end) = e.span().unwrap();
+    assert_eq!((start.offset(), end.offset()), (16, 19));
+
+    let src = "-error(\"oops\").";
+    let mut pp = pp(src);
+    pp.set_fail_on_error_directive(true);
+    let e = pp.collect::<Result<Vec<_>, _>>().err().unwrap();
+    let (start, end) = e.span().unwrap();
+    assert_eq!((start.offset(), end.offset()), (0, 15));
+}
+
+#[test]
+fn error_source_line_works() {
+    let src = r#"-include("tests/undefined_macro.hrl")."#;
+    let e = pp(src).collect::<Result<Vec<_>, _>>().err().unwrap();
+    let line = e.source_line().unwrap();
+    assert_eq!(line.text, "    ?UNDEFINED.");
+    assert_eq!(line.column, 5);
+    assert!(e.to_string().ends_with("\n    ?UNDEFINED.\n    ^"));
+}
+
+#[test]
+fn error_source_line_of_main_file_works() {
+    // The text differs from the file on disk, which must not be read.
+    let src = "foo.\n  ?UNDEFINED.";
+    let lexer = || {
+        let mut lexer = Lexer::new(src);
+        lexer.set_filepath("tests/bar.hrl");
+        lexer
+    };
+    let e = Preprocessor::new(lexer())
+        .collect::<Result<Vec<_>, _>>()
+        .err()
+        .unwrap();
+    assert!(e.source_line().is_none());
+
+    let mut pp = Preprocessor::new(lexer());
+    pp.set_source_map(move |path: Option<&Path>| {
+        assert_eq!(path, Some(Path::new("tests/bar.hrl")));
+        Some(src.to_owned())
+    });
+    let e = pp.collect::<Result<Vec<_>, _>>().err().unwrap();
+    let line = e.source_line().unwrap();
+    assert_eq!(line.text, "  ?UNDEFINED.");
+    assert_eq!(line.column, 3);
+}
+
+#[test]
+fn reset_works() {
+    let mut pp = pp(r#"-define(foo, a). ?foo. ?bar."#);
+    pp.macros_mut()
+        .insert("bar".to_string(), MacroDef::Dynamic(Vec::new().into()));
+    let tokens = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["a", ".", "."]
+    );
+
+    pp.reset(Lexer::new(r#"?bar. ?foo."#));
+    assert!(pp.macros().contains_key("bar"));
+    assert!(!pp.macros().contains_key("foo"));
+    assert!(pp.directives().is_empty());
+    assert!((&mut pp).collect::<Result<Vec<_>, _>>().is_err());
+}
+
+#[test]
+fn never_defined_conditional_macros_works() {
+    let src = r#"-ifdef(DEUBG).-define(FOO, 1).-endif.-ifndef(FOO).-endif.
+                 -ifdef(BAR).-endif.-ifdef(LINE).-endif.-ifdef(BAZ).-endif."#;
+    let mut pp = pp(src);
+    pp.macros_mut()
+        .insert("BAR".to_string(), MacroDef::Dynamic(Vec::new().into()));
+    let _ = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        pp.never_defined_conditional_macros(|name| name == "BAZ")
+            .iter()
+            .map(|m| m.value())
+            .collect::<Vec<_>>(),
+        ["DEUBG"]
+    );
+}
+
+#[test]
+fn evaluate_condition_works() {
+    let mut pp = pp(r#"-define(RELEASE, 26). -define(MAX(A, B), (if A > B -> A; true -> B end))."#);
+    let _ = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+    let env = MacroEnv::from(pp.macros().clone());
+
+    assert!(evaluate_condition("?RELEASE >= 23", &env).unwrap());
+    assert!(evaluate_condition("?RELEASE - 20 * 2 < -10 orelse 1 div 0", &env).unwrap());
+    assert!(evaluate_condition("not defined(DEBUG) and (defined(MAX) xor false)", &env).unwrap());
+    assert!(evaluate_condition("foo < \"bar\" andalso 1 < foo", &env).unwrap());
+    assert!(!evaluate_condition("(7 rem 4) bsl 2 =/= 12", &env).unwrap());
+    assert!(evaluate_condition("1 + 2", &env).is_err());
+    assert!(evaluate_condition("1 < 2 < 3", &env).is_err());
+    assert!(evaluate_condition("?UNDEFINED", &env).is_err());
+}
+
+#[test]
+fn macro_env_works() {
+    let mut pp = pp(r#"-define(foo, a). -ifdef(LINE). ?foo. -endif."#);
+    let tokens = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["a", "."]
+    );
+
+    let mut env = pp.macro_env().clone();
+    assert!(env.is_defined("foo"));
+    assert_eq!(env.undef("foo").len(), 1);
+    assert!(!env.is_defined("foo"));
+    assert!(env.is_defined("MACHINE"));
+}
+
+#[test]
+fn cross_file_conditionals_works() {
+    let src = r#"-define(foo, 1). -include("tests/open_ifdef.hrl"). a. -endif. b."#;
+    let mut pp = pp(src);
+    pp.set_warn_cross_file_conditionals(true);
+    let tokens = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["opened", ".", "a", ".", "b", "."]
+    );
+
+    let conditionals = pp.cross_file_conditionals();
+    assert_eq!(conditionals.len(), 1);
+    assert!(conditionals[0]
+        .open
+        .filepath()
+        .is_some_and(|f| f.ends_with("open_ifdef.hrl")));
+    assert_eq!(conditionals[0].close.filepath(), None);
+}
+
+#[test]
+fn if_works() {
+    let src = r#"-define(RELEASE, 26).
+                 -if(?RELEASE >= 27). a. -elif(?RELEASE >= 25). b. -elif(true). c. -else. d. -endif.
+                 -if(false). -if(?UNDEFINED). x. -endif. -else. e. -endif.
+                 -if(defined(RELEASE) andalso not defined(DEBUG)). f. -endif."#;
+    let tokens = pp(src).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["b", ".", "e", ".", "f", "."]
+    );
+
+    assert!(pp("-if(1). a. -endif.")
+        .collect::<Result<Vec<_>, _>>()
+        .is_err());
+    assert!(pp("-if(true). -else. -elif(true). -endif.")
+        .collect::<Result<Vec<_>, _>>()
+        .is_err());
+}
+
+#[test]
+fn feature_works() {
+    let src = r#"-module(foo). -feature(maybe_expr, enable). -feature(foo, disable). a."#;
+    let mut pp = pp(src);
+    let tokens = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<String>(),
+        "-module(foo).-feature(maybe_expr,enable).-feature(foo,disable).a."
+    );
+    assert_eq!(pp.features().get("maybe_expr"), Some(&true));
+    assert_eq!(pp.features().get("foo"), Some(&false));
+}
+
+#[test]
+fn otp_release_works() {
+    let src = r#"-ifdef(OTP_RELEASE). -if(?OTP_RELEASE >= 23). new. -else. old. -endif. -else. unknown. -endif."#;
+    let tokens = pp(src).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(tokens[0].text(), "unknown");
+
+    let mut pp = pp(src);
+    pp.set_otp_release(26);
+    let tokens = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(tokens[0].text(), "new");
+    assert_eq!(pp.otp_release(), Some(26));
+}
+
+#[test]
+fn otp_release_must_be_set_before_use() {
+    let mut pp = pp("-if(?OTP_RELEASE >= 23). new. -endif.");
+    assert_eq!(pp.otp_release(), None);
+    match pp.next() {
+        Some(Err(erl_pp::Error::UndefinedMacro { .. })) => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn function_name_and_arity_works() {
+    let src = r#"foo(A, {B, C}) -> {?FUNCTION_NAME, ?FUNCTION_ARITY}; foo(_, _) -> ?FUNCTION_ARITY.
+                 bar() -> ?FUNCTION_ARITY."#;
+    let tokens = pp(src).collect::<Result<Vec<_>, _>>().unwrap();
+    let texts = tokens.iter().map(|t| t.text()).collect::<String>();
+    assert_eq!(texts, "foo(A,{B,C})->{foo,2};foo(_,_)->2.bar()->0.");
+
+    assert!(pp("a. ?FUNCTION_NAME.")
+        .collect::<Result<Vec<_>, _>>()
+        .is_err());
+}
+
+#[test]
+fn feature_macros_works() {
+    let src = r#"-feature(maybe_expr, enable).
+                 {?FEATURE_AVAILABLE(maybe_expr), ?FEATURE_AVAILABLE(foo),
+                  ?FEATURE_ENABLED(maybe_expr), ?FEATURE_ENABLED(foo)}."#;
+    assert!(pp("?FEATURE_ENABLED(1, 2).")
+        .collect::<Result<Vec<_>, _>>()
+        .is_err());
+
+    let mut pp = pp(src);
+    pp.available_features_mut().insert("maybe_expr".to_owned());
+    let tokens = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().skip(8).map(|t| t.text()).collect::<String>(),
+        "{true,false,true,false}."
+    );
+}
+
+#[test]
+fn macro_overloading_works() {
+    let src = r#"-define(FOO, a). -define(FOO(X), {b, X}). -define(FOO(X, Y), {c, X, Y}).
+                 ?FOO. ?FOO(1). ?FOO(1, 2). ?FOO(1, 2, 3).
+                 -undef(FOO). -ifdef(FOO). defined. -endif."#;
+    let tokens = pp(src).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<String>(),
+        "a.{b,1}.{c,1,2}.a(1,2,3)."
+    );
+
+    let src = r#"-define(BAR(X), X). ?BAR(1, 2)."#;
+    assert!(pp(src).collect::<Result<Vec<_>, _>>().is_err());
+}
+
+#[test]
+fn redefinition_policy_works() {
+    let src = r#"-define(FOO, a). -define(FOO(X), X). -define(FOO, b). ?FOO."#;
+    let tokens = pp(src).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(tokens[0].text(), "b");
+
+    let mut pp = pp(src);
+    pp.set_redefinition_policy(RedefinitionPolicy::Error);
+    assert!((&mut pp).collect::<Result<Vec<_>, _>>().is_err());
+
+    let mut pp = self::pp(src);
+    pp.set_redefinition_policy(RedefinitionPolicy::Warn);
+    let tokens = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(tokens[0].text(), "b");
+    let redefinitions = pp.macro_redefinitions();
+    assert_eq!(redefinitions.len(), 1);
+    assert_eq!(redefinitions[0].name, "FOO");
+    assert_eq!(redefinitions[0].old_position.as_ref().unwrap().offset(), 0);
+    assert_eq!(redefinitions[0].new_position.offset(), 37);
+}
+
+#[test]
+fn module_macros_are_defined_by_module_attribute() {
+    let src = r#"-ifdef(MODULE). defined. -endif.
+-module(foo).
+f() -> {?MODULE, ?MODULE_STRING}."#;
+    let tokens = pp(src).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<String>(),
+        r#"-module(foo).f()->{foo,"foo"}."#
+    );
+}
+
+#[test]
+fn reset_forgets_partially_read_module_attribute() {
+    let mut pp = pp("-module(foo).");
+    let tokens = (&mut pp).take(3).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(tokens.len(), 3);
+
+    pp.reset(Lexer::new("bar). ?MODULE."));
+    let result = pp.collect::<Result<Vec<_>, _>>();
+    assert!(matches!(result, Err(erl_pp::Error::UndefinedMacro { .. })));
+}
+
+#[test]
+fn predefined_macro_directive_works() {
+    for src in &[
+        "-define(LINE, 1).",
+        "-undef(FILE).",
+        "-define(MODULE(), foo).",
+    ] {
+        match pp(src).collect::<Result<Vec<_>, _>>() {
+            Err(erl_pp::Error::PredefinedMacroDirective { position, .. }) => {
+                assert_eq!(position.offset(), 0)
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    let src = r#"-ifdef(UNDEFINED). -define(LINE, 1). -endif. ?LINE."#;
+    assert!(pp(src).collect::<Result<Vec<_>, _>>().is_ok());
+}
+
+#[test]
+fn overloaded_macro_is_not_recursive() {
+    let src = r#"-define(FOO(X), {X, ?FOO}). -define(FOO, foo). ?FOO(1)."#;
+    let tokens = pp(src).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<String>(),
+        "{1,foo}."
+    );
+
+    let src = r#"-define(FOO(X), ?FOO(X)). ?FOO(1)."#;
+    assert!(pp(src).collect::<Result<Vec<_>, _>>().is_err());
+}
+
+#[test]
+fn recursive_macro_works() {
+    let src = r#"-define(A, ?B). -define(B, [?A]). ?A."#;
+    match pp(src).collect::<Result<Vec<_>, _>>() {
+        Err(erl_pp::Error::RecursiveMacro { macro_call, .. }) => {
+            assert_eq!(macro_call.name.value(), "A")
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    let src = r#"-define(F(X), {X}). ?F(?F(1))."#;
+    let tokens = pp(src).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<String>(),
+        "{{1}}."
+    );
+
+    let src = r#"-define(A, ?B). -define(B, ?C). -define(C, c). ?A."#;
+    let mut pp = pp(src);
+    pp.set_max_expansion_depth(2);
+    match pp.collect::<Result<Vec<_>, _>>() {
+        Err(erl_pp::Error::MacroExpansionTooDeep { max_depth, .. }) => assert_eq!(max_depth, 2),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn fail_on_error_directive_works() {
+    let src = r#"aaa. -ifdef(foo). -error("skipped"). -endif. -error("foo"). bbb."#;
+    let mut pp = pp(src);
+    pp.set_fail_on_error_directive(true);
+    match pp.collect::<Result<Vec<_>, _>>() {
+        Err(erl_pp::Error::ErrorDirective {
+            message, position, ..
+        }) => {
+            assert_eq!(message, "foo");
+            assert_eq!(position.offset(), 45);
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn skipped_regions_works() {
+    let src =
+        r#"-ifdef(foo). aaa. -else. bbb. -endif. -ifndef(bar). -ifdef(baz). ccc. -endif. -endif."#;
+    let mut pp = pp(src);
+    let tokens = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["bbb", "."]
+    );
+
+    let regions = pp
+        .skipped_regions()
+        .iter()
+        .map(|r| {
+            (
+                r.directive.offset(),
+                r.start.offset(),
+                r.end.offset(),
+                &src[r.start.offset()..r.end.offset()],
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(regions, [(0, 12, 18, " aaa. "), (52, 64, 70, " ccc. ")]);
+}
+
+#[test]
+fn define_from_str_works() {
+    let src = r#"-ifdef(DEBUG). {?VSN, ?'Level'}. -endif."#;
+    let mut pp = pp(src);
+    pp.define_from_str("DEBUG").unwrap();
+    pp.define_from_str(r#"VSN="1.2.3""#).unwrap();
+    pp.define_from_str("'Level'=1 + 2").unwrap();
+    assert!(pp.define_from_str("=foo").is_err());
+    assert!(pp.define_from_str("foo bar").is_err());
+    let tokens = pp.collect::<Result<Vec<_>, _>>().unwrap();
+
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["{", r#""1.2.3""#, ",", "1", "+", "2", "}", "."]
+    );
+}
+
+#[test]
+fn register_macro_fn_works() {
+    let src = r#"-ifdef(GIT_SHA). {?GIT_SHA, ?GIT_SHA(short), ?LINE}. -endif."#;
+    let mut pp = pp(src);
+    pp.register_macro_fn("GIT_SHA", |call| {
+        let sha = if call.args.is_some() { "abc" } else { "abcdef" };
+        Ok(vec![
+            AtomToken::from_value(sha, call.start_position()).into()
+        ])
+    });
+    let tokens = pp.collect::<Result<Vec<_>, _>>().unwrap();
+
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["{", "abcdef", ",", "abc", ",", "1", "}", "."]
+    );
+}
+
+#[test]
+fn include_resolver_works() {
+    #[derive(Debug)]
+    struct Resolver;
+    impl IncludeResolver for Resolver {
+        fn resolve(
+            &self,
+            kind: IncludeKind,
+            path: &Path,
+            search_dirs: &[PathBuf],
+        ) -> std::io::Result<(PathBuf, String)> {
+            match (kind, path.to_str()) {
+                (IncludeKind::Include, Some("virtual.hrl")) => {
+                    assert_eq!(search_dirs, [PathBuf::from("include")]);
+                    Ok((path.to_path_buf(), "virtual.".to_owned()))
+                }
+                _ => Err(std::io::ErrorKind::NotFound.into()),
+            }
+        }
+    }
+
+    let src = r#"-include("virtual.hrl")."#;
+    let mut pp = pp(src);
+    pp.include_paths_mut().push("include".into());
+    pp.set_include_resolver(Resolver);
+    let tokens = pp.collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["virtual", "."]
+    );
+
+    let src = r#"-include_lib("virtual.hrl")."#;
+    let mut pp = crate::pp(src);
+    pp.set_include_resolver(Resolver);
+    assert!(pp.collect::<Result<Vec<_>, _>>().is_err());
+}
+
+#[test]
+fn memory_includes_works() {
+    let mut includes = MemoryIncludes::new();
+    includes.insert("include/foo.hrl", "foo.");
+    includes.insert("libs/bar-1.0/include/bar.hrl", "bar.");
+
+    let src = r#"-include("foo.hrl").-include_lib("bar/include/bar.hrl")."#;
+    let mut pp = pp(src);
+    pp.include_paths_mut().push("include".into());
+    pp.code_paths_mut().push_back("libs".into());
+    pp.set_include_resolver(includes);
+    let tokens = pp.collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["foo", ".", "bar", "."]
+    );
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_preprocessor_works() {
+    use erl_pp::{AsyncIncludeResolver, AsyncPreprocessor, ResolveFuture};
+    use futures_core::Stream;
+    use std::pin::Pin;
+    use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+    struct Resolver;
+    impl AsyncIncludeResolver for Resolver {
+        fn resolve(
+            &self,
+            _kind: IncludeKind,
+            path: PathBuf,
+            _search_dirs: Vec<PathBuf>,
+        ) -> ResolveFuture {
+            Box::pin(async move {
+                let text = format!("{}.", path.file_stem().unwrap().to_str().unwrap());
+                Ok((path, text))
+            })
+        }
+    }
+
+    fn noop_waker() -> Waker {
+        fn clone(_: *const ()) -> RawWaker {
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+        fn noop(_: *const ()) {}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+        unsafe { Waker::from_raw(RawWaker::new(std::ptr::null(), &VTABLE)) }
+    }
+
+    let src = r#"aaa. -include("bbb.hrl"). -include_lib("ccc/include/ccc.hrl"). ddd."#;
+    let mut stream = AsyncPreprocessor::new(pp(src), Resolver);
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut tokens = Vec::new();
+    loop {
+        match Pin::new(&mut stream).poll_next(&mut cx) {
+            Poll::Ready(Some(token)) => tokens.push(token.unwrap()),
+            Poll::Ready(None) => break,
+            Poll::Pending => {}
+        }
+    }
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["aaa", ".", "bbb", ".", "ccc", ".", "ddd", "."]
+    );
+}
+
+#[test]
+fn include_same_file_repeatedly_works() {
+    let src = r#"-include("tests/bar.hrl").-include("tests/bar.hrl").-include("tests/bar.hrl")."#;
+    let tokens = pp(src).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["bar", ".", "bar", ".", "bar", "."]
+    );
+    for token in &tokens {
+        let position = token.start_position();
+        assert_eq!(position.filepath(), Some(&PathBuf::from("tests/bar.hrl")));
+    }
+}
+
+#[test]
+fn observer_works() {
+    use erl_pp::{Directive, ExpansionObserver, MacroCall};
+    use erl_tokenize::LexicalToken;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, Default)]
+    struct Observer(Arc<Mutex<Vec<String>>>);
+    impl ExpansionObserver for Observer {
+        fn on_directive(&mut self, directive: &Directive) {
+            self.0.lock().unwrap().push(directive.to_string());
+        }
+        fn on_macro_expanded(&mut self, call: &MacroCall, output: &[LexicalToken]) {
+            let output = output.iter().map(|t| t.text()).collect::<String>();
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{} => {}", call, output));
+        }
+        fn on_include_start(&mut self, path: &Path) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("start {}", path.display()));
+        }
+        fn on_include_end(&mut self, path: &Path) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("end {}", path.display()));
+        }
+    }
+
+    let src = r#"-define(FOO(X), [X]). ?FOO(1). -include("tests/bar.hrl"). baz."#;
+    let observer = Observer::default();
+    let events = Arc::clone(&observer.0);
+    let mut pp = pp(src);
+    pp.set_observer(observer);
+    pp.collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        *events.lock().unwrap(),
+        [
+            "-define(FOO(X), [X]).",
+            "?FOO(1) => [1]",
+            r#"-include("tests/bar.hrl")."#,
+            "start tests/bar.hrl",
+            "end tests/bar.hrl"
+        ]
+    );
+}
+
+#[test]
+fn error_code_works() {
+    let error = pp("?FOO.").find_map(|t| t.err()).unwrap();
+    assert_eq!(error.code(), "EPP0007");
+
+    let error = pp("-endif.").find_map(|t| t.err()).unwrap();
+    assert_eq!(error.code(), "EPP0012");
+}
+
+#[test]
+fn display_with_source_works() {
+    let mut includes = MemoryIncludes::new();
+    includes.insert("a.hrl", "foo.\n  ?BAR(1,\n 2).");
+    let mut pp = pp(r#"-include("a.hrl")."#);
+    pp.set_include_resolver(includes.clone());
+    let error = pp.find_map(|t| t.err()).unwrap();
+
+    let display = error.display_with_source(&includes).to_string();
+    assert!(display.starts_with("undefined macro: "));
+    assert_eq!(
+        display.lines().skip(1).collect::<Vec<_>>(),
+        [
+            " --> a.hrl:2:3",
+            "  ?BAR(1,",
+            "  ^^^^",
+            " included from unknown:1"
+        ]
+    );
+}
+
+#[test]
+fn diagnostics_works() {
+    let src = r#"-define(A, 1). -define(A, 2). -warning("w"). -error("e"). -endif. ok."#;
+    let mut pp = pp(src);
+    pp.set_redefinition_policy(RedefinitionPolicy::Warn);
+    pp.set_error_recovery(true);
+    let tokens = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(tokens.len(), 2);
+
+    let diagnostics = pp.diagnostics();
+    assert_eq!(
+        diagnostics
+            .iter()
+            .map(|d| (d.severity, d.code))
+            .collect::<Vec<_>>(),
+        [
+            (Severity::Warning, "EPP1002"),
+            (Severity::Warning, "EPP1001"),
+            (Severity::Error, "EPP0017"),
+            (Severity::Error, "EPP0012"),
+        ]
+    );
+    assert_eq!(diagnostics[1].message, "w");
+    let (start, end) = diagnostics[0].span.clone().unwrap();
+    assert_eq!((start.offset(), end.offset()), (15, 29));
+}
+
+#[test]
+fn undefined_macro_placeholder_works() {
+    let src = r#"-define(A, ?B). foo(?A, ?C(1))."#;
+    let mut pp = pp(src);
+    pp.set_undefined_macro_placeholder(Some("undefined".to_owned()));
+    let tokens = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<String>(),
+        "foo(undefined,undefined(1))."
+    );
+
+    let diagnostics = pp.diagnostics();
+    assert_eq!(diagnostics.len(), 2);
+    assert!(diagnostics.iter().all(|d| d.code == "EPP1005"));
+    assert_eq!(diagnostics[1].span.as_ref().unwrap().0.offset(), 24);
+}
+
+#[test]
+fn preprocessed_text_works() {
+    let mut includes = MemoryIncludes::new();
+    includes.insert("a.hrl", "%% header\n-define(A(X), {X, X}).\na() -> ok.\n");
+    let src = r#"-include("a.hrl").
+-ifdef(B).
+b() -> ?B.
+-endif.
+%% main
+foo(X) ->
+    ?A(X).
+"#;
+    let mut pp = pp(src);
+    pp.set_include_resolver(includes);
+    let text = pp.preprocessed_text(src).unwrap();
+    assert_eq!(
+        text,
+        "%% header\n\na() -> ok.\n\n\n\n%% main\nfoo(X) ->\n    { X , X }.\n"
+    );
+}
+
+#[test]
+fn passed_through_tokens_are_not_expanded() {
+    let src = "-feature(maybe_expr,\tenable). % comment\nfoo() ->\n    ?BAR.";
+    let mut preprocessor = pp(src);
+    preprocessor.set_expand_macros(false);
+    while let Some(token) = preprocessor.next() {
+        token.unwrap();
+        assert!(!preprocessor.in_expansion_region());
+    }
+
+    let mut preprocessor = pp(src);
+    preprocessor.set_expand_macros(false);
+    let text = preprocessor.preprocessed_text(src).unwrap();
+    assert_eq!(text, src);
+}
+
+#[test]
+fn record_origins_works() {
+    let src = "-define(FOO, {1, 2}).\nfoo() -> ?FOO.";
+    let mut pp = pp(src);
+    pp.set_record_origins(true);
+    let tokens = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+
+    let origins = pp.origins();
+    assert_eq!(origins.len(), tokens.len());
+    assert!(!origins.get(0).unwrap().is_expanded());
+    assert_eq!(origins.get(0).unwrap().start.offset(), 22);
+
+    let one = origins.get(5).unwrap();
+    assert_eq!(tokens[5].text(), "1");
+    assert!(one.is_expanded());
+    assert_eq!(one.start.offset(), 14);
+    let (start, end) = one.source_span();
+    assert_eq!((start.offset(), end.offset()), (31, 35));
+
+    let position = tokens[5].start_position();
+    assert_eq!(origins.output_indices_at(&position).count(), 0);
+    let call = one.source_span().0.clone();
+    assert_eq!(
+        origins.output_indices_at(&call).collect::<Vec<_>>(),
+        [4, 5, 6, 7, 8]
+    );
+}
+
+#[test]
+fn token_provenance_works() {
+    let mut includes = MemoryIncludes::new();
+    includes.insert(
+        "a.hrl",
+        "-define(A(X), [X, ?B]).\n-define(B, b).\nfoo() -> ?A(x).",
+    );
+    let mut pp = pp(r#"-include("a.hrl")."#);
+    pp.set_include_resolver(includes);
+    pp.set_record_origins(true);
+    let tokens = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<String>(),
+        "foo()->[x,b]."
+    );
+
+    let chain = |i: usize| {
+        pp.origins()
+            .get(i)
+            .unwrap()
+            .macro_chain
+            .iter()
+            .map(|f| f.name.clone())
+            .collect::<Vec<_>>()
+    };
+    assert!(chain(0).is_empty());
+    assert_eq!(chain(4), ["A"]);
+    assert_eq!(chain(5), ["A"]);
+    assert_eq!(chain(7), ["A", "B"]);
+
+    let origin = pp.origins().get(7).unwrap();
+    assert_eq!(origin.macro_chain[1].position.offset(), 18);
+    assert_eq!(origin.include_chain.len(), 1);
+    assert_eq!(origin.include_chain[0].offset(), 0);
+}
+
+#[cfg(feature = "codespan")]
+#[test]
+fn codespan_diagnostic_works() {
+    use erl_pp::codespan::SourceFiles;
+
+    let mut includes = MemoryIncludes::new();
+    includes.insert("a.hrl", "foo.\n?BAR.");
+    let src = r#"-include("a.hrl")."#;
+    let mut pp = pp(src);
+    pp.set_include_resolver(includes);
+    let error = pp.find_map(|t| t.err()).unwrap();
+
+    let mut files = SourceFiles::new();
+    let main = files.add(None, src);
+    let included = files.add(Some(Path::new("a.hrl")), "foo.\n?BAR.");
+
+    let diagnostic = error.to_diagnostic(&files);
+    assert!(diagnostic.message.starts_with("undefined macro"));
+    assert!(!diagnostic.message.contains("included from"));
+    assert_eq!(diagnostic.code.as_deref(), Some("EPP0007"));
+    assert_eq!(diagnostic.labels.len(), 2);
+    assert_eq!(diagnostic.labels[0].file_id, included);
+    assert_eq!(diagnostic.labels[0].range, 5..9);
+    assert_eq!(diagnostic.labels[1].file_id, main);
+    assert_eq!(diagnostic.labels[1].range, 0..0);
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn json_diagnostics_works() {
+    use erl_pp::json;
+
+    let mut includes = MemoryIncludes::new();
+    includes.insert("a.hrl", "-warning(\"w\").\n?BAR.");
+    let mut pp = pp(r#"-include("a.hrl")."#);
+    pp.set_include_resolver(includes);
+    let error = pp.find_map(|t| t.err()).unwrap();
+
+    let mut diagnostics = vec![json::Diagnostic::from_error(&error)];
+    diagnostics.extend(pp.warnings().iter().map(json::Diagnostic::from_warning));
+    let json = json::to_string(&diagnostics);
+    assert!(json.contains(
+        r#""span":{"start":{"file":"a.hrl","line":2,"column":1,"offset":15},"end":{"file":"a.hrl","line":2,"column":5,"offset":19}}"#
+    ));
+    assert!(json.contains(r#""included_from":[{"file":null,"line":1,"column":1,"offset":0}]"#));
+    assert!(json.contains(r#"{"severity":"warning","code":"EPP1001","message":"w","#));
+}
+
+#[cfg(all(feature = "serde", feature = "json"))]
+#[test]
+fn serde_works() {
+    let src = "%% comment\n-define(FOO(A), {A, \"bar\"}).\n?FOO(1).";
+    let mut pp = pp(src);
+    let error = pp.find_map(|t| t.err());
+    assert!(error.is_none());
+
+    let def = pp.macros().get("FOO", Some(1)).unwrap();
+    let json = serde_json::to_string(def).unwrap();
+    assert!(
+        json.starts_with(r#"{"Static":[{"text":"-","file":null,"line":2,"column":1,"offset":11},"#)
+    );
+    let restored: MacroDef = serde_json::from_str(&json).unwrap();
+    match (def, &restored) {
+        (MacroDef::Static(d0), MacroDef::Static(d1)) => {
+            assert_eq!(d0.to_string(), d1.to_string());
+            assert_eq!(
+                d0.replacement[1].start_position(),
+                d1.replacement[1].start_position()
+            );
+        }
+        _ => panic!(),
+    }
+
+    let error = crate::pp("?FOO.").next().unwrap().unwrap_err();
+    let json = serde_json::to_string(&error).unwrap();
+    assert!(json.starts_with(r#"{"code":"EPP0007","message":"#));
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn json_tokens_works() {
+    use erl_pp::json;
+
+    let mut lexer = Lexer::new("-define(FOO(X), X).\nfoo(A) -> ?FOO('a b').");
+    lexer.set_filepath("a.erl");
+    let tokens = Preprocessor::new(lexer)
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    let mut buf = Vec::new();
+    json::tokens_to_ndjson(&mut buf, &tokens).unwrap();
+    let ndjson = String::from_utf8(buf).unwrap();
+    let lines = ndjson.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 7);
+    assert_eq!(
+        lines[0],
+        r#"{"kind":"atom","text":"foo","file":"a.erl","line":2,"column":1}"#
+    );
+    assert_eq!(
+        lines[2],
+        r#"{"kind":"variable","text":"A","file":"a.erl","line":2,"column":5}"#
+    );
+    assert!(lines[5].starts_with(r#"{"kind":"atom","text":"'a b'","#));
+}
+
+#[test]
+fn include_graph_dot_works() {
+    let mut includes = MemoryIncludes::new();
+    includes.insert("a.hrl", "-include(\"b.hrl\").");
+    includes.insert("b.hrl", "");
+    let mut lexer = Lexer::new("-include(\"a.hrl\").\n-include_lib(\"b.hrl\").");
+    lexer.set_filepath("m.erl");
+    let mut pp = Preprocessor::new(lexer);
+    pp.set_include_resolver(includes);
+    let _ = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+
+    let dot = pp.include_graph_dot();
+    assert!(dot.contains("n0 [label=\"m.erl\"];"));
+    assert!(dot.contains("n0 -> n1 [label=\"include 1:1\"];"));
+    assert!(dot.contains("n1 -> n2 [label=\"include 1:1\"];"));
+    assert!(dot.contains("n0 -> n2 [label=\"include_lib 2:1\"];"));
+}
+
+#[test]
+fn makefile_deps_works() {
+    let mut includes = MemoryIncludes::new();
+    includes.insert("a b.hrl", "-include(\"c.hrl\").");
+    includes.insert("c.hrl", "");
+    let mut lexer = Lexer::new("-include(\"a b.hrl\").\n-include(\"c.hrl\").");
+    lexer.set_filepath("src/m.erl");
+    let mut pp = Preprocessor::new(lexer);
+    pp.set_include_resolver(includes);
+    let _ = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        pp.makefile_deps("ebin/m.beam"),
+        "ebin/m.beam: src/m.erl a\\ b.hrl c.hrl\n"
+    );
+}
+
+#[test]
+fn content_hash_works() {
+    let src = "-include(\"a.hrl\").\n?FOO.";
+    let hash = |hrl: &str, definition: &str| {
+        let mut includes = MemoryIncludes::new();
+        includes.insert("a.hrl", hrl);
+        let mut pp = crate::pp(src);
+        pp.set_include_resolver(includes);
+        pp.define_from_str(definition).unwrap();
+        let _ = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+        pp.content_hash(src)
+    };
+    let h0 = hash("-define(FOO, 1).", "BAR=1");
+    assert_eq!(h0, hash("-define(FOO, 1).", "BAR=1"));
+    assert_ne!(h0, hash("-define(FOO, 2).", "BAR=1"));
+    assert_ne!(h0, hash("-define(FOO, 1).", "BAR=2"));
+}
+
+#[test]
+fn macro_xref_works() {
+    let src = "-define(A, 1).\n-define(B(X), {X, ?A}).\nfoo() -> ?B(?A).\n-define(C, unused).";
+    let mut pp = pp(src);
+    pp.set_record_macro_xref(true);
+    pp.define_from_str("D").unwrap();
+    let _ = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+
+    let xref = pp.macro_xref();
+    assert_eq!(xref.len(), 3);
+
+    let a = xref.definitions("A").next().unwrap();
+    assert_eq!(a.arity, None);
+    assert_eq!(a.position.as_ref().map(|p| p.line()), Some(1));
+    let calls = a
+        .calls
+        .iter()
+        .map(|c| (c.position.line(), c.caller.as_deref()))
+        .collect::<Vec<_>>();
+    assert_eq!(calls, [(3, None), (2, Some("B"))]);
+
+    let b = xref.definitions("B").next().unwrap();
+    assert_eq!(b.arity, Some(1));
+    assert_eq!(b.calls.len(), 1);
+
+    let c = xref.definitions("C").next().unwrap();
+    assert!(c.calls.is_empty());
+}
+
+#[test]
+fn conditional_flags_works() {
+    let src = r#"
+-ifdef(DEBUG).
+-ifndef(TEST).
+-endif.
+-endif.
+-if(defined(OTP_21) orelse not defined('Prod')).
+-elif(?OTP_RELEASE >= 21 andalso defined(Trace)).
+-endif."#;
+    let mut pp = pp(src);
+    let _ = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        pp.conditional_flags().into_iter().collect::<Vec<_>>(),
+        ["DEBUG", "OTP_21", "Prod", "TEST", "Trace"]
+    );
+}
+
+#[test]
+fn dump_macros_as_hrl_works() {
+    let src = "-define('foo bar', ?MODULE_STRING ++ \"x\").\n-define(F(A), ??A).";
+    let mut pp = pp(src);
+    let _ = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+    let hrl = pp.dump_macros_as_hrl();
+    assert_eq!(
+        hrl,
+        "-define(F(A), ??A).\n-define('foo bar', ?MODULE_STRING ++ \"x\").\n"
+    );
+
+    // The rendered text defines the same macros.
+    let mut pp = crate::pp(&hrl);
+    let _ = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(pp.dump_macros_as_hrl(), hrl);
+}
+
+#[cfg(feature = "config")]
+#[test]
+fn config_works() {
+    use erl_pp::config::Config;
+
+    let dir = std::env::temp_dir().join(format!("erl_pp_config_test_{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("include")).unwrap();
+    std::fs::write(dir.join("include/a.hrl"), "-define(A, a).").unwrap();
+    std::fs::write(
+        dir.join("pp.toml"),
+        "code_paths = [\"/libs\"]\n[erl_opts]\nd = { DEBUG = true, LEVEL = 3, VSN = '\"1.0\"' }\ni = [\"include\"]\n",
+    )
+    .unwrap();
+    let config = Config::load(dir.join("pp.toml")).unwrap();
+
+    let mut pp = pp("-include(\"a.hrl\"). [?A, ?DEBUG, ?LEVEL, ?VSN].");
+    config.apply(&mut pp).unwrap();
+    assert_eq!(pp.include_paths(), [dir.join("include")]);
+    assert!(pp.code_paths().contains(&PathBuf::from("/libs")));
+    let tokens = (&mut pp).collect::<Result<Vec<_>, _>>();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(
+        tokens.unwrap().iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["[", "a", ",", "true", ",", "3", ",", "\"1.0\"", "]", "."]
+    );
+
+    let error = Config::from_json_str(r#"{"erl_opts": {"x": 1}}"#).unwrap_err();
+    assert_eq!(error.code(), "EPP0024");
+}
+
+#[test]
+fn forms_works() {
+    let mut includes = MemoryIncludes::new();
+    includes.insert("a.hrl", "-record(r, {x}).");
+    let mut pp = pp("-include(\"a.hrl\").\nf() -> ?MISSING.\ng() -> ok");
+    pp.set_include_resolver(includes);
+    let mut forms = pp.forms();
+    assert_eq!(forms.next().unwrap().unwrap().len(), 10);
+    assert!(forms.next().unwrap().is_err());
+    let last = forms.next().unwrap().unwrap();
+    assert_eq!(last.len(), 5);
+    assert_eq!(last.last().unwrap().text(), "ok");
+    assert!(forms.next().is_none());
+    assert_eq!(pp.included_files().len(), 1);
+}
+
+#[cfg(feature = "otp-compat-tests")]
+#[test]
+fn otp_compat_works() {
+    use erl_pp::otp_compat::{CompatToken, EppHarness};
+
+    let harness = EppHarness::new();
+    let tokens = harness.run_erl_pp("tests/bar.hrl").unwrap();
+    assert_eq!(tokens[0].kind, "symbol");
+    assert_eq!(tokens[0].value, "-");
+    assert_eq!(tokens[1].value, "file");
+
+    let token = CompatToken::new(&Lexer::new("10.50").next().unwrap().unwrap());
+    assert_eq!(token.kind, "float");
+    let other = CompatToken::new(&Lexer::new("10.5").next().unwrap().unwrap());
+    assert!(token.is_equivalent(&other));
+}
+
+#[cfg(feature = "otp-compat-tests")]
+#[test]
+fn epp_comparison_realigns_forms() {
+    use erl_pp::otp_compat::{CompatToken, EppComparison};
+
+    let tokens = |src| {
+        Lexer::new(src)
+            .map(|t| CompatToken::new(&t.unwrap()))
+            .collect::<Vec<_>>()
+    };
+    let comparison =
+        EppComparison::new(tokens("a. b. c(1, 2). d."), tokens("a. x. b. c(3, 4). d."));
+    assert!(!comparison.is_compatible());
+    let divergences = comparison
+        .divergences
+        .iter()
+        .map(|d| {
+            (
+                d.erl_pp_index,
+                d.epp_index,
+                d.erl_pp.as_ref().map(|t| t.value.as_str()),
+                d.epp.as_ref().map(|t| t.value.as_str()),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        divergences,
+        [(2, 2, None, Some("x")), (6, 8, Some("1"), Some("3"))]
+    );
+
+    assert!(EppComparison::new(tokens("a. b."), tokens("a. b.")).is_compatible());
+}
+
+#[test]
+fn streaming_works() {
+    let src = r#"-include("a.hrl"). -ifdef(X). ?Y. -endif. -include("a.hrl"). ?A(1)."#;
+    let mut includes = MemoryIncludes::new();
+    includes.insert("a.hrl", "-define(A(X), {X}).");
+
+    let mut pp = Preprocessor::new(Lexer::new(src));
+    pp.set_include_resolver(includes);
+    pp.set_streaming(true);
+    assert!(pp.streaming());
+    assert!(!pp.record_history());
+    let tokens = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(tokens.iter().map(|t| t.text()).collect::<String>(), "{1}.");
+    assert!(pp.directives().is_empty());
+    assert!(pp.macro_calls().is_empty());
+    assert!(pp.skipped_regions().is_empty());
+    assert!(pp.skipped_macro_refs().is_empty());
+    assert!(pp.conditional_flags().is_empty());
+    assert_eq!(pp.included_files().len(), 2);
+}
+
+#[test]
+fn include_path_variables_are_cached() {
+    std::env::set_var("ERL_PP_TEST_INCLUDE_DIR", "d1");
+    let src = r#"-include("$ERL_PP_TEST_INCLUDE_DIR/a.hrl"). foo. -include("${ERL_PP_TEST_INCLUDE_DIR}/a.hrl")."#;
+    let mut includes = MemoryIncludes::new();
+    includes.insert("d1/a.hrl", "a.");
+
+    let mut pp = Preprocessor::new(Lexer::new(src));
+    pp.set_include_resolver(includes);
+    let mut tokens = Vec::new();
+    for token in &mut pp {
+        let token = token.unwrap();
+        if token.text() == "foo" {
+            std::env::set_var("ERL_PP_TEST_INCLUDE_DIR", "d2");
+        }
+        tokens.push(token.text().to_owned());
+    }
+    assert_eq!(tokens.concat(), "a.foo.a.");
+}
+
+#[test]
+fn remap_expanded_positions_works() {
+    let src = "-define(FOO(X), {X, bar}).\n\nfoo() -> ?FOO(1).";
+    let mut pp = Preprocessor::new(Lexer::new(src));
+    pp.set_remap_expanded_positions(true);
+    pp.set_record_origins(true);
+    let tokens = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<String>(),
+        "foo()->{1,bar}."
+    );
+
+    let call = &tokens[4..9];
+    assert!(call
+        .iter()
+        .all(|t| (t.start_position().line(), t.start_position().column()) == (3, 10)));
+    assert_eq!(tokens[9].start_position().column(), 17);
+
+    // The original positions are kept as the origins.
+    let origin = pp.origins().get(7).unwrap();
+    assert_eq!((origin.start.line(), origin.start.column()), (1, 21));
+}
+
+#[test]
+fn latin1_include_works() {
+    let src = r#"-include("tests/latin1.hrl"). ?NAME."#;
+    let tokens = pp(src).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens[0].as_string_token().map(|t| t.value()),
+        Some("caf\u{e9}")
+    );
+}
+
+#[test]
+fn bom_is_stripped_from_included_files() {
+    let mut includes = MemoryIncludes::new();
+    includes.insert("a.hrl", "\u{feff}-define(A, a).");
+    includes.insert("b.hrl", "-define(B, b).");
+    let mut pp = Preprocessor::new(Lexer::new(
+        r#"-include("a.hrl"). -include("b.hrl"). [?A, ?B]."#,
+    ));
+    pp.set_include_resolver(includes);
+    let tokens = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<String>(),
+        "[a,b]."
+    );
+    let included = pp.included_files();
+    assert!(included[0].bom);
+    assert!(!included[1].bom);
+}
+
+#[test]
+fn bom_is_stripped_from_files_on_disk() {
+    let mut pp = pp(r#"-include("tests/bom.hrl"). -include("tests/bar.hrl"). ?BOM."#);
+    let tokens = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<String>(),
+        "bar.bom."
+    );
+    let included = pp.included_files();
+    assert!(included[0].bom);
+    assert!(!included[1].bom);
+}
+
+#[test]
+fn block_expressions_in_macro_args_work() {
+    let src = r#"-define(LOG(X), {log, X}).
+?LOG(case X of a -> 1; b -> 2 end).
+?LOG(begin a, b end).
+?LOG(fun (A, B) -> A + B end).
+?LOG(fun F(0) -> 1; F(N) -> N * F(N - 1) end).
+?LOG([fun foo/1, fun m:f/2])."#;
+    let tokens = pp(src).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens
+            .iter()
+            .map(|t| t.text())
+            .collect::<Vec<_>>()
+            .join(" "),
+        "{ log , case X of a -> 1 ; b -> 2 end } . \
+         { log , begin a , b end } . \
+         { log , fun ( A , B ) -> A + B end } . \
+         { log , fun F ( 0 ) -> 1 ; F ( N ) -> N * F ( N - 1 ) end } . \
+         { log , [ fun foo / 1 , fun m : f / 2 ] } ."
+    );
+
+    let src = r#"-define(LOG(X), X). ?LOG((case a))."#;
+    assert!(pp(src).collect::<Result<Vec<_>, _>>().is_err());
+}
+
+#[test]
+fn raw_replacement_text_works() {
+    let src = "-define(FOO(X),   {X,\t bar}).\n-define(BAR, [1, % one\n\t2]).\n-define(BAZ, ).";
+    let mut pp = pp(src);
+    assert!(pp.next().is_none());
+    let defines = pp
+        .directives()
+        .values()
+        .filter_map(|d| match d {
+            erl_pp::Directive::Define(d) => d.raw_replacement_text(src),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(defines, ["{X,\t bar}", "[1, % one\n\t2]", ""]);
+}
+
+#[test]
+fn directive_source_text_works() {
+    let src = r#"-include( "tests/bar.hrl" ).
+-undef	(FOO).
+-ifdef( FOO ).
+-else.
+-define(BAR(X) , {X, % comment
+                  X}).
+-endif .
+"#;
+    let mut pp = pp(src);
+    let tokens = (&mut pp).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(tokens.len(), 2);
+    let lines = src.lines().collect::<Vec<_>>();
+    let directives = pp
+        .directives()
+        .values()
+        .filter(|d| d.start_position().filepath().is_none())
+        .map(|d| d.source_text(src).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        directives,
+        [
+            lines[0].to_owned(),
+            lines[1].to_owned(),
+            lines[2].to_owned(),
+            lines[3].to_owned(),
+            lines[4..6].join("\n"),
+            lines[6].to_owned()
+        ]
+    );
+}
+
+#[test]
+fn directive_tokens_works() {
+    use erl_pp::types::ToTokens;
+
+    let src = "-ifdef(FOO).\n-define(BAR(X), {X}).\n-endif.\n";
+    let mut pp = pp(src);
+    assert!(pp.next().is_none());
+    let tokens = pp
+        .directives()
+        .values()
+        .flat_map(|d| d.tokens())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<String>(),
+        "-ifdef(FOO).-define(BAR(X),{X}).-endif."
+    );
+    let last = tokens.last().unwrap();
+    assert_eq!(last.end_position().offset(), src.len() - 1);
+}
@@ -0,0 +1,2 @@
+-module(baz).
+-include("bar.hrl").
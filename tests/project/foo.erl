@@ -0,0 +1,5 @@
+-module(foo).
+-include("bar.hrl").
+-ifdef(TEST).
+-warning("test build").
+-endif.